@@ -0,0 +1,145 @@
+//! Stores annotations next to the series they belong to.
+//!
+//! Annotations are ordered by their start, so the ones overlapping a range
+//! of time are found by scanning the annotations of a series up to the end
+//! of the range. Global annotations are stored as if they belonged to a
+//! series with id 0.
+
+use crate::catalog::SeriesId;
+use crate::codec;
+use crate::database::{Error, SERIES_CF};
+use crate::entities::annotation::{Annotation, AnnotationId};
+use crate::storage::{iter_prefix, Batch, StorageBackend};
+use bincode::{deserialize, serialize};
+use chrono::{DateTime, Utc};
+use std::sync::Mutex;
+
+const NEXT_ANNOTATION_ID: &str = "next_annotation_id";
+
+/// The series id global annotations are stored under
+const GLOBAL: SeriesId = 0;
+
+pub struct Annotations {
+  /// Held while an annotation is created, so that two annotations can not be
+  /// assigned the same id
+  next_annotation_id: Mutex<AnnotationId>,
+}
+
+impl Annotations {
+  pub fn load(db: &dyn StorageBackend) -> Result<Annotations, Error> {
+    let next_annotation_id = db
+      .get(SERIES_CF, &codec::catalog_key(NEXT_ANNOTATION_ID))?
+      .and_then(|value| codec::decode_series_id(&value))
+      .unwrap_or(1);
+
+    Ok(Annotations {
+      next_annotation_id: Mutex::new(next_annotation_id),
+    })
+  }
+
+  /// Assigns the next id to the annotation built by `build`
+  pub fn create<F>(&self, db: &dyn StorageBackend, build: F) -> Result<Annotation, Error>
+  where
+    F: FnOnce(AnnotationId) -> Annotation,
+  {
+    let mut next_annotation_id = self.next_annotation_id.lock().unwrap();
+    let annotation = build(*next_annotation_id);
+    let key = codec::annotation_key(
+      annotation.series_id.unwrap_or(GLOBAL),
+      &annotation.since,
+      annotation.id,
+    );
+
+    let mut batch = Batch::default();
+    batch.put(
+      SERIES_CF,
+      codec::catalog_key(NEXT_ANNOTATION_ID),
+      codec::encode_series_id(annotation.id + 1),
+    );
+    batch.put(
+      SERIES_CF,
+      codec::annotation_id_key(annotation.id),
+      key.clone(),
+    );
+    batch.put(SERIES_CF, key, serialize(&annotation).unwrap());
+    db.write(batch)?;
+
+    *next_annotation_id = annotation.id + 1;
+    Ok(annotation)
+  }
+
+  /// The annotations of the series, or the global ones if `series_id` is
+  /// none, that overlap the range from `since` to `until`
+  pub fn list(
+    &self,
+    db: &dyn StorageBackend,
+    series_id: Option<SeriesId>,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+  ) -> Result<Vec<Annotation>, Error> {
+    let mut annotations = vec![];
+    let prefix = codec::annotation_prefix(series_id.unwrap_or(GLOBAL));
+    for item in iter_prefix(db, SERIES_CF, prefix) {
+      let (key, value) = item?;
+      if let (Some(until), Some(start)) = (until, codec::decode_annotation_since(&key)) {
+        if start > until {
+          break;
+        }
+      }
+      let annotation = match deserialize::<Annotation>(&value) {
+        Ok(annotation) => annotation,
+        Err(err) => {
+          warn!("Could not parse an annotation. It is excluded from the result");
+          debug!("Parse error: {:?}", err);
+          continue;
+        }
+      };
+      if annotation.overlaps(since, until) {
+        annotations.push(annotation);
+      }
+    }
+    Ok(annotations)
+  }
+
+  /// Returns if there was an annotation with the id
+  pub fn delete(
+    &self,
+    db: &dyn StorageBackend,
+    annotation_id: AnnotationId,
+  ) -> Result<bool, Error> {
+    let id_key = codec::annotation_id_key(annotation_id);
+    let key = match db.get(SERIES_CF, &id_key)? {
+      Some(key) => key,
+      None => return Ok(false),
+    };
+
+    let mut batch = Batch::default();
+    batch.delete(SERIES_CF, id_key);
+    batch.delete(SERIES_CF, key);
+    db.write(batch)?;
+    Ok(true)
+  }
+
+  /// Adds deletes for every annotation of the series to `batch`
+  pub fn remove(
+    &self,
+    db: &dyn StorageBackend,
+    batch: &mut Batch,
+    series_id: SeriesId,
+  ) -> Result<(), Error> {
+    for item in iter_prefix(db, SERIES_CF, codec::annotation_prefix(series_id)) {
+      let (key, value) = item?;
+      // The id of an annotation that can not be parsed is not known, so only
+      // the annotation itself is deleted
+      match deserialize::<Annotation>(&value) {
+        Ok(annotation) => batch.delete(SERIES_CF, codec::annotation_id_key(annotation.id)),
+        Err(err) => warn!(
+          "Could not parse an annotation of series {}: {:?}",
+          series_id, err
+        ),
+      }
+      batch.delete(SERIES_CF, key);
+    }
+    Ok(())
+  }
+}
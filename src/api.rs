@@ -1,9 +1,17 @@
-use crate::database::Database;
-use crate::entities::point::{NewPoint, Point, QueryOptions};
+use crate::backup::{create_backup, BackupConfig};
+use crate::database::{Database, Error};
+use crate::entities::annotation::{Annotation, NewAnnotation};
+use crate::entities::point::{Deletion, NewPointInput, Point, QueryOptions, SeriesPoints};
 use crate::entities::series::{NewSeries, Series};
-use juniper::FieldResult;
-use std::net::IpAddr;
-use std::sync::{Arc, RwLock};
+use crate::entities::tag::TagMatcher;
+use crate::replication::{log_filter, ReplicationStatus};
+use crate::storage::Durability;
+use chrono::{DateTime, Utc};
+use juniper::{FieldResult, ID};
+use std::cell::OnceCell;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, RwLock, RwLockReadGuard};
+use warp::filters::BoxedFilter;
 use warp::{http::Response, log, Filter};
 
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Default)]
@@ -14,6 +22,33 @@ pub struct ApiConfig {
 
 struct Context {
   db: Arc<RwLock<Database>>,
+  backup: Option<BackupConfig>,
+  /// Taken by the first field of the request that reads series or points,
+  /// so that the fields read consistent data
+  snapshot: OnceCell<Database>,
+}
+
+impl Context {
+  /// The database, unless it is opened read-only or follows a primary
+  fn writable_db(&self) -> Result<RwLockReadGuard<'_, Database>, Error> {
+    let db = self.db.read().unwrap();
+    if db.is_read_only() {
+      return Err(Error::ReadOnly("be written"));
+    }
+    if db.is_following() {
+      return Err(Error::Following("be written"));
+    }
+    Ok(db)
+  }
+
+  /// The snapshot the request reads from
+  fn snapshot(&self) -> Result<&Database, Error> {
+    if let Some(snapshot) = self.snapshot.get() {
+      return Ok(snapshot);
+    }
+    let snapshot = self.db.read().unwrap().snapshot()?;
+    Ok(self.snapshot.get_or_init(|| snapshot))
+  }
 }
 
 impl juniper::Context for Context {}
@@ -26,19 +61,54 @@ graphql_object!(Query: Context |&self| {
         "0.1"
     }
 
-    field list_series(&executor) -> FieldResult<Vec<Series>> {
-        let db = &executor.context().db;
-        Ok(db.read().unwrap().list_series()?)
+    field read_only(&executor) -> bool as "If the database is opened read-only" {
+        executor.context().db.read().unwrap().is_read_only()
+    }
+
+    field lag(&executor) -> Option<f64> as "Seconds since a read-only database or a follower caught up with the writer" {
+        let lag = executor.context().db.read().unwrap().lag()?;
+        Some(lag.num_milliseconds() as f64 / 1000.0)
+    }
+
+    field replication(&executor) -> Option<ReplicationStatus> {
+        ReplicationStatus::of(&executor.context().db.read().unwrap())
+    }
+
+    field list_series(&executor, matchers: Option<Vec<TagMatcher>>) -> FieldResult<Vec<Series>> {
+        let db = executor.context().snapshot()?;
+        match matchers {
+            Some(matchers) => Ok(db.select_series(&matchers)?),
+            None => Ok(db.list_series()?),
+        }
     }
 
     field series(&executor, name: String) -> FieldResult<Option<Series>> {
-        let db = &executor.context().db;
-        Ok(db.read().unwrap().get_series(&name)?)
+        let db = executor.context().snapshot()?;
+        Ok(db.get_series(&name)?)
     }
 
     field query(&executor, series_name: String, options: Option<QueryOptions>) -> FieldResult<Vec<Point>> {
-        let db = &executor.context().db;
-        Ok(db.read().unwrap().query(&series_name, options)?)
+        let db = executor.context().snapshot()?;
+        Ok(db.query(&series_name, options)?)
+    }
+
+    field query_by_tags(
+        &executor,
+        matchers: Vec<TagMatcher>,
+        options: Option<QueryOptions>,
+    ) -> FieldResult<Vec<SeriesPoints>> {
+        let db = executor.context().snapshot()?;
+        Ok(db.query_by_tags(&matchers, options)?)
+    }
+
+    field annotations(
+        &executor,
+        series_name: Option<String>,
+        options: Option<QueryOptions>,
+    ) -> FieldResult<Vec<Annotation>> {
+        let db = executor.context().snapshot()?;
+        let options = options.unwrap_or_default();
+        Ok(db.list_annotations(series_name.as_deref(), &options)?)
     }
 });
 
@@ -47,19 +117,84 @@ struct Mutation;
 graphql_object!(Mutation: Context |&self| {
 
     field create_series(&executor, new_series: NewSeries) -> FieldResult<Series> {
-        let db = &executor.context().db;
-        Ok(db.read().unwrap().create_series(new_series)?)
+        let db = executor.context().writable_db()?;
+        Ok(db.create_series(new_series)?)
+    }
+
+    field rename_series(&executor, series_name: String, new_name: String) -> FieldResult<Series> {
+        let db = executor.context().writable_db()?;
+        Ok(db.rename_series(&series_name, &new_name)?)
     }
 
-    field delete_series(&executor, series_name: String) -> FieldResult<Option<Series>> {
-        let db = &executor.context().db;
-        db.read().unwrap().delete_series(&series_name)?;
-        Ok(None)
+    field delete_series(&executor, series_name: String) -> FieldResult<Deletion> {
+        let db = executor.context().writable_db()?;
+        Ok(db.delete_series(&series_name)?.into())
     }
 
-    field create_point(&executor, series_name: String, new_point: NewPoint) -> FieldResult<Point> {
-        let db = &executor.context().db;
-        Ok(db.read().unwrap().create_point(&series_name, new_point)?)
+    field delete_points(
+        &executor,
+        series_name: String,
+        since: Option<DateTime<Utc>>,
+        until: Option<DateTime<Utc>>,
+    ) -> FieldResult<Deletion> {
+        let db = executor.context().writable_db()?;
+        let options = QueryOptions::with(|options| {
+            options.since = since;
+            options.until = until;
+        });
+        Ok(db.delete_by_query(&series_name, options)?.into())
+    }
+
+    field create_point(
+        &executor,
+        series_name: String,
+        new_point: NewPointInput,
+        durability: Option<Durability>,
+    ) -> FieldResult<Point> {
+        let db = executor.context().writable_db()?;
+        let new_point = new_point.into_new_point()?;
+        Ok(db.create_point(&series_name, new_point, durability)?)
+    }
+
+    field create_points(
+        &executor,
+        series_name: String,
+        new_points: Vec<NewPointInput>,
+        durability: Option<Durability>,
+    ) -> FieldResult<Vec<Point>> {
+        let db = executor.context().writable_db()?;
+        let new_points = new_points
+            .into_iter()
+            .map(NewPointInput::into_new_point)
+            .collect::<Result<_, _>>()?;
+        Ok(db.create_points(&series_name, new_points, durability)?)
+    }
+
+    field create_annotation(&executor, new_annotation: NewAnnotation) -> FieldResult<Annotation> {
+        let db = executor.context().writable_db()?;
+        Ok(db.create_annotation(new_annotation)?)
+    }
+
+    field delete_annotation(&executor, id: ID) -> FieldResult<bool> {
+        let db = executor.context().writable_db()?;
+        let id = id.parse().map_err(|_| "Invalid annotation id")?;
+        Ok(db.delete_annotation(id)?)
+    }
+
+    // Not gated by `writable_db`, as only followers can be promoted
+    field promote(&executor) -> FieldResult<bool> as "Stops following the primary, so that the database can be written" {
+        executor.context().db.write().unwrap().promote()?;
+        warn!("Promoted to primary, remove the primary from [replication] before restarting");
+        Ok(true)
+    }
+
+    // Not gated by `writable_db`, as backing up does not write the database
+    // and followers can be backed up. Read-only databases refuse backups.
+    field create_backup(&executor) -> FieldResult<bool> {
+        let context = executor.context();
+        let config = context.backup.as_ref().ok_or("Backups are not configured")?;
+        create_backup(config, &context.db.read().unwrap())?;
+        Ok(true)
     }
 });
 
@@ -69,14 +204,36 @@ fn schema() -> Schema {
   Schema::new(Query, Mutation)
 }
 
-pub fn start_api(config: &Option<ApiConfig>, db: Arc<RwLock<Database>>) {
-  let config = config.as_ref().map_or_else(Default::default, Clone::clone);
-  let host: IpAddr = config
-    .host
-    .unwrap_or_else(|| "127.0.0.1".to_string())
-    .parse()
-    .unwrap();
-  let port = config.port.unwrap_or(7766);
+impl ApiConfig {
+  /// The address the API listens on
+  pub fn address(&self) -> SocketAddr {
+    let host: IpAddr = self.host.as_deref().unwrap_or("127.0.0.1").parse().unwrap();
+    SocketAddr::new(host, self.port.unwrap_or(7766))
+  }
+}
+
+/// Answers GraphQL requests with `db`
+pub fn graphql_filter(
+  backup: Option<BackupConfig>,
+  db: Arc<RwLock<Database>>,
+) -> BoxedFilter<(Response<Vec<u8>>,)> {
+  let state = warp::any().map(move || Context {
+    db: db.clone(),
+    backup: backup.clone(),
+    snapshot: OnceCell::new(),
+  });
+  juniper_warp::make_graphql_filter(schema(), state.boxed())
+}
+
+pub fn start_api(
+  config: &Option<ApiConfig>,
+  backup: &Option<BackupConfig>,
+  db: Arc<RwLock<Database>>,
+) {
+  let address = config
+    .as_ref()
+    .map_or_else(Default::default, Clone::clone)
+    .address();
   let log = log("api");
 
   let homepage = warp::path::end().map(|| {
@@ -88,18 +245,19 @@ pub fn start_api(config: &Option<ApiConfig>, db: Arc<RwLock<Database>>) {
       )
   });
 
-  info!("Listening on {}:{}", host, port);
+  info!("Listening on {}", address);
 
-  let state = warp::any().map(move || Context { db: db.clone() });
-  let graphql_filter = juniper_warp::make_graphql_filter(schema(), state.boxed());
+  let replication_log = log_filter(db.clone());
+  let graphql_filter = graphql_filter(backup.clone(), db);
 
   warp::serve(
     warp::get2()
       .and(warp::path("graphiql"))
       .and(juniper_warp::graphiql_filter("/graphql"))
       .or(homepage)
+      .or(replication_log)
       .or(warp::path("graphql").and(graphql_filter))
       .with(log),
   )
-  .run((host, port));
+  .run(address);
 }
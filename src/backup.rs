@@ -0,0 +1,230 @@
+//! Online backups of the database.
+//!
+//! Backups are taken with the RocksDB backup engine while the database keeps
+//! serving requests. Table files are shared between backups, so every backup
+//! after the first only copies the files written since the previous one.
+//!
+//! Backups of a running server are created with the `createBackup` mutation,
+//! which the `backup` command calls. RocksDB locks the database to a single
+//! process, so `backup --offline` opens the database itself while the server
+//! is stopped. Memtables are flushed first, so writes without the write ahead
+//! log are backed up too.
+
+use crate::database::{Database, Error};
+use crate::replication::StdConnector;
+use crate::storage::rocks::BackupEngine;
+use hyper::{Body, Client, Request};
+use std::net::SocketAddr;
+use std::path::Path;
+use tokio::prelude::*;
+use tokio::runtime::current_thread::Runtime;
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct BackupConfig {
+  /// Directory the backups are stored in
+  path: String,
+  /// Number of backups to keep, older backups are purged
+  keep: Option<usize>,
+}
+
+impl BackupConfig {
+  fn open_engine(&self) -> Result<BackupEngine, Error> {
+    BackupEngine::open(&self.path)
+  }
+}
+
+pub fn create_backup(config: &BackupConfig, db: &Database) -> Result<(), Error> {
+  let keep = config.keep.unwrap_or(7);
+  let mut engine = config.open_engine()?;
+
+  info!("Creating backup in {}", config.path);
+  db.backup(&mut engine)?;
+  engine.purge_old_backups(keep)?;
+  info!("Created backup, keeping the latest {}", keep);
+
+  Ok(())
+}
+
+#[derive(Deserialize)]
+struct GraphQLResponse {
+  errors: Option<Vec<GraphQLError>>,
+}
+
+#[derive(Deserialize)]
+struct GraphQLError {
+  message: String,
+}
+
+/// Asks the server listening on `address` to create a backup
+pub fn request_backup(address: SocketAddr) -> Result<(), String> {
+  let uri = format!("http://{}/graphql", address);
+  let request = Request::post(uri.as_str())
+    .header("content-type", "application/json")
+    .body(Body::from(r#"{"query":"mutation { createBackup }"}"#))
+    .map_err(|err| err.to_string())?;
+  let response = Client::builder()
+    .build(StdConnector)
+    .request(request)
+    .and_then(|response| {
+      let status = response.status();
+      response
+        .into_body()
+        .concat2()
+        .map(move |body| (status, body))
+    })
+    .map_err(|err| err.to_string());
+  let (status, body) = Runtime::new()
+    .map_err(|err| err.to_string())?
+    .block_on(response)?;
+
+  let response: GraphQLResponse = serde_json::from_slice(&body)
+    .map_err(|_| format!("{}: {}", status, String::from_utf8_lossy(&body)))?;
+  match response.errors.and_then(|errors| errors.into_iter().next()) {
+    Some(error) => Err(error.message),
+    None => Ok(()),
+  }
+}
+
+/// Replaces the database in `db_dir` with the latest backup. The database
+/// must not be open.
+pub fn restore_backup<P: AsRef<Path>>(config: &BackupConfig, db_dir: P) -> Result<(), Error> {
+  let db_dir = db_dir.as_ref();
+  let mut engine = config.open_engine()?;
+
+  info!(
+    "Restoring latest backup from {} into {}",
+    config.path,
+    db_dir.display()
+  );
+  engine.restore_from_latest_backup(db_dir)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::api::graphql_filter;
+  use crate::entities::point::NewPoint;
+  use crate::entities::series::NewSeries;
+  use crate::entities::value::Value;
+  use crate::storage::Durability;
+  use chrono::{TimeZone, Utc};
+  use std::fs;
+  use std::sync::{Arc, RwLock};
+  use tempdir::TempDir;
+  use tokio::runtime::Builder;
+
+  #[test]
+  fn test_backup_restore() {
+    let tmp_dir = TempDir::new("test_backup_restore").unwrap();
+    let db_path = tmp_dir.path().join("db");
+    let config = BackupConfig {
+      path: tmp_dir.path().join("backup").to_string_lossy().into_owned(),
+      keep: Some(2),
+    };
+    let time = Utc.timestamp(1_560_000_000, 0);
+
+    {
+      let db = Database::open(&db_path, &Default::default()).unwrap();
+      db.create_series(NewSeries {
+        name: "cpu".to_string(),
+        ..Default::default()
+      })
+      .unwrap();
+      db.create_point(
+        "cpu",
+        NewPoint {
+          time,
+          value: Value::Float(1.0),
+        },
+        None,
+      )
+      .unwrap();
+      create_backup(&config, &db).unwrap();
+
+      db.create_point(
+        "cpu",
+        NewPoint {
+          time: time + chrono::Duration::seconds(1),
+          value: Value::Float(2.0),
+        },
+        None,
+      )
+      .unwrap();
+      create_backup(&config, &db).unwrap();
+
+      db.create_point(
+        "cpu",
+        NewPoint {
+          time: time + chrono::Duration::seconds(2),
+          value: Value::Float(3.0),
+        },
+        None,
+      )
+      .unwrap();
+      create_backup(&config, &db).unwrap();
+
+      db.delete_series("cpu").unwrap();
+    }
+
+    // Only the latest 2 backups are kept
+    let mut backups = fs::read_dir(Path::new(&config.path).join("meta"))
+      .unwrap()
+      .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+      .collect::<Vec<_>>();
+    backups.sort();
+    assert_eq!(backups, vec!["2", "3"]);
+
+    let restored_path = tmp_dir.path().join("restored");
+    restore_backup(&config, &restored_path).unwrap();
+    let db = Database::open(&restored_path, &Default::default()).unwrap();
+
+    assert_eq!(db.query("cpu", None).unwrap().len(), 3);
+  }
+
+  #[test]
+  fn test_request_backup() {
+    let tmp_dir = TempDir::new("test_request_backup").unwrap();
+    let config = BackupConfig {
+      path: tmp_dir.path().join("backup").to_string_lossy().into_owned(),
+      keep: None,
+    };
+    let mut db = Database::open(tmp_dir.path().join("db"), &Default::default()).unwrap();
+    // Only in the memtable until it is flushed
+    db.set_durability(Durability::Disabled);
+    db.create_series(NewSeries {
+      name: "cpu".to_string(),
+      ..Default::default()
+    })
+    .unwrap();
+    db.create_point(
+      "cpu",
+      NewPoint {
+        time: Utc.timestamp(1_560_000_000, 0),
+        value: Value::Float(1.0),
+      },
+      None,
+    )
+    .unwrap();
+    let db = Arc::new(RwLock::new(db));
+
+    let (address, server) = warp::serve(graphql_filter(Some(config.clone()), db.clone()))
+      .bind_ephemeral(([127, 0, 0, 1], 0));
+    let (unconfigured, unconfigured_server) =
+      warp::serve(graphql_filter(None, db)).bind_ephemeral(([127, 0, 0, 1], 0));
+    // GraphQL requests are answered on the blocking threads of a thread pool
+    let mut runtime = Builder::new().core_threads(1).build().unwrap();
+    runtime.spawn(server);
+    runtime.spawn(unconfigured_server);
+
+    assert_eq!(request_backup(address), Ok(()));
+    assert_eq!(
+      request_backup(unconfigured),
+      Err("Backups are not configured".to_string())
+    );
+
+    let restored_path = tmp_dir.path().join("restored");
+    restore_backup(&config, &restored_path).unwrap();
+    let restored = Database::open(&restored_path, &Default::default()).unwrap();
+    assert_eq!(restored.query("cpu", None).unwrap().len(), 1);
+  }
+}
@@ -0,0 +1,361 @@
+//! Compressed blocks of points.
+//!
+//! Points of a series are grouped into blocks covering a fixed span of time
+//! and compressed the way Facebook's Gorilla paper describes:
+//!
+//! - Timestamps are stored as the delta of the delta to the previous point.
+//!   Regularly sampled series have a delta of delta of zero, which costs a
+//!   single bit.
+//! - Values are XOR'ed with the previous value and only the meaningful bits
+//!   of the result are stored. Slowly changing values share most of their
+//!   bits with the previous value and compress to a handful of bits.
+//!
+//! Values are taken as their 64 bits, the value type of the series tells how
+//! to read them back.
+//!
+//! A block starts with the number of points as a big-endian `u32` followed by
+//! the bit stream. Points must be pushed in chronological order.
+
+use crate::codec;
+use chrono::{DateTime, Utc};
+use std::fmt;
+
+/// The span of time covered by a block
+pub const BLOCK_SPAN: i64 = 2 * 60 * 60 * 1_000_000_000;
+
+/// The start of the block a point at `time` belongs to
+pub fn block_start(time: &DateTime<Utc>) -> DateTime<Utc> {
+  codec::truncate_time(time, BLOCK_SPAN)
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum DecodeError {
+  Truncated,
+  Invalid,
+}
+
+impl fmt::Display for DecodeError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      DecodeError::Truncated => write!(f, "Block ended before all points were read"),
+      DecodeError::Invalid => write!(f, "Block is not a valid compressed block"),
+    }
+  }
+}
+
+/// Bit lengths a non zero delta of delta can be stored in. The length is
+/// selected by a run of one bits, terminated by a zero bit unless it is the
+/// last one: `10` for 7 bits, `110` for 9 bits and so on up to `11111`.
+const DELTA_OF_DELTA_LENGTHS: [u8; 5] = [7, 9, 12, 32, 64];
+
+struct BitWriter {
+  bytes: Vec<u8>,
+  bit: u8,
+}
+
+impl BitWriter {
+  fn new(bytes: Vec<u8>) -> BitWriter {
+    BitWriter { bytes, bit: 8 }
+  }
+
+  fn write_bit(&mut self, bit: bool) {
+    if self.bit == 8 {
+      self.bytes.push(0);
+      self.bit = 0;
+    }
+    if bit {
+      *self.bytes.last_mut().unwrap() |= 0x80 >> self.bit;
+    }
+    self.bit += 1;
+  }
+
+  /// Writes the `length` least significant bits of `value`
+  fn write_bits(&mut self, value: u64, length: u8) {
+    for i in (0..length).rev() {
+      self.write_bit((value >> i) & 1 == 1);
+    }
+  }
+}
+
+struct BitReader<'a> {
+  bytes: &'a [u8],
+  position: usize,
+}
+
+impl<'a> BitReader<'a> {
+  fn read_bit(&mut self) -> Result<bool, DecodeError> {
+    let byte = self
+      .bytes
+      .get(self.position / 8)
+      .ok_or(DecodeError::Truncated)?;
+    let bit = byte & (0x80 >> (self.position % 8)) != 0;
+    self.position += 1;
+    Ok(bit)
+  }
+
+  fn read_bits(&mut self, length: u8) -> Result<u64, DecodeError> {
+    let mut value = 0;
+    for _ in 0..length {
+      value = (value << 1) | self.read_bit()? as u64;
+    }
+    Ok(value)
+  }
+}
+
+fn sign_extend(value: u64, length: u8) -> i64 {
+  let shift = 64 - u32::from(length);
+  ((value << shift) as i64) >> shift
+}
+
+struct BlockEncoder {
+  writer: BitWriter,
+  count: u32,
+  previous_time: i64,
+  previous_delta: i64,
+  previous_value: u64,
+  leading_zeros: u8,
+  trailing_zeros: u8,
+}
+
+impl BlockEncoder {
+  fn new() -> BlockEncoder {
+    BlockEncoder {
+      writer: BitWriter::new(vec![0; 4]),
+      count: 0,
+      previous_time: 0,
+      previous_delta: 0,
+      previous_value: 0,
+      leading_zeros: u8::MAX,
+      trailing_zeros: 0,
+    }
+  }
+
+  /// Appends a point, `time` is in nanoseconds since the unix epoch
+  fn push(&mut self, time: i64, value: u64) {
+    if self.count == 0 {
+      self.writer.write_bits(time as u64, 64);
+      self.writer.write_bits(value, 64);
+    } else {
+      let delta = time.wrapping_sub(self.previous_time);
+      self.write_delta_of_delta(delta.wrapping_sub(self.previous_delta));
+      self.write_value(value);
+      self.previous_delta = delta;
+    }
+
+    self.previous_time = time;
+    self.previous_value = value;
+    self.count += 1;
+  }
+
+  fn write_delta_of_delta(&mut self, delta_of_delta: i64) {
+    if delta_of_delta == 0 {
+      self.writer.write_bit(false);
+      return;
+    }
+
+    for (i, &length) in DELTA_OF_DELTA_LENGTHS.iter().enumerate() {
+      if sign_extend(delta_of_delta as u64, length) == delta_of_delta {
+        for _ in 0..=i {
+          self.writer.write_bit(true);
+        }
+        if i + 1 < DELTA_OF_DELTA_LENGTHS.len() {
+          self.writer.write_bit(false);
+        }
+        self.writer.write_bits(delta_of_delta as u64, length);
+        return;
+      }
+    }
+  }
+
+  fn write_value(&mut self, value: u64) {
+    let xor = value ^ self.previous_value;
+    if xor == 0 {
+      self.writer.write_bit(false);
+      return;
+    }
+    self.writer.write_bit(true);
+
+    // The leading zero count is stored in 5 bits
+    let leading_zeros = (xor.leading_zeros() as u8).min(31);
+    let trailing_zeros = xor.trailing_zeros() as u8;
+
+    if self.leading_zeros != u8::MAX
+      && leading_zeros >= self.leading_zeros
+      && trailing_zeros >= self.trailing_zeros
+    {
+      // The meaningful bits fit in the previous window
+      let length = 64 - self.leading_zeros - self.trailing_zeros;
+      self.writer.write_bit(false);
+      self.writer.write_bits(xor >> self.trailing_zeros, length);
+    } else {
+      let length = 64 - leading_zeros - trailing_zeros;
+      self.writer.write_bit(true);
+      self.writer.write_bits(u64::from(leading_zeros), 5);
+      self.writer.write_bits(u64::from(length - 1), 6);
+      self.writer.write_bits(xor >> trailing_zeros, length);
+      self.leading_zeros = leading_zeros;
+      self.trailing_zeros = trailing_zeros;
+    }
+  }
+
+  fn finish(self) -> Vec<u8> {
+    let mut bytes = self.writer.bytes;
+    bytes[..4].copy_from_slice(&self.count.to_be_bytes());
+    bytes
+  }
+}
+
+pub fn encode<I: IntoIterator<Item = (i64, u64)>>(points: I) -> Vec<u8> {
+  let mut encoder = BlockEncoder::new();
+  for (time, value) in points {
+    encoder.push(time, value);
+  }
+  encoder.finish()
+}
+
+/// The number of points in a block, without decoding it
+pub fn point_count(bytes: &[u8]) -> Option<u32> {
+  if bytes.len() < 4 {
+    return None;
+  }
+  Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Decodes a block into its points, as nanoseconds since the unix epoch and
+/// the bits of the value
+pub fn decode(bytes: &[u8]) -> Result<Vec<(i64, u64)>, DecodeError> {
+  let count = point_count(bytes).ok_or(DecodeError::Truncated)?;
+  let mut reader = BitReader {
+    bytes: &bytes[4..],
+    position: 0,
+  };
+  // The first point takes 128 bits and every other point at least 2, so a
+  // corrupt count can not make us allocate more than the bytes can hold
+  let bits = reader.bytes.len() * 8;
+  let max_count = if bits < 128 { 0 } else { 1 + (bits - 128) / 2 };
+  let mut points = Vec::with_capacity((count as usize).min(max_count));
+
+  let mut time = 0i64;
+  let mut delta = 0i64;
+  let mut value = 0u64;
+  let mut leading_zeros = 0u8;
+  let mut trailing_zeros = 0u8;
+
+  for i in 0..count {
+    if i == 0 {
+      time = reader.read_bits(64)? as i64;
+      value = reader.read_bits(64)?;
+    } else {
+      let mut ones = 0;
+      while ones < DELTA_OF_DELTA_LENGTHS.len() && reader.read_bit()? {
+        ones += 1;
+      }
+      let delta_of_delta = match ones {
+        0 => 0,
+        _ => {
+          let length = DELTA_OF_DELTA_LENGTHS[ones - 1];
+          sign_extend(reader.read_bits(length)?, length)
+        }
+      };
+      delta = delta.wrapping_add(delta_of_delta);
+      time = time.wrapping_add(delta);
+
+      if reader.read_bit()? {
+        if reader.read_bit()? {
+          leading_zeros = reader.read_bits(5)? as u8;
+          let length = reader.read_bits(6)? as u8 + 1;
+          trailing_zeros = 64u8
+            .checked_sub(leading_zeros + length)
+            .ok_or(DecodeError::Invalid)?;
+        }
+        let length = 64 - leading_zeros - trailing_zeros;
+        value ^= reader.read_bits(length)? << trailing_zeros;
+      }
+    }
+
+    points.push((time, value));
+  }
+
+  Ok(points)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn float_bits(points: Vec<(i64, f64)>) -> Vec<(i64, u64)> {
+    points
+      .into_iter()
+      .map(|(time, value)| (time, value.to_bits()))
+      .collect()
+  }
+
+  fn assert_roundtrip(points: Vec<(i64, u64)>) {
+    assert_eq!(decode(&encode(points.clone())), Ok(points));
+  }
+
+  #[test]
+  fn test_empty_block() {
+    assert_eq!(decode(&encode(vec![])), Ok(vec![]));
+  }
+
+  #[test]
+  fn test_regular_series() {
+    let points = (0..1000)
+      .map(|i| {
+        (
+          1_560_000_000_000_000_000 + i * 10_000_000_000,
+          20.0 + (i % 7) as f64,
+        )
+      })
+      .collect::<Vec<_>>();
+
+    let points = float_bits(points);
+    assert_roundtrip(points.clone());
+    // 16 bytes per point uncompressed
+    assert!(encode(points).len() < 1000 * 2);
+  }
+
+  #[test]
+  fn test_irregular_series() {
+    let points = vec![
+      (-1_000_000_000_000, 0.0),
+      (-1, -0.0),
+      (0, 1.5),
+      (1, f64::NAN),
+      (1_000, f64::INFINITY),
+      (1_000_000_000_000_000_000, f64::MIN_POSITIVE),
+      (1_000_000_000_000_000_001, f64::MAX),
+      (i64::MAX, 42.0),
+    ];
+
+    assert_roundtrip(float_bits(points));
+  }
+
+  #[test]
+  fn test_integer_series() {
+    let points = (0..1000)
+      .map(|i| (i * 1_000_000_000, (i64::MAX - 1000 + i) as u64))
+      .collect::<Vec<_>>();
+
+    assert_roundtrip(points);
+  }
+
+  #[test]
+  fn test_truncated_block() {
+    let bytes = encode(float_bits(vec![(0, 1.0), (10, 2.0)]));
+
+    assert_eq!(
+      decode(&bytes[..bytes.len() - 1]),
+      Err(DecodeError::Truncated)
+    );
+  }
+
+  #[test]
+  fn test_corrupt_count() {
+    let mut bytes = encode(float_bits(vec![(0, 1.0), (10, 2.0)]));
+    bytes[..4].copy_from_slice(&u32::MAX.to_be_bytes());
+
+    assert_eq!(decode(&bytes), Err(DecodeError::Truncated));
+  }
+}
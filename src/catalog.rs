@@ -0,0 +1,192 @@
+//! Assigns series their numeric ids and resolves series names to them.
+//!
+//! Points and series metadata are stored under the id, so the name of a
+//! series only exists in the series name key. Renaming a series is a matter
+//! of moving that single key.
+//!
+//! The catalog also keeps the inverted index from the tags of series to
+//! their ids, written and removed together with the series.
+
+use crate::codec;
+use crate::database::{Error, SERIES_CF};
+use crate::entities::series::{NewSeries, Series};
+use crate::entities::tag::{Matcher, Tag};
+use crate::storage::{iter_prefix, Batch, StorageBackend};
+use bincode::serialize;
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::sync::Mutex;
+
+pub type SeriesId = u64;
+
+const NEXT_SERIES_ID: &str = "next_series_id";
+
+pub struct Catalog {
+  /// Held while the catalog is written to, so that two series can not be
+  /// assigned the same name or id
+  next_series_id: Mutex<SeriesId>,
+}
+
+impl Catalog {
+  pub fn load(db: &dyn StorageBackend) -> Result<Catalog, Error> {
+    let next_series_id = db
+      .get(SERIES_CF, &codec::catalog_key(NEXT_SERIES_ID))?
+      .and_then(|value| codec::decode_series_id(&value))
+      .unwrap_or(1);
+
+    Ok(Catalog {
+      next_series_id: Mutex::new(next_series_id),
+    })
+  }
+
+  pub fn resolve(
+    &self,
+    db: &dyn StorageBackend,
+    series_name: &str,
+  ) -> Result<Option<SeriesId>, Error> {
+    Ok(
+      db.get(SERIES_CF, &codec::series_name_key(series_name))?
+        .and_then(|value| codec::decode_series_id(&value)),
+    )
+  }
+
+  pub fn create(&self, db: &dyn StorageBackend, new_series: NewSeries) -> Result<Series, Error> {
+    let series_name = new_series.name.clone();
+    let tags = new_series
+      .tags
+      .iter()
+      .flatten()
+      .cloned()
+      .map(Tag::from)
+      .collect::<Vec<_>>();
+    self.insert(db, &series_name, &tags, |series_id| {
+      Series::new(series_id, new_series)
+    })
+  }
+
+  /// Assigns the next id to the series built by `build` and indexes its
+  /// `tags`. Migrations insert series in the layout of their version, hence
+  /// the generic value.
+  pub fn insert<T, F>(
+    &self,
+    db: &dyn StorageBackend,
+    series_name: &str,
+    tags: &[Tag],
+    build: F,
+  ) -> Result<T, Error>
+  where
+    T: Serialize,
+    F: FnOnce(SeriesId) -> T,
+  {
+    let mut next_series_id = self.next_series_id.lock().unwrap();
+
+    if self.resolve(db, series_name)?.is_some() {
+      return Err(Error::SeriesExists(series_name.to_string()));
+    }
+
+    let series_id = *next_series_id;
+    let series = build(series_id);
+
+    let mut batch = Batch::default();
+    batch.put(
+      SERIES_CF,
+      codec::catalog_key(NEXT_SERIES_ID),
+      codec::encode_series_id(series_id + 1),
+    );
+    self.write_series(&mut batch, series_id, series_name, &series);
+    for tag in tags {
+      batch.put(
+        SERIES_CF,
+        codec::tag_index_key(&tag.key, &tag.value, series_id),
+        vec![],
+      );
+    }
+    db.write(batch)?;
+
+    *next_series_id = series_id + 1;
+    Ok(series)
+  }
+
+  pub fn rename(
+    &self,
+    db: &dyn StorageBackend,
+    series: &mut Series,
+    new_name: &str,
+  ) -> Result<(), Error> {
+    let _guard = self.next_series_id.lock().unwrap();
+
+    if self.resolve(db, new_name)?.is_some() {
+      return Err(Error::SeriesExists(new_name.to_string()));
+    }
+
+    let mut batch = Batch::default();
+    batch.delete(SERIES_CF, codec::series_name_key(&series.name));
+    series.name = new_name.to_string();
+    self.write_series(&mut batch, series.id, &series.name, series);
+    db.write(batch)
+  }
+
+  /// Stores changes to the metadata of a series, except its name
+  pub fn update(&self, db: &dyn StorageBackend, series: &Series) -> Result<(), Error> {
+    db.put(
+      SERIES_CF,
+      &codec::series_key(series.id),
+      &serialize(series).unwrap(),
+    )
+  }
+
+  fn write_series<T: Serialize>(
+    &self,
+    batch: &mut Batch,
+    series_id: SeriesId,
+    series_name: &str,
+    series: &T,
+  ) {
+    batch.put(
+      SERIES_CF,
+      codec::series_name_key(series_name),
+      codec::encode_series_id(series_id),
+    );
+    batch.put(
+      SERIES_CF,
+      codec::series_key(series_id),
+      serialize(series).unwrap(),
+    );
+  }
+
+  /// Removes the series from the catalog, its points are left to the caller
+  pub fn remove(&self, batch: &mut Batch, series: &Series) {
+    batch.delete(SERIES_CF, codec::series_name_key(&series.name));
+    batch.delete(SERIES_CF, codec::series_key(series.id));
+    for tag in &series.tags {
+      batch.delete(
+        SERIES_CF,
+        codec::tag_index_key(&tag.key, &tag.value, series.id),
+      );
+    }
+  }
+
+  /// The ids of the series that have a tag matched by `matcher`. Negation is
+  /// ignored, the caller has to apply it.
+  pub fn lookup(
+    &self,
+    db: &dyn StorageBackend,
+    matcher: &Matcher,
+  ) -> Result<BTreeSet<SeriesId>, Error> {
+    let prefix = match matcher {
+      Matcher::Equal(key, value) => codec::tag_prefix(key, value),
+      _ => codec::tag_key_prefix(matcher.key()),
+    };
+    let mut series_ids = BTreeSet::new();
+    for item in iter_prefix(db, SERIES_CF, prefix) {
+      let (key, _) = item?;
+      match codec::decode_tag_index_key(&key) {
+        Some((value, series_id)) if matcher.matches_value(&value) => {
+          series_ids.insert(series_id);
+        }
+        _ => (),
+      }
+    }
+    Ok(series_ids)
+  }
+}
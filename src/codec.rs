@@ -0,0 +1,475 @@
+//! Binary layout of the keys stored in RocksDB.
+//!
+//! RocksDB orders keys bytewise, so every key is built to make that order
+//! match the order we want to scan in:
+//!
+//! ```text
+//! catalog key:     0x00 | name
+//! series key:      0x01 | series id
+//! point key:       0x02 | series id | timestamp
+//! series name key: 0x03 | series name
+//! block key:       0x04 | series id | timestamp of the start of the block
+//! shard key:       0x05 | series id | timestamp of the start of the shard
+//! tag key:         0x06 | tag key length | tag key | tag value length |
+//!                  tag value | series id
+//! annotation key:  0x07 | series id | timestamp of the start | annotation id
+//! annotation id key: 0x08 | annotation id
+//! stats key:       0x09 | series id
+//! log key:         sequence number
+//! ```
+//!
+//! Catalog, series, series name, shard, tag, annotation and stats keys live
+//! in the series column family, point keys in the points and rollups column families and block
+//! keys in the blocks column family. Log keys are the only keys of the
+//! replication column family, so they have no tag. Sequence numbers are
+//! big-endian `u64`.
+//!
+//! Series are stored under the numeric id assigned by the catalog, the series
+//! name key maps a name to that id. Ids are big-endian `u64` so that every
+//! series owns a fixed width key prefix and no series can share a prefix
+//! with another. Timestamps are nanoseconds since the unix epoch stored as a
+//! big-endian `i64` with the sign bit flipped, which makes the bytewise
+//! order equal to the chronological order, including times before 1970.
+//!
+//! Tag keys form the inverted index from tags to the series that have them.
+//! Keys and values of tags are prefixed with their length as a big-endian
+//! `u32`, so that no tag can be a prefix of another.
+//!
+//! Annotations are ordered by their start within the series they belong to,
+//! global annotations belong to series id 0, which no series is assigned.
+//! The annotation id key maps an id to the annotation key.
+
+use crate::catalog::SeriesId;
+use chrono::{DateTime, TimeZone, Utc};
+
+const CATALOG_TAG: u8 = 0x00;
+const SERIES_TAG: u8 = 0x01;
+const POINT_TAG: u8 = 0x02;
+const SERIES_NAME_TAG: u8 = 0x03;
+const BLOCK_TAG: u8 = 0x04;
+const SHARD_TAG: u8 = 0x05;
+const TAG_TAG: u8 = 0x06;
+const ANNOTATION_TAG: u8 = 0x07;
+const ANNOTATION_ID_TAG: u8 = 0x08;
+const STATS_TAG: u8 = 0x09;
+
+/// What a key is, told by its first byte
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum KeyKind {
+  Catalog,
+  Series,
+  Point,
+  SeriesName,
+  Block,
+  Shard,
+  Tag,
+  Annotation,
+  AnnotationId,
+  Stats,
+}
+
+pub fn key_kind(key: &[u8]) -> Option<KeyKind> {
+  match *key.first()? {
+    CATALOG_TAG => Some(KeyKind::Catalog),
+    SERIES_TAG => Some(KeyKind::Series),
+    POINT_TAG => Some(KeyKind::Point),
+    SERIES_NAME_TAG => Some(KeyKind::SeriesName),
+    BLOCK_TAG => Some(KeyKind::Block),
+    SHARD_TAG => Some(KeyKind::Shard),
+    TAG_TAG => Some(KeyKind::Tag),
+    ANNOTATION_TAG => Some(KeyKind::Annotation),
+    ANNOTATION_ID_TAG => Some(KeyKind::AnnotationId),
+    STATS_TAG => Some(KeyKind::Stats),
+    _ => None,
+  }
+}
+
+const SERIES_ID_LENGTH: usize = 8;
+
+const TIMESTAMP_LENGTH: usize = 8;
+const NANOS_PER_SECOND: i64 = 1_000_000_000;
+
+/// Nanoseconds since the unix epoch, none for times before 1677 or after
+/// 2262, which do not fit an `i64`
+pub fn checked_time_to_nanos(time: &DateTime<Utc>) -> Option<i64> {
+  time
+    .timestamp()
+    .checked_mul(NANOS_PER_SECOND)?
+    .checked_add(i64::from(time.timestamp_subsec_nanos()))
+}
+
+/// Nanoseconds since the unix epoch, times out of range are clamped to the
+/// earliest or latest time that can be stored
+pub fn time_to_nanos(time: &DateTime<Utc>) -> i64 {
+  checked_time_to_nanos(time).unwrap_or(if time.timestamp() < 0 {
+    i64::MIN
+  } else {
+    i64::MAX
+  })
+}
+
+pub fn nanos_to_time(nanos: i64) -> DateTime<Utc> {
+  let mut seconds = nanos / NANOS_PER_SECOND;
+  let mut subsec_nanos = nanos % NANOS_PER_SECOND;
+  if subsec_nanos < 0 {
+    seconds -= 1;
+    subsec_nanos += NANOS_PER_SECOND;
+  }
+  Utc.timestamp(seconds, subsec_nanos as u32)
+}
+
+/// The start of the span of `span` nanoseconds that `time` falls in, spans
+/// are aligned to the unix epoch. Spans before the earliest time that can be
+/// stored start at that time.
+pub fn truncate_time(time: &DateTime<Utc>, span: i64) -> DateTime<Utc> {
+  let nanos = time_to_nanos(time);
+  nanos_to_time(nanos.saturating_sub(nanos.rem_euclid(span)))
+}
+
+pub fn encode_time(time: &DateTime<Utc>) -> [u8; TIMESTAMP_LENGTH] {
+  ((time_to_nanos(time) as u64) ^ (1 << 63)).to_be_bytes()
+}
+
+pub fn decode_time(bytes: &[u8]) -> Option<DateTime<Utc>> {
+  if bytes.len() != TIMESTAMP_LENGTH {
+    return None;
+  }
+  let mut buffer = [0; TIMESTAMP_LENGTH];
+  buffer.copy_from_slice(bytes);
+  Some(nanos_to_time(
+    (u64::from_be_bytes(buffer) ^ (1 << 63)) as i64,
+  ))
+}
+
+pub fn catalog_key(name: &str) -> Vec<u8> {
+  let mut key = vec![CATALOG_TAG];
+  key.extend_from_slice(name.as_bytes());
+  key
+}
+
+pub fn encode_series_id(series_id: SeriesId) -> [u8; SERIES_ID_LENGTH] {
+  series_id.to_be_bytes()
+}
+
+pub fn decode_series_id(bytes: &[u8]) -> Option<SeriesId> {
+  if bytes.len() != SERIES_ID_LENGTH {
+    return None;
+  }
+  let mut buffer = [0; SERIES_ID_LENGTH];
+  buffer.copy_from_slice(bytes);
+  Some(SeriesId::from_be_bytes(buffer))
+}
+
+/// The first key after every key starting with `prefix`
+pub fn prefix_end(prefix: &[u8]) -> Vec<u8> {
+  let mut end = prefix.to_vec();
+  while let Some(last) = end.pop() {
+    if last < u8::MAX {
+      end.push(last + 1);
+      break;
+    }
+  }
+  end
+}
+
+/// The prefix shared by all series keys
+pub fn series_prefix() -> Vec<u8> {
+  vec![SERIES_TAG]
+}
+
+pub fn series_key(series_id: SeriesId) -> Vec<u8> {
+  let mut key = series_prefix();
+  key.extend_from_slice(&encode_series_id(series_id));
+  key
+}
+
+/// Extracts the series id from a series key
+pub fn decode_series_key(key: &[u8]) -> Option<SeriesId> {
+  if key.first() != Some(&SERIES_TAG) {
+    return None;
+  }
+  decode_series_id(&key[1..])
+}
+
+pub fn series_name_key(series_name: &str) -> Vec<u8> {
+  let mut key = vec![SERIES_NAME_TAG];
+  key.extend_from_slice(series_name.as_bytes());
+  key
+}
+
+/// The prefix shared by all points of a series
+pub fn point_prefix(series_id: SeriesId) -> Vec<u8> {
+  let mut key = Vec::with_capacity(1 + SERIES_ID_LENGTH + TIMESTAMP_LENGTH);
+  key.push(POINT_TAG);
+  key.extend_from_slice(&encode_series_id(series_id));
+  key
+}
+
+pub fn point_key(series_id: SeriesId, time: &DateTime<Utc>) -> Vec<u8> {
+  let mut key = point_prefix(series_id);
+  key.extend_from_slice(&encode_time(time));
+  key
+}
+
+/// Extracts the series id from a point, block or shard key
+pub fn decode_key_series_id(key: &[u8]) -> Option<SeriesId> {
+  match key.first() {
+    Some(&POINT_TAG) | Some(&BLOCK_TAG) | Some(&SHARD_TAG) => {
+      decode_series_id(key.get(1..1 + SERIES_ID_LENGTH)?)
+    }
+    _ => None,
+  }
+}
+
+/// Extracts the timestamp from a point key
+pub fn decode_point_time(key: &[u8]) -> Option<DateTime<Utc>> {
+  if key.first() != Some(&POINT_TAG) || key.len() != 1 + SERIES_ID_LENGTH + TIMESTAMP_LENGTH {
+    return None;
+  }
+  decode_time(&key[1 + SERIES_ID_LENGTH..])
+}
+
+/// The prefix shared by all blocks of a series
+pub fn block_prefix(series_id: SeriesId) -> Vec<u8> {
+  let mut key = Vec::with_capacity(1 + SERIES_ID_LENGTH + TIMESTAMP_LENGTH);
+  key.push(BLOCK_TAG);
+  key.extend_from_slice(&encode_series_id(series_id));
+  key
+}
+
+pub fn block_key(series_id: SeriesId, start: &DateTime<Utc>) -> Vec<u8> {
+  let mut key = block_prefix(series_id);
+  key.extend_from_slice(&encode_time(start));
+  key
+}
+
+/// Extracts the start of the block from a block key
+pub fn decode_block_start(key: &[u8]) -> Option<DateTime<Utc>> {
+  if key.first() != Some(&BLOCK_TAG) || key.len() != 1 + SERIES_ID_LENGTH + TIMESTAMP_LENGTH {
+    return None;
+  }
+  decode_time(&key[1 + SERIES_ID_LENGTH..])
+}
+
+/// The prefix shared by all shards of a series
+pub fn shard_prefix(series_id: SeriesId) -> Vec<u8> {
+  let mut key = Vec::with_capacity(1 + SERIES_ID_LENGTH + TIMESTAMP_LENGTH);
+  key.push(SHARD_TAG);
+  key.extend_from_slice(&encode_series_id(series_id));
+  key
+}
+
+pub fn shard_key(series_id: SeriesId, start: &DateTime<Utc>) -> Vec<u8> {
+  let mut key = shard_prefix(series_id);
+  key.extend_from_slice(&encode_time(start));
+  key
+}
+
+/// Extracts the start of the shard from a shard key
+pub fn decode_shard_start(key: &[u8]) -> Option<DateTime<Utc>> {
+  if key.first() != Some(&SHARD_TAG) || key.len() != 1 + SERIES_ID_LENGTH + TIMESTAMP_LENGTH {
+    return None;
+  }
+  decode_time(&key[1 + SERIES_ID_LENGTH..])
+}
+
+/// The prefix shared by the index entries of every value of a tag key
+pub fn tag_key_prefix(key: &str) -> Vec<u8> {
+  let mut prefix = vec![TAG_TAG];
+  prefix.extend_from_slice(&(key.len() as u32).to_be_bytes());
+  prefix.extend_from_slice(key.as_bytes());
+  prefix
+}
+
+/// The prefix shared by the index entries of a tag
+pub fn tag_prefix(key: &str, value: &str) -> Vec<u8> {
+  let mut prefix = tag_key_prefix(key);
+  prefix.extend_from_slice(&(value.len() as u32).to_be_bytes());
+  prefix.extend_from_slice(value.as_bytes());
+  prefix
+}
+
+pub fn tag_index_key(key: &str, value: &str, series_id: SeriesId) -> Vec<u8> {
+  let mut index_key = tag_prefix(key, value);
+  index_key.extend_from_slice(&encode_series_id(series_id));
+  index_key
+}
+
+/// Extracts the tag value and series id from a tag key
+pub fn decode_tag_index_key(index_key: &[u8]) -> Option<(String, SeriesId)> {
+  if index_key.first() != Some(&TAG_TAG) {
+    return None;
+  }
+  let (_, rest) = split_length_prefixed(&index_key[1..])?;
+  let (value, rest) = split_length_prefixed(rest)?;
+  let value = String::from_utf8(value.to_vec()).ok()?;
+  Some((value, decode_series_id(rest)?))
+}
+
+/// Splits the length prefixed bytes at the start of `bytes` from the rest
+fn split_length_prefixed(bytes: &[u8]) -> Option<(&[u8], &[u8])> {
+  let mut buffer = [0; 4];
+  buffer.copy_from_slice(bytes.get(..4)?);
+  let length = u32::from_be_bytes(buffer) as usize;
+  let rest = &bytes[4..];
+  Some((rest.get(..length)?, rest.get(length..)?))
+}
+
+/// The prefix shared by all annotations of a series
+pub fn annotation_prefix(series_id: SeriesId) -> Vec<u8> {
+  let mut key = Vec::with_capacity(1 + SERIES_ID_LENGTH + TIMESTAMP_LENGTH + 8);
+  key.push(ANNOTATION_TAG);
+  key.extend_from_slice(&encode_series_id(series_id));
+  key
+}
+
+pub fn annotation_key(series_id: SeriesId, since: &DateTime<Utc>, annotation_id: u64) -> Vec<u8> {
+  let mut key = annotation_prefix(series_id);
+  key.extend_from_slice(&encode_time(since));
+  key.extend_from_slice(&annotation_id.to_be_bytes());
+  key
+}
+
+/// Extracts the series id from an annotation key, 0 for global annotations
+pub fn decode_annotation_series_id(key: &[u8]) -> Option<SeriesId> {
+  if key.first() != Some(&ANNOTATION_TAG)
+    || key.len() != 1 + SERIES_ID_LENGTH + TIMESTAMP_LENGTH + 8
+  {
+    return None;
+  }
+  decode_series_id(&key[1..1 + SERIES_ID_LENGTH])
+}
+
+/// Extracts the start from an annotation key
+pub fn decode_annotation_since(key: &[u8]) -> Option<DateTime<Utc>> {
+  if key.first() != Some(&ANNOTATION_TAG)
+    || key.len() != 1 + SERIES_ID_LENGTH + TIMESTAMP_LENGTH + 8
+  {
+    return None;
+  }
+  decode_time(&key[1 + SERIES_ID_LENGTH..1 + SERIES_ID_LENGTH + TIMESTAMP_LENGTH])
+}
+
+pub fn annotation_id_key(annotation_id: u64) -> Vec<u8> {
+  let mut key = vec![ANNOTATION_ID_TAG];
+  key.extend_from_slice(&annotation_id.to_be_bytes());
+  key
+}
+
+/// The prefix shared by the stats of all series
+pub fn stats_prefix() -> Vec<u8> {
+  vec![STATS_TAG]
+}
+
+pub fn stats_key(series_id: SeriesId) -> Vec<u8> {
+  let mut key = stats_prefix();
+  key.extend_from_slice(&encode_series_id(series_id));
+  key
+}
+
+pub fn decode_stats_series_id(key: &[u8]) -> Option<SeriesId> {
+  if key.first() != Some(&STATS_TAG) {
+    return None;
+  }
+  decode_series_id(&key[1..])
+}
+
+pub fn log_key(sequence: u64) -> [u8; 8] {
+  sequence.to_be_bytes()
+}
+
+pub fn decode_log_sequence(key: &[u8]) -> Option<u64> {
+  let mut buffer = [0; 8];
+  if key.len() != buffer.len() {
+    return None;
+  }
+  buffer.copy_from_slice(key);
+  Some(u64::from_be_bytes(buffer))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_time_roundtrip() {
+    let times = [
+      Utc.timestamp(0, 0),
+      Utc.timestamp(1_560_000_000, 123_456_789),
+      Utc.timestamp(-1, 999_999_999),
+      Utc.timestamp(-1_560_000_000, 1),
+    ];
+
+    for time in times {
+      assert_eq!(decode_time(&encode_time(&time)), Some(time));
+    }
+  }
+
+  #[test]
+  fn test_time_out_of_range() {
+    let earliest = nanos_to_time(i64::MIN);
+    let latest = nanos_to_time(i64::MAX);
+    let before = Utc.ymd(1000, 1, 1).and_hms(0, 0, 0);
+    let after = Utc.ymd(3000, 1, 1).and_hms(0, 0, 0);
+
+    assert_eq!(checked_time_to_nanos(&latest), Some(i64::MAX));
+    assert_eq!(checked_time_to_nanos(&before), None);
+    assert_eq!(checked_time_to_nanos(&after), None);
+    assert_eq!(decode_time(&encode_time(&before)), Some(earliest));
+    assert_eq!(decode_time(&encode_time(&after)), Some(latest));
+    assert!(point_key(1, &before) < point_key(1, &Utc.timestamp(0, 0)));
+  }
+
+  #[test]
+  fn test_point_key_order() {
+    let times = [
+      Utc.timestamp(-1_560_000_000, 0),
+      Utc.timestamp(-1, 500_000_000),
+      Utc.timestamp(0, 0),
+      Utc.timestamp(1_560_000_000, 0),
+      Utc.timestamp(1_560_000_000, 1),
+      Utc.timestamp(1_560_000_000, 100_000_000),
+      Utc.timestamp(1_560_000_001, 0),
+    ];
+
+    let keys = times
+      .iter()
+      .map(|time| point_key(1, time))
+      .collect::<Vec<_>>();
+    let mut sorted_keys = keys.clone();
+    sorted_keys.sort();
+
+    assert_eq!(keys, sorted_keys);
+  }
+
+  #[test]
+  fn test_point_prefix_collision() {
+    let time = Utc.timestamp(1_560_000_000, 0);
+    let key = point_key(256, &time);
+
+    assert!(!key.starts_with(&point_prefix(1)));
+    assert!(key.starts_with(&point_prefix(256)));
+    assert_eq!(decode_point_time(&key), Some(time));
+  }
+
+  #[test]
+  fn test_tag_index_key() {
+    let key = tag_index_key("host", "web-1", 256);
+
+    assert!(key.starts_with(&tag_key_prefix("host")));
+    assert!(key.starts_with(&tag_prefix("host", "web-1")));
+    assert!(!key.starts_with(&tag_prefix("host", "web")));
+    assert!(!key.starts_with(&tag_key_prefix("hos")));
+    assert_eq!(decode_tag_index_key(&key), Some(("web-1".to_string(), 256)));
+  }
+
+  #[test]
+  fn test_series_id_roundtrip() {
+    for series_id in &[0, 1, 256, SeriesId::MAX] {
+      assert_eq!(
+        decode_series_id(&encode_series_id(*series_id)),
+        Some(*series_id)
+      );
+    }
+  }
+}
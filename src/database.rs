@@ -1,15 +1,100 @@
+use crate::annotations::Annotations;
+use crate::block;
+use crate::catalog::{Catalog, SeriesId};
+use crate::codec::{self, KeyKind};
+use crate::entities::aggregation::AggregationFunction;
+use crate::entities::annotation::{Annotation, AnnotationId, NewAnnotation};
+use crate::entities::duration::Duration;
 use crate::entities::point::StoragePoint;
-use crate::entities::point::{NewPoint, Point, QueryOptions};
-use crate::entities::series::{NewSeries, Series};
-use bincode::{deserialize, serialize};
-use rocksdb::{Direction, IteratorMode, WriteBatch, DB};
+use crate::entities::point::{NewPoint, Point, QueryOptions, SeriesPoints};
+use crate::entities::series::{
+  DuplicatePolicy, NewSeries, Series, SeriesStats, CURRENT_STORAGE_VERSION,
+};
+use crate::entities::tag::{Matcher, Tag, TagMatcher};
+use crate::entities::value::{Value, ValueType};
+use crate::migration;
+use crate::retention::{Dropped, RetentionTable};
+use crate::shard;
+use crate::stats::StatsTable;
+use crate::storage::encrypted::{EncryptedBackend, Keyring};
+use crate::storage::memory::MemoryBackend;
+use crate::storage::read_only::ReadOnlyBackend;
+use crate::storage::replicated::{LogEntry, ReplicatedBackend};
+use crate::storage::rocks::BackupEngine;
+use crate::storage::rocks::RocksDbBackend;
+use crate::storage::{iter_prefix, Batch, Durability, KeyValue, Operation, StorageBackend};
+use crate::storage_options::RocksDbConfig;
+use bincode::deserialize;
+use chrono::{DateTime, Utc};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
 use std::fmt;
+use std::iter::Peekable;
 use std::path::Path;
-use std::str;
+use std::sync::Arc;
+
+/// Keys written before column families were used
+pub const DEFAULT_CF: &str = "default";
+/// Series metadata and the catalog
+pub const SERIES_CF: &str = "series";
+/// Points as they were written
+pub const POINTS_CF: &str = "points";
+/// Points aggregated by the janitor
+pub const ROLLUPS_CF: &str = "rollups";
+/// Points sealed into compressed blocks
+pub const BLOCKS_CF: &str = "blocks";
+/// Keys the integrity check could not read, under the name of their column
+/// family and a zero byte
+pub const QUARANTINE_CF: &str = "quarantine";
+/// The log of write batches followers replicate
+pub const REPLICATION_CF: &str = "replication";
+
+const COLUMN_FAMILIES: [&str; 7] = [
+  DEFAULT_CF,
+  SERIES_CF,
+  POINTS_CF,
+  ROLLUPS_CF,
+  BLOCKS_CF,
+  QUARANTINE_CF,
+  REPLICATION_CF,
+];
+
+/// The column family named `name`, if there is one
+pub fn column_family(name: &str) -> Option<&'static str> {
+  COLUMN_FAMILIES.iter().find(|cf| **cf == name).cloned()
+}
 
 #[derive(PartialEq, Debug, Clone)]
 pub enum Error {
   SeriesMissing(String),
+  SeriesExists(String),
+  InvalidShardDuration(Duration),
+  UnsupportedFormatVersion(i32),
+  UnsupportedStorageVersion(String, i32),
+  Unsupported(&'static str),
+  ValueTypeMismatch(String, ValueType),
+  UnsupportedAggregation(AggregationFunction, ValueType),
+  AggregationOverflow(String),
+  InvalidFields(&'static str),
+  UnknownField(String, String),
+  MissingField(String, String),
+  FieldValueTypeMismatch(String, String, ValueType),
+  DuplicateTag(String),
+  DuplicatePoint(String, DateTime<Utc>),
+  TimeOutOfRange(DateTime<Utc>),
+  InvalidMatcher(String),
+  InvalidAnnotation(&'static str),
+  EncryptionKeyMissing,
+  WrongEncryptionKey(String),
+  NotEncrypted,
+  ReadOnly(&'static str),
+  Following(&'static str),
+  NotFollowing,
+  ReplicationDisabled,
+  LogTrimmed(u64),
+  Replication(String),
+  Storage(String),
+  Corrupted(String),
   Inner(rocksdb::Error),
 }
 
@@ -18,90 +103,370 @@ impl fmt::Display for Error {
     match self {
       Error::Inner(error) => error.fmt(f),
       Error::SeriesMissing(series_name) => write!(f, "Series \"{}\" do not exist", series_name),
+      Error::SeriesExists(series_name) => write!(f, "Series \"{}\" already exist", series_name),
+      Error::InvalidShardDuration(shard_duration) => write!(
+        f,
+        "Shard duration {} {} is not a multiple of 2 hours",
+        shard_duration.value, shard_duration.time_unit
+      ),
+      Error::UnsupportedFormatVersion(version) => write!(
+        f,
+        "Database format version {} is newer than the supported version {}",
+        version,
+        migration::CURRENT_FORMAT_VERSION
+      ),
+      Error::Unsupported(feature) => {
+        write!(f, "{} are not supported by the storage backend", feature)
+      }
+      Error::ValueTypeMismatch(series_name, value_type) => write!(
+        f,
+        "Series \"{}\" only holds {} values",
+        series_name, value_type
+      ),
+      Error::UnsupportedAggregation(function, value_type) => {
+        write!(f, "{:?} can not aggregate {} values", function, value_type)
+      }
+      Error::AggregationOverflow(series_name) => write!(
+        f,
+        "A sum of integers of series \"{}\" does not fit a 64-bit integer",
+        series_name
+      ),
+      Error::InvalidFields(reason) => write!(f, "Invalid fields: {}", reason),
+      Error::UnknownField(series_name, field_name) => write!(
+        f,
+        "Series \"{}\" has no field \"{}\"",
+        series_name, field_name
+      ),
+      Error::MissingField(series_name, field_name) => write!(
+        f,
+        "Point lacks field \"{}\" of series \"{}\"",
+        field_name, series_name
+      ),
+      Error::FieldValueTypeMismatch(series_name, field_name, value_type) => write!(
+        f,
+        "Field \"{}\" of series \"{}\" only holds {} values",
+        field_name, series_name, value_type
+      ),
+      Error::DuplicateTag(key) => write!(f, "Tag \"{}\" is given more than once", key),
+      Error::DuplicatePoint(series_name, time) => write!(
+        f,
+        "Series \"{}\" already has a point at {}",
+        series_name, time
+      ),
+      Error::TimeOutOfRange(time) => write!(
+        f,
+        "Time {} is out of the range that can be stored, from {} to {}",
+        time,
+        codec::nanos_to_time(i64::MIN),
+        codec::nanos_to_time(i64::MAX)
+      ),
+      Error::InvalidMatcher(reason) => write!(f, "Invalid tag matcher: {}", reason),
+      Error::InvalidAnnotation(reason) => write!(f, "Invalid annotation: {}", reason),
+      Error::EncryptionKeyMissing => write!(f, "Database is encrypted, but no key is given"),
+      Error::WrongEncryptionKey(fingerprint) => write!(
+        f,
+        "None of the given keys can decrypt the database, it is encrypted with the key with fingerprint {}",
+        fingerprint
+      ),
+      Error::ReadOnly(action) => write!(f, "Database is opened read-only and can not {}", action),
+      Error::Following(action) => {
+        write!(f, "Database follows a primary and can not {}", action)
+      }
+      Error::NotFollowing => write!(f, "Database does not follow a primary"),
+      Error::ReplicationDisabled => write!(f, "Replication is not configured"),
+      Error::LogTrimmed(sequence) => write!(
+        f,
+        "Write batches after {} are no longer in the replication log, seed the follower from a backup",
+        sequence
+      ),
+      Error::Replication(reason) => write!(f, "Could not replicate: {}", reason),
+      Error::Storage(message) => write!(f, "{}", message),
+      Error::Corrupted(message) => write!(
+        f,
+        "{}. `kakoidb fsck --repair-rocksdb` salvages what it can, but may lose recent writes",
+        message
+      ),
+      Error::NotEncrypted => write!(
+        f,
+        "Database is not encrypted, rotate the key to encrypt it"
+      ),
+      Error::UnsupportedStorageVersion(series_name, version) => write!(
+        f,
+        "Series \"{}\" storage version {} is newer than the supported version {}",
+        series_name, version, CURRENT_STORAGE_VERSION
+      ),
+    }
+  }
+}
+
+/// How many stored points a delete removes, the size of their keys and
+/// values, and the range of their values as numbers
+#[derive(Default, Clone, Copy)]
+pub struct Deleted {
+  pub points: usize,
+  pub bytes: u64,
+  min: Option<f64>,
+  max: Option<f64>,
+}
+
+impl Deleted {
+  fn add(&mut self, key: &[u8], value: &[u8]) {
+    self.points += 1;
+    self.bytes += (key.len() + value.len()) as u64;
+  }
+
+  fn add_value(&mut self, value: &Value) {
+    if let Some(value) = value.as_f64() {
+      self.min = Some(self.min.map_or(value, |min| min.min(value)));
+      self.max = Some(self.max.map_or(value, |max| max.max(value)));
+    }
+  }
+
+  /// Adds every point of a block
+  fn count_block(&mut self, value_type: ValueType, key: &[u8], value: &[u8]) {
+    self.points += block::point_count(value).unwrap_or(0) as usize;
+    self.bytes += (key.len() + value.len()) as u64;
+    for (_, bits) in block::decode(value).unwrap_or_default() {
+      if let Some(value) = Value::from_bits(value_type, bits) {
+        self.add_value(&value);
+      }
     }
   }
 }
 
 pub struct Database {
-  db: DB,
+  db: Box<dyn StorageBackend>,
+  /// Shared with snapshots, which only swap the storage
+  catalog: Arc<Catalog>,
+  annotations: Arc<Annotations>,
+  /// Unused by read-only databases, which read the statistics they store
+  stats: Arc<StatsTable>,
+  retention: RetentionTable,
+  /// The durability of point writes that do not ask for one
+  durability: Durability,
+  /// When a read-only database read the data on disk, none if the database
+  /// can be written
+  caught_up: Option<DateTime<Utc>>,
+  replication: Option<ReplicatedBackend>,
 }
 
 impl Database {
-  pub fn open<P: AsRef<Path>>(path: P) -> Database {
-    Database {
-      db: DB::open_default(path).unwrap(),
+  /// Opens the RocksDB database at `path`
+  pub fn open<P: AsRef<Path>>(path: P, config: &RocksDbConfig) -> Result<Database, Error> {
+    let retention = RetentionTable::default();
+    let backend = RocksDbBackend::open(path, config, &retention)?;
+    EncryptedBackend::refuse_encrypted(&backend)?;
+    Database::with_backend(Box::new(backend), retention)
+  }
+
+  /// Opens the RocksDB database at `path`, with its values encrypted by
+  /// `keys`
+  pub fn open_encrypted<P: AsRef<Path>>(
+    path: P,
+    config: &RocksDbConfig,
+    keys: Keyring,
+  ) -> Result<Database, Error> {
+    let keys = Arc::new(keys);
+    let retention = RetentionTable::encrypted(keys.clone());
+    let backend = RocksDbBackend::open(path, config, &retention)?;
+    let backend = EncryptedBackend::new(Box::new(backend), keys)?;
+    Database::with_backend(Box::new(backend), retention)
+  }
+
+  /// Opens the RocksDB database at `path` for reading only, while another
+  /// process may keep writing to it. `keys` are needed if it is encrypted.
+  pub fn open_read_only<P: AsRef<Path>>(
+    path: P,
+    config: &RocksDbConfig,
+    keys: Option<Keyring>,
+  ) -> Result<Database, Error> {
+    let backend = ReadOnlyBackend::open(path, config)?;
+    let backend: Box<dyn StorageBackend> = match keys {
+      Some(keys) => Box::new(EncryptedBackend::new(Box::new(backend), Arc::new(keys))?),
+      None => {
+        EncryptedBackend::refuse_encrypted(&backend)?;
+        Box::new(backend)
+      }
+    };
+    Database::with_backend(backend, Default::default())
+  }
+
+  /// Encrypts every value of the RocksDB database at `path` with the current
+  /// key of `keys`, and returns the number of values that were encrypted
+  pub fn rotate_key<P: AsRef<Path>>(
+    path: P,
+    config: &RocksDbConfig,
+    keys: Keyring,
+  ) -> Result<usize, Error> {
+    let backend = RocksDbBackend::open(path, config, &Default::default())?;
+    let (_, encrypted) = EncryptedBackend::rotate(Box::new(backend), Arc::new(keys))?;
+    Ok(encrypted)
+  }
+
+  /// Repairs the corrupted RocksDB database at `path`, losing what can not
+  /// be salvaged
+  pub fn repair_rocksdb<P: AsRef<Path>>(path: P, config: &RocksDbConfig) -> Result<(), Error> {
+    warn!("Repairing the database, some recent writes may be lost");
+    RocksDbBackend::repair(path, config)
+  }
+
+  /// A database that only lives in memory
+  pub fn in_memory() -> Database {
+    Database::with_backend(Box::new(MemoryBackend::new()), Default::default()).unwrap()
+  }
+
+  /// `retention` is filled with the retention policies of the series, for
+  /// backends that drop expired points by themselves
+  pub fn with_backend(
+    db: Box<dyn StorageBackend>,
+    retention: RetentionTable,
+  ) -> Result<Database, Error> {
+    let catalog = Arc::new(Catalog::load(db.as_ref())?);
+    let caught_up = if db.is_read_only() {
+      migration::check(db.as_ref())?;
+      Some(Utc::now())
+    } else {
+      migration::migrate(db.as_ref(), &catalog)?;
+      None
+    };
+    let annotations = Arc::new(Annotations::load(db.as_ref())?);
+    let stats = Arc::new(StatsTable::load(db.as_ref())?);
+
+    let database = Database {
+      db,
+      catalog,
+      annotations,
+      stats,
+      retention,
+      durability: Durability::default(),
+      caught_up,
+      replication: None,
+    };
+    for series in database.list_series()? {
+      database.retention.set(&series);
+      if caught_up.is_none() {
+        // Series written before statistics were kept
+        if database.stats.get(series.id).is_none() {
+          database.refresh_stats(&series)?;
+        }
+        // What compactions dropped before the database was closed was lost
+        database.retention.mark_uncounted(series.id);
+      }
     }
+    Ok(database)
   }
 
-  fn iter_prefix(&self, key_prefix: String) -> impl Iterator<Item = (Box<[u8]>, Box<[u8]>)> + '_ {
-    let key_prefix_bytes = key_prefix.into_bytes();
-    let prefix_length = key_prefix_bytes.len();
+  fn iter_series(&self) -> impl Iterator<Item = Result<KeyValue, Error>> + '_ {
+    iter_prefix(self.db.as_ref(), SERIES_CF, codec::series_prefix())
+  }
 
-    self
-      .db
-      .iterator(IteratorMode::From(&key_prefix_bytes, Direction::Forward))
-      .take_while(move |(key, _)| &key[..prefix_length] == key_prefix_bytes.as_slice())
+  /// Decodes a stored series and fills in its statistics. Series that can
+  /// not be decoded are left out, for the integrity check to report.
+  fn decode_series(&self, bytes: &[u8]) -> Option<Series> {
+    let mut series: Series = match deserialize(bytes) {
+      Ok(series) => series,
+      Err(err) => {
+        warn!("Could not parse a series. It is excluded from the result");
+        debug!("Parse error: {:?}", err);
+        return None;
+      }
+    };
+    let stats = if self.is_read_only() {
+      StatsTable::read(self.db.as_ref(), series.id)
+    } else {
+      self.stats.get(series.id)
+    };
+    series.stats = stats.unwrap_or_default();
+    Some(series)
   }
 
-  fn iter_series(&self) -> impl Iterator<Item = (Box<[u8]>, Box<[u8]>)> + '_ {
-    self.iter_prefix("series::".to_string())
+  /// Recomputes the statistics of the series from its stored points
+  pub fn refresh_stats(&self, series: &Series) -> Result<(), Error> {
+    let mut guard = self.stats.lock();
+    // Whatever compactions dropped so far is not counted anymore
+    self.retention.take_dropped(series.id);
+
+    let mut stats = SeriesStats::default();
+    for point in self.iter_points(series, None) {
+      stats.add(&point?, true);
+    }
+    let stored = [
+      (POINTS_CF, codec::point_prefix(series.id)),
+      (ROLLUPS_CF, codec::point_prefix(series.id)),
+      (BLOCKS_CF, codec::block_prefix(series.id)),
+    ];
+    for (cf, prefix) in stored.iter() {
+      for item in iter_prefix(self.db.as_ref(), cf, prefix.clone()) {
+        let (key, value) = item?;
+        stats.bytes += (key.len() + value.len()) as u64;
+      }
+    }
+
+    let mut batch = Batch::default();
+    guard.set(&mut batch, series.id, stats);
+    guard.write(self.db.as_ref(), batch)
   }
 
-  fn iter_points_serialized(
+  fn iter_points_serialized_cf(
     &self,
-    series_name: &str,
-    options: Option<QueryOptions>,
-  ) -> impl Iterator<Item = (Box<[u8]>, Box<[u8]>)> + '_ {
-    let options = options.unwrap_or_default();
+    cf: &str,
+    series_id: SeriesId,
+    options: &QueryOptions,
+  ) -> impl Iterator<Item = Result<KeyValue, Error>> + '_ {
+    let prefix = codec::point_prefix(series_id);
     let start_key = match options.since {
-      Some(since) => format!("points::{}::{}", series_name, since.to_rfc3339()),
-      None => format!("points::{}::", series_name),
-    }
-    .into_bytes();
-    let end_key = match options.until {
-      Some(until) => format!("points::{}::{}", series_name, until.to_rfc3339()),
-      None => format!("points::{}:;", series_name),
-    }
-    .into_bytes();
+      Some(since) => codec::point_key(series_id, &since),
+      None => prefix.clone(),
+    };
+    let end_key = options
+      .until
+      .map(|until| codec::point_key(series_id, &until));
 
     self
       .db
-      .iterator(IteratorMode::From(&start_key, Direction::Forward))
-      .take_while(move |(key, _)| **key <= *end_key.as_slice())
+      .iter_from(cf, &start_key)
+      .take_while(move |item| match (item, &end_key) {
+        (Ok((key, _)), Some(end_key)) => **key <= *end_key.as_slice(),
+        (Ok((key, _)), None) => key.starts_with(&prefix),
+        (Err(_), _) => true,
+      })
   }
 
-  pub fn iter_points(
+  fn iter_points_cf(
     &self,
-    series_name: &str,
-    options: Option<QueryOptions>,
-  ) -> impl Iterator<Item = Point> + '_ {
-    let prefix_length = format!("points::{}::", series_name).len();
+    cf: &str,
+    series: &Series,
+    options: &QueryOptions,
+  ) -> impl Iterator<Item = Result<Point, Error>> + '_ {
+    let value_type = series.value_type;
+    let fields = series.fields.clone();
+
     self
-      .iter_points_serialized(&series_name, options.clone())
-      .filter_map(move |(key, value)| {
-        let key = String::from_utf8_lossy(&key[prefix_length..]);
+      .iter_points_serialized_cf(cf, series.id, options)
+      .filter_map(move |item| {
+        let (key, value) = match item {
+          Ok(item) => item,
+          Err(error) => return Some(Err(error)),
+        };
+        let time = match codec::decode_point_time(&key) {
+          Some(time) => time,
+          None => {
+            warn!(
+              "Could not parse key {:?} as a point key. It is excluded from the result",
+              key
+            );
+            return None;
+          }
+        };
 
-        Some(Point {
-          time: match key.parse() {
-            Ok(time) => time,
-            Err(err) => {
-              warn!(
-                "Could not parse key \"{}\" as date. It is excluded from the result",
-                key
-              );
-              debug!(
-                "Parse error: {:?}",
-                err
-              );
-              return None;
-            }
-          },
-          value: match deserialize::<StoragePoint>(&value) {
+        Some(Ok(Point {
+          time,
+          value: match StoragePoint::decode(value_type, &fields, &value) {
             Ok(point) => point.value,
             Err(err) => {
               warn!(
-                "Could not parse value of key \"{}\" as a StoragePoint. It is excluded from the result",
-                key
+                "Could not parse value of point at {} as a StoragePoint. It is excluded from the result",
+                time
               );
               debug!(
                 "Parse error: {:?}",
@@ -110,47 +475,656 @@ impl Database {
               return None;
             }
           },
-        })
+        }))
+      })
+  }
+
+  /// Iterates over the blocks that may contain points within the range of
+  /// `options`
+  fn iter_blocks_serialized(
+    &self,
+    series_id: SeriesId,
+    options: &QueryOptions,
+  ) -> impl Iterator<Item = Result<KeyValue, Error>> + '_ {
+    let prefix = codec::block_prefix(series_id);
+    let start_key = match options.since {
+      Some(since) => codec::block_key(series_id, &block::block_start(&since)),
+      None => prefix.clone(),
+    };
+    let end_key = options
+      .until
+      .map(|until| codec::block_key(series_id, &until));
+
+    self
+      .db
+      .iter_from(BLOCKS_CF, &start_key)
+      .take_while(move |item| match (item, &end_key) {
+        (Ok((key, _)), Some(end_key)) => **key <= *end_key.as_slice(),
+        (Ok((key, _)), None) => key.starts_with(&prefix),
+        (Err(_), _) => true,
       })
   }
 
+  fn iter_block_points(
+    &self,
+    series: &Series,
+    options: &QueryOptions,
+  ) -> impl Iterator<Item = Result<Point, Error>> + '_ {
+    let range = options.clone();
+    let value_type = series.value_type;
+
+    self
+      .iter_blocks_serialized(series.id, options)
+      .flat_map(move |item| {
+        let (key, value) = match item {
+          Ok(item) => item,
+          Err(error) => return vec![Err(error)],
+        };
+        match block::decode(&value) {
+          Ok(points) => points.into_iter().map(Ok).collect(),
+          Err(err) => {
+            warn!(
+              "Could not decode block {:?}. It is excluded from the result",
+              codec::decode_block_start(&key)
+            );
+            debug!("Decode error: {:?}", err);
+            vec![]
+          }
+        }
+      })
+      .filter_map(move |item| match item {
+        Ok((time, bits)) => Some(Ok(Point {
+          time: codec::nanos_to_time(time),
+          value: Value::from_bits(value_type, bits)?,
+        })),
+        Err(error) => Some(Err(error)),
+      })
+      .filter(move |point| match point {
+        Ok(point) => range.contains(&point.time),
+        Err(_) => true,
+      })
+  }
+
+  /// Iterates over raw points, sealed blocks and rollups in chronological
+  /// order. Should several of them contain a point at the same time, raw
+  /// points win over blocks and blocks over rollups.
+  pub fn iter_points(
+    &self,
+    series: &Series,
+    options: Option<QueryOptions>,
+  ) -> impl Iterator<Item = Result<Point, Error>> + '_ {
+    let options = options.unwrap_or_default();
+
+    MergeByTime {
+      left: self.iter_points_cf(POINTS_CF, series, &options).peekable(),
+      right: MergeByTime {
+        left: self.iter_block_points(series, &options).peekable(),
+        right: self.iter_points_cf(ROLLUPS_CF, series, &options).peekable(),
+      }
+      .peekable(),
+    }
+  }
+
+  /// Adds deletes for all points within the range of `options` to `batch`
+  /// and returns how many stored points they remove. Raw points, rollups and
+  /// blocks entirely within the range are removed with range deletes, blocks
+  /// only partially within the range are rewritten without the points.
+  /// Blocks partially within the range that can not be decoded are kept.
+  pub fn delete_points(
+    &self,
+    batch: &mut Batch,
+    series: &Series,
+    options: &QueryOptions,
+  ) -> Result<Deleted, Error> {
+    let series_id = series.id;
+    let (from, to) = point_range(series_id, options);
+    let mut deleted = self.count_points(series, options, false)?;
+    for cf in &[POINTS_CF, ROLLUPS_CF] {
+      batch.delete_range(cf, &from, &to);
+    }
+
+    let mut covered_blocks: Option<(Vec<u8>, Vec<u8>)> = None;
+    for item in self.iter_blocks_serialized(series_id, options) {
+      let (key, value) = item?;
+      let start = match codec::decode_block_start(&key) {
+        Some(start) => start,
+        None => continue,
+      };
+      let end = start + chrono::Duration::nanoseconds(block::BLOCK_SPAN - 1);
+
+      if options.contains(&start) && options.contains(&end) {
+        deleted.count_block(series.value_type, &key, &value);
+        covered_blocks = Some(match covered_blocks {
+          Some((first, _)) => (first, key.to_vec()),
+          None => (key.to_vec(), key.to_vec()),
+        });
+        continue;
+      }
+
+      let (removed, remaining): (Vec<_>, Vec<_>) = match block::decode(&value) {
+        Ok(points) => points
+          .into_iter()
+          .partition(|(time, _)| options.contains(&codec::nanos_to_time(*time))),
+        Err(err) => {
+          warn!(
+            "Left block {} of series {} in place, it can not be decoded: {:?}",
+            start, series_id, err
+          );
+          continue;
+        }
+      };
+      deleted.points += removed.len();
+      for (_, bits) in &removed {
+        if let Some(value) = Value::from_bits(series.value_type, *bits) {
+          deleted.add_value(&value);
+        }
+      }
+
+      trace!("Rewriting block {}", start);
+      if remaining.is_empty() {
+        deleted.bytes += (key.len() + value.len()) as u64;
+        batch.delete(BLOCKS_CF, &key);
+      } else if !removed.is_empty() {
+        let encoded = block::encode(remaining);
+        deleted.bytes += value.len().saturating_sub(encoded.len()) as u64;
+        batch.put(BLOCKS_CF, &key, encoded);
+      }
+    }
+
+    // Blocks entirely within the range are contiguous
+    if let Some((first, last)) = covered_blocks {
+      trace!("Deleting blocks {:?} to {:?}", first, last);
+      batch.delete_range(BLOCKS_CF, first, codec::prefix_end(&last));
+    }
+
+    Ok(deleted)
+  }
+
+  /// The number and size of the raw points and rollups within the range of
+  /// `options`, and of the blocks if `blocks`. Blocks are counted whole,
+  /// even if they are only partially within the range.
+  fn count_points(
+    &self,
+    series: &Series,
+    options: &QueryOptions,
+    blocks: bool,
+  ) -> Result<Deleted, Error> {
+    let (from, to) = point_range(series.id, options);
+    let mut counted = Deleted::default();
+
+    for cf in &[POINTS_CF, ROLLUPS_CF] {
+      for item in self.db.iter_from(cf, &from) {
+        let (key, value) = item?;
+        if *key >= *to {
+          break;
+        }
+        counted.add(&key, &value);
+        if let Ok(point) = StoragePoint::decode(series.value_type, &series.fields, &value) {
+          counted.add_value(&point.value);
+        }
+      }
+    }
+    if blocks {
+      for item in self.iter_blocks_serialized(series.id, options) {
+        let (key, value) = item?;
+        counted.count_block(series.value_type, &key, &value);
+      }
+    }
+
+    Ok(counted)
+  }
+
+  /// Takes the points deleted from the range of `options` out of `stats`.
+  /// The points outside of the range are only read if the first or last
+  /// point, or the smallest or largest value, was deleted.
+  fn subtract_deleted(
+    &self,
+    series: &Series,
+    options: &QueryOptions,
+    deleted: Deleted,
+    stats: &mut SeriesStats,
+  ) -> Result<(), Error> {
+    stats.count = stats.count.saturating_sub(deleted.points as u64);
+    stats.bytes = stats.bytes.saturating_sub(deleted.bytes);
+    if stats.count == 0 {
+      *stats = SeriesStats::default();
+      return Ok(());
+    }
+
+    let one = chrono::Duration::nanoseconds(1);
+    let before = options.since.map(|since| {
+      self.iter_points(
+        series,
+        Some(QueryOptions::with(|before| {
+          before.until = Some(since - one)
+        })),
+      )
+    });
+    let after = options.until.map(|until| {
+      self.iter_points(
+        series,
+        Some(QueryOptions::with(|after| after.since = Some(until + one))),
+      )
+    });
+
+    let extreme_deleted = deleted
+      .min
+      .is_some_and(|min| stats.min.is_none_or(|m| min <= m))
+      || deleted
+        .max
+        .is_some_and(|max| stats.max.is_none_or(|m| max >= m));
+    if extreme_deleted {
+      let mut left = SeriesStats::default();
+      for point in before
+        .into_iter()
+        .flatten()
+        .chain(after.into_iter().flatten())
+      {
+        left.add(&point?, false);
+      }
+      stats.first = left.first;
+      stats.last = left.last;
+      stats.min = left.min;
+      stats.max = left.max;
+      return Ok(());
+    }
+
+    // Points within the range can be neither before the first nor after the
+    // last point left
+    if stats.first.is_some_and(|first| options.contains(&first)) {
+      stats.first = after
+        .and_then(|mut after| after.next())
+        .transpose()?
+        .map(|point| point.time);
+    }
+    if stats.last.is_some_and(|last| options.contains(&last)) {
+      stats.last = match before {
+        Some(mut before) => {
+          before.try_fold(None, |_, point| point.map(|point| Some(point.time)))?
+        }
+        None => None,
+      };
+    }
+    Ok(())
+  }
+
+  /// Deletes the points of the series within the range of `options` and
+  /// writes `rollups` in their place, together with the statistics of the
+  /// series. Returns the number of points that were deleted.
+  pub fn replace_points(
+    &self,
+    series: &Series,
+    options: &QueryOptions,
+    rollups: Vec<Point>,
+  ) -> Result<usize, Error> {
+    let mut stats = self.stats.lock();
+    let mut batch = Batch::default();
+    let deleted = self.delete_points(&mut batch, series, options)?;
+
+    let mut series_stats = stats.get(series.id);
+    self.subtract_deleted(series, options, deleted, &mut series_stats)?;
+    for rollup in &rollups {
+      let key = codec::point_key(series.id, &rollup.time);
+      let value = StoragePoint {
+        value: rollup.value.clone(),
+      }
+      .encode();
+      series_stats.add(rollup, true);
+      series_stats.bytes += (key.len() + value.len()) as u64;
+      batch.put(ROLLUPS_CF, key, value);
+    }
+    stats.set(&mut batch, series.id, series_stats);
+    stats.write(self.db.as_ref(), batch)?;
+
+    Ok(deleted.points)
+  }
+
+  /// Compacts the key ranges `delete_points` removed points from
+  fn compact_points(&self, series_id: SeriesId, options: &QueryOptions) {
+    let (from, to) = point_range(series_id, options);
+    for cf in &[POINTS_CF, ROLLUPS_CF] {
+      self.db.compact_range(cf, &from, &to);
+    }
+
+    let from = match options.since {
+      Some(since) => codec::block_key(series_id, &block::block_start(&since)),
+      None => codec::block_prefix(series_id),
+    };
+    let to = match options.until {
+      Some(until) => codec::prefix_end(&codec::block_key(series_id, &until)),
+      None => codec::prefix_end(&codec::block_prefix(series_id)),
+    };
+    self.db.compact_range(BLOCKS_CF, &from, &to);
+  }
+
+  /// Moves the raw points of all blocks that ended before `until` into
+  /// compressed blocks. Returns the number of points that were sealed.
+  /// Series of strings, fields or histograms are never sealed.
+  pub fn seal_blocks(&mut self, series: &Series, until: DateTime<Utc>) -> Result<usize, Error> {
+    if let ValueType::String | ValueType::Fields | ValueType::Histogram = series.value_type {
+      return Ok(0);
+    }
+    let series_id = series.id;
+    let until = block::block_start(&until);
+    let options = QueryOptions::with(|options| {
+      options.until = Some(until - chrono::Duration::nanoseconds(1));
+    });
+
+    let mut stats = self.stats.lock();
+    let mut series_stats = stats.get(series_id);
+    let mut batch = Batch::default();
+    let mut count = 0;
+    {
+      let mut raw_points = self.iter_points_cf(POINTS_CF, series, &options).peekable();
+
+      while let Some(first) = raw_points.next() {
+        let first = first?;
+        let start = block::block_start(&first.time);
+        let end = start + chrono::Duration::nanoseconds(block::BLOCK_SPAN);
+        let mut points = vec![first];
+        // An error is returned by the next block
+        while let Some(Ok(point)) = raw_points.peek() {
+          if point.time >= end {
+            break;
+          }
+          points.push(raw_points.next().unwrap()?);
+        }
+
+        for point in &points {
+          let key = codec::point_key(series_id, &point.time);
+          let value = StoragePoint {
+            value: point.value.clone(),
+          }
+          .encode();
+          series_stats.bytes = series_stats
+            .bytes
+            .saturating_sub((key.len() + value.len()) as u64);
+          batch.delete(POINTS_CF, key);
+        }
+        count += points.len();
+
+        // Late points are merged into the block that was already sealed
+        let block_key = codec::block_key(series_id, &start);
+        let sealed = match self.db.get(BLOCKS_CF, &block_key)? {
+          Some(value) => {
+            series_stats.bytes = series_stats
+              .bytes
+              .saturating_sub((block_key.len() + value.len()) as u64);
+            block::decode(&value).unwrap_or_else(|err| {
+              warn!("Could not decode block {}, it is replaced", start);
+              debug!("Decode error: {:?}", err);
+              vec![]
+            })
+          }
+          None => vec![],
+        };
+        let sealed = sealed.into_iter().filter_map(|(time, bits)| {
+          Some(Ok(Point {
+            time: codec::nanos_to_time(time),
+            value: Value::from_bits(series.value_type, bits)?,
+          }))
+        });
+        let merged = MergeByTime {
+          left: points.into_iter().map(Ok).peekable(),
+          right: sealed.peekable(),
+        };
+
+        // The points are only moved, so only their size changes
+        debug!("Sealing block {}", start);
+        let encoded = block::encode(
+          merged
+            .flatten()
+            .filter_map(|point| Some((codec::time_to_nanos(&point.time), point.value.to_bits()?))),
+        );
+        series_stats.bytes += (block_key.len() + encoded.len()) as u64;
+        batch.put(BLOCKS_CF, &block_key, encoded);
+      }
+    }
+
+    stats.set(&mut batch, series_id, series_stats);
+    stats.write(self.db.as_ref(), batch)?;
+    Ok(count)
+  }
+
+  /// Drops every shard of the series that ended before `until`. Returns the
+  /// number of shards that were dropped.
+  pub fn drop_shards(&self, series: &Series, until: DateTime<Utc>) -> Result<usize, Error> {
+    let mut stats = self.stats.lock();
+    let mut batch = Batch::default();
+    let mut count = 0;
+    let mut dropped_until = None;
+    let mut series_stats = stats.get(series.id);
+
+    // The compaction filter drops the oldest points, so the first point has
+    // to be found again. The smallest and largest values are left as they are.
+    let dropped = self.retention.take_dropped(series.id);
+    let (points, bytes) = match dropped {
+      Dropped::Counted(points, bytes) => (points, bytes),
+      Dropped::Uncounted => (0, 0),
+    };
+    if points > 0 {
+      series_stats.count = series_stats.count.saturating_sub(points);
+      series_stats.bytes = series_stats.bytes.saturating_sub(bytes);
+      if series_stats.count == 0 {
+        series_stats = SeriesStats::default();
+      } else {
+        series_stats.first = self
+          .iter_points(series, None)
+          .next()
+          .transpose()?
+          .map(|point| point.time);
+      }
+    }
+
+    for item in iter_prefix(self.db.as_ref(), SERIES_CF, codec::shard_prefix(series.id)) {
+      let (key, _) = item?;
+      let start = match codec::decode_shard_start(&key) {
+        Some(start) => start,
+        None => continue,
+      };
+      // Shards are iterated from the oldest
+      let end = shard::shard_end(&start, &series.shard_duration);
+      if end > until {
+        break;
+      }
+
+      debug!("Dropping shard {} of series {}", start, series.name);
+      shard::drop_shard(&mut batch, series.id, &start, &series.shard_duration);
+      dropped_until = Some(end);
+      count += 1;
+    }
+
+    // Shards are dropped from the oldest, so every point before the end of
+    // the last dropped shard is gone
+    if let Some(end) = dropped_until {
+      let options = QueryOptions::with(|options| {
+        options.until = Some(end - chrono::Duration::nanoseconds(1));
+      });
+      let deleted = self.count_points(series, &options, true)?;
+      self.subtract_deleted(series, &options, deleted, &mut series_stats)?;
+    }
+    if points > 0 || dropped_until.is_some() {
+      stats.set(&mut batch, series.id, series_stats);
+    }
+    stats.write(self.db.as_ref(), batch)?;
+
+    if dropped == Dropped::Uncounted {
+      warn!(
+        "The points dropped from series {} were not counted, refreshing its statistics",
+        series.name
+      );
+      self.refresh_stats(series)?;
+    }
+    Ok(count)
+  }
+
   pub fn list_series(&self) -> Result<Vec<Series>, Error> {
+    self
+      .iter_series()
+      .filter_map(|item| match item {
+        Ok((_, value)) => self.decode_series(&value).map(Ok),
+        Err(error) => Some(Err(error)),
+      })
+      .collect()
+  }
+
+  /// The series with tags matching every matcher
+  pub fn select_series(&self, matchers: &[TagMatcher]) -> Result<Vec<Series>, Error> {
+    let matchers = matchers
+      .iter()
+      .map(Matcher::compile)
+      .collect::<Result<Vec<_>, _>>()
+      .map_err(|error| Error::InvalidMatcher(error.to_string()))?;
+
+    // Only series with a tag matched by every plain matcher can be selected
+    let mut candidates: Option<BTreeSet<SeriesId>> = None;
+    for matcher in matchers.iter().filter(|matcher| !matcher.is_negated()) {
+      let series_ids = self.catalog.lookup(self.db.as_ref(), matcher)?;
+      candidates = Some(match candidates {
+        Some(candidates) => candidates.intersection(&series_ids).cloned().collect(),
+        None => series_ids,
+      });
+    }
+
+    let series = match candidates {
+      Some(series_ids) => series_ids
+        .into_iter()
+        .filter_map(|series_id| {
+          self
+            .db
+            .get(SERIES_CF, &codec::series_key(series_id))
+            .map(|series| series.and_then(|series| self.decode_series(&series)))
+            .transpose()
+        })
+        .collect::<Result<Vec<Series>, _>>()?,
+      None => self.list_series()?,
+    };
+
     Ok(
-      self
-        .iter_series()
-        .map(|(_, value)| deserialize(&value).unwrap())
+      series
+        .into_iter()
+        .filter(|series| matchers.iter().all(|matcher| matcher.matches(&series.tags)))
         .collect(),
     )
   }
 
-  pub fn get_series(&self, name: &str) -> Result<Option<Series>, rocksdb::Error> {
-    match self.db.get(&format!("series::{}", name).into_bytes())? {
-      Some(series) => Ok(Some(deserialize(&series).unwrap())),
+  pub fn get_series(&self, name: &str) -> Result<Option<Series>, Error> {
+    let series_id = match self.catalog.resolve(self.db.as_ref(), name)? {
+      Some(series_id) => series_id,
+      None => return Ok(None),
+    };
+    match self.db.get(SERIES_CF, &codec::series_key(series_id))? {
+      Some(series) => Ok(self.decode_series(&series)),
       None => Ok(None),
     }
   }
 
-  pub fn create_series(&self, new_series: NewSeries) -> Result<Series, rocksdb::Error> {
-    let series = Series::from(new_series);
-    self.db.put(
-      &format!("series::{}", &series.name).into_bytes(),
-      &serialize(&series).unwrap(),
-    )?;
+  pub fn create_series(&self, new_series: NewSeries) -> Result<Series, Error> {
+    if let Some(shard_duration) = &new_series.shard_duration {
+      if !shard::is_valid_shard_duration(shard_duration) {
+        return Err(Error::InvalidShardDuration(shard_duration.clone()));
+      }
+    }
+    let mut tag_keys = HashSet::new();
+    for tag in new_series.tags.iter().flatten() {
+      if !tag_keys.insert(&tag.key) {
+        return Err(Error::DuplicateTag(tag.key.clone()));
+      }
+    }
+    let value_types = match (&new_series.fields, new_series.value_type) {
+      (None, Some(ValueType::Fields)) => {
+        return Err(Error::InvalidFields("Series of fields need fields"))
+      }
+      (None, value_type) => vec![value_type.unwrap_or_default()],
+      (Some(_), Some(value_type)) if value_type != ValueType::Fields => {
+        return Err(Error::InvalidFields("Series with fields hold fields"))
+      }
+      (Some(fields), _) => {
+        if fields.is_empty() {
+          return Err(Error::InvalidFields("Series with fields need a field"));
+        }
+        let names = fields
+          .iter()
+          .map(|field| &field.name)
+          .collect::<HashSet<_>>();
+        if names.len() != fields.len() {
+          return Err(Error::InvalidFields("Field names must be unique"));
+        }
+        if fields
+          .iter()
+          .any(|field| field.value_type == ValueType::Fields)
+        {
+          return Err(Error::InvalidFields("Fields can not hold fields"));
+        }
+        fields.iter().map(|field| field.value_type).collect()
+      }
+    };
+    let compact = new_series
+      .retention_policy
+      .iter()
+      .flat_map(|policy| policy.compact.iter().flatten());
+    for strategy in compact {
+      check_aggregation(strategy.aggregate.function, &value_types)?;
+    }
+    if new_series.duplicate_policy == Some(DuplicatePolicy::Sum) {
+      check_aggregation(AggregationFunction::Sum, &value_types)?;
+    }
+    let series = self.catalog.create(self.db.as_ref(), new_series)?;
+    self.retention.set(&series);
+    self.refresh_stats(&series)?;
+    Ok(series)
+  }
+
+  pub fn rename_series(&self, series_name: &str, new_name: &str) -> Result<Series, Error> {
+    let mut series = self
+      .get_series(series_name)?
+      .ok_or_else(|| Error::SeriesMissing(series_name.to_string()))?;
+    self
+      .catalog
+      .rename(self.db.as_ref(), &mut series, new_name)?;
     Ok(series)
   }
 
-  pub fn delete_series(&self, series_name: &str) -> Result<(), rocksdb::Error> {
-    let points = self.iter_points_serialized(series_name, None);
+  /// Deletes the series and all its points. Returns the number of points
+  /// that were deleted.
+  pub fn delete_series(&self, series_name: &str) -> Result<usize, Error> {
+    let series = match self.get_series(series_name)? {
+      Some(series) => series,
+      None => return Ok(0),
+    };
+    let options = QueryOptions::default();
+    let shard_prefix = codec::shard_prefix(series.id);
 
-    let mut batch = WriteBatch::default();
+    let mut stats = self.stats.lock();
+    let mut batch = Batch::default();
+    stats.remove(&mut batch, series.id);
+    self.catalog.remove(&mut batch, &series);
+    self
+      .annotations
+      .remove(self.db.as_ref(), &mut batch, series.id)?;
+    batch.delete_range(
+      SERIES_CF,
+      shard_prefix.clone(),
+      codec::prefix_end(&shard_prefix),
+    );
+    let count = self.delete_points(&mut batch, &series, &options)?.points;
+    stats.write(self.db.as_ref(), batch)?;
 
-    batch.delete(&format!("series::{}", series_name).into_bytes())?;
+    self.retention.remove(series.id);
+    self.compact_points(series.id, &options);
+    Ok(count)
+  }
 
-    for (point, _) in points {
-      batch.delete(&point)?;
-    }
+  /// Deletes the points of a series within the range of `options`. Returns
+  /// the number of points that were deleted.
+  pub fn delete_by_query(&self, series_name: &str, options: QueryOptions) -> Result<usize, Error> {
+    let series = self
+      .get_series(series_name)?
+      .ok_or_else(|| Error::SeriesMissing(series_name.to_string()))?;
 
-    self.db.write(batch)
+    let count = self.replace_points(&series, &options, vec![])?;
+    self.compact_points(series.id, &options);
+    Ok(count)
   }
 
   pub fn query(
@@ -158,118 +1132,558 @@ impl Database {
     series_name: &str,
     options: Option<QueryOptions>,
   ) -> Result<Vec<Point>, Error> {
-    let mut points = self.iter_points(&series_name, options.clone());
+    match self.get_series(series_name)? {
+      Some(series) => self.query_series(&series, options),
+      None => Ok(vec![]),
+    }
+  }
+
+  /// Queries every series with tags matching every matcher
+  pub fn query_by_tags(
+    &self,
+    matchers: &[TagMatcher],
+    options: Option<QueryOptions>,
+  ) -> Result<Vec<SeriesPoints>, Error> {
+    self
+      .select_series(matchers)?
+      .into_iter()
+      .map(|series| {
+        let points = self.query_series(&series, options.clone())?;
+        Ok(SeriesPoints { series, points })
+      })
+      .collect()
+  }
+
+  fn query_series(
+    &self,
+    series: &Series,
+    options: Option<QueryOptions>,
+  ) -> Result<Vec<Point>, Error> {
     let options = options.unwrap_or_default();
+    let value_types = match &options.fields {
+      Some(names) => names
+        .iter()
+        .map(|name| {
+          series
+            .fields
+            .iter()
+            .find(|field| field.name == *name)
+            .map(|field| field.value_type)
+            .ok_or_else(|| Error::UnknownField(series.name.clone(), name.clone()))
+        })
+        .collect::<Result<Vec<_>, _>>()?,
+      None => series.value_types(),
+    };
+    if let Some(aggregation) = &options.aggregate {
+      check_aggregation(aggregation.function, &value_types)?;
+    }
+
+    let selected = options.fields.clone();
+    let mut points = self
+      .iter_points(series, Some(options.clone()))
+      .map(move |point| {
+        let point = point?;
+        Ok(match &selected {
+          Some(selected) => Point {
+            time: point.time,
+            value: select_fields(point.value, selected),
+          },
+          None => point,
+        })
+      });
 
     let points = match options
       .aggregate
       .and_then(|a| points.next().map(|p| (a, p)))
     {
       Some((aggregation, first)) => {
+        let first = first?;
         let duration = (&aggregation.over).into();
+        let function = aggregation.function;
+        let finish = |partial, count| {
+          function
+            .finish(partial, count)
+            .ok_or_else(|| Error::AggregationOverflow(series.name.clone()))
+        };
         let mut count = 1;
         let mut start_time = first.time;
-        let mut value = first.value;
-
-        let mut points: Vec<Point> = points
-          .filter_map(|point| {
-            if point.time - start_time >= duration {
-              let aggregated_point = Point {
-                time: start_time,
-                value: aggregation.function.finish(value, count),
-              };
-              count = 1;
-              start_time = point.time;
-              value = point.value;
-              Some(aggregated_point)
-            } else {
-              count += 1;
-              value = aggregation.function.reduce(value, point.value);
-              None
-            }
-          })
-          .collect();
+        let mut partial = function.start(first.value);
 
-        points.push(Point {
+        let mut aggregated_points = vec![];
+        for point in points {
+          let point = point?;
+          if point.time - start_time >= duration {
+            aggregated_points.push(Point {
+              time: start_time,
+              value: finish(partial, count)?,
+            });
+            count = 1;
+            start_time = point.time;
+            partial = function.start(point.value);
+          } else {
+            count += 1;
+            partial = function.reduce(partial, point.value);
+          }
+        }
+
+        aggregated_points.push(Point {
           time: start_time,
-          value: aggregation.function.finish(value, count),
+          value: finish(partial, count)?,
         });
 
-        points
+        aggregated_points
       }
-      None => points.collect(),
+      None => points.collect::<Result<_, _>>()?,
     };
 
     Ok(points)
   }
 
-  pub fn write(&mut self, batch: WriteBatch) -> Result<(), rocksdb::Error> {
-    self.db.write(batch)
+  pub fn backend(&self) -> &dyn StorageBackend {
+    self.db.as_ref()
   }
 
-  pub fn delete_by_query(
-    &mut self,
-    series_name: &str,
-    options: Option<QueryOptions>,
-  ) -> Result<(), rocksdb::Error> {
-    let mut batch = WriteBatch::default();
-    let points = self.iter_points_serialized(series_name, options);
+  pub fn backup(&self, engine: &mut BackupEngine) -> Result<(), Error> {
+    self.db.backup(engine)
+  }
 
-    for (point, _) in points {
-      println!("Deleting {}", str::from_utf8(&point).unwrap());
-      batch.delete(&point)?;
-    }
+  pub fn is_read_only(&self) -> bool {
+    self.caught_up.is_some()
+  }
 
-    self.db.write(batch)
+  /// If the database applies the writes of a primary, and refuses others
+  pub fn is_following(&self) -> bool {
+    self
+      .replication
+      .as_ref()
+      .is_some_and(ReplicatedBackend::is_following)
   }
 
-  pub fn create_point(&self, series_name: &str, new_point: NewPoint) -> Result<Point, Error> {
-    if self
-      .db
-      .get(&format!("series::{}", series_name).into_bytes())
-      .map_err(Error::Inner)?
-      .is_none()
-    {
-      return Err(Error::SeriesMissing(series_name.to_string()));
+  /// When a read-only database read the data on disk, or a follower had
+  /// applied every write of its primary. None if the database is written to.
+  fn caught_up(&self) -> Option<DateTime<Utc>> {
+    match &self.replication {
+      Some(replication) if replication.is_following() => Some(replication.status().caught_up),
+      _ => self.caught_up,
     }
+  }
 
-    let point = StoragePoint {
-      value: new_point.value,
-    };
-    self
-      .db
-      .put(
-        &format!("points::{}::{}", &series_name, &new_point.time.to_rfc3339()).into_bytes(),
-        &serialize(&point).unwrap(),
-      )
-      .map_err(Error::Inner)?;
-    Ok(Point {
-      time: new_point.time,
-      value: new_point.value,
-    })
+  /// How far behind the writer a read-only database or a follower may be
+  pub fn lag(&self) -> Option<chrono::Duration> {
+    self.caught_up().map(|caught_up| Utc::now() - caught_up)
   }
-}
 
-#[cfg(test)]
-mod tests {
-  use super::*;
-  use crate::entities::duration::Duration;
-  use crate::entities::point::{NewPoint, Point};
-  use crate::entities::series::{NewSeries, Series};
-  use chrono::Utc;
-  use tempdir::TempDir;
+  /// A database reading a snapshot of this one, so that everything read
+  /// from it is consistent, however this one is written meanwhile. The
+  /// snapshot is read-only and lags behind from when it was taken.
+  pub fn snapshot(&self) -> Result<Database, Error> {
+    let db = self.db.snapshot();
+    Ok(Database {
+      catalog: self.catalog.clone(),
+      annotations: self.annotations.clone(),
+      stats: Default::default(),
+      retention: Default::default(),
+      durability: self.durability,
+      caught_up: Some(self.caught_up().unwrap_or_else(Utc::now)),
+      replication: None,
+      db,
+    })
+  }
 
+  /// Logs the writes to the database for followers, keeping the last
+  /// `log_size` batches. If `following` a primary, only the batches of the
+  /// primary are written until the database is promoted.
+  pub fn with_replication(self, log_size: u64, following: bool) -> Result<Database, Error> {
+    if self.is_read_only() {
+      return Err(Error::ReadOnly("be replicated"));
+    }
+    let replication = ReplicatedBackend::new(self.db, log_size, following)?;
+    Ok(Database {
+      db: Box::new(replication.clone()),
+      replication: Some(replication),
+      ..self
+    })
+  }
+
+  pub fn replication(&self) -> Option<&ReplicatedBackend> {
+    self.replication.as_ref()
+  }
+
+  /// Writes a batch of the primary, keeping the retention policies of the
+  /// series it writes
+  pub fn apply(&self, entry: &LogEntry) -> Result<(), Error> {
+    let replication = self.replication.as_ref().ok_or(Error::NotFollowing)?;
+    for operation in replication.apply(entry)? {
+      match operation {
+        Operation::Put { cf, key, value }
+          if cf == SERIES_CF && codec::key_kind(&key) == Some(KeyKind::Series) =>
+        {
+          if let Ok(series) = deserialize::<Series>(&value) {
+            self.retention.set(&series);
+          }
+        }
+        Operation::Delete { cf, key }
+          if cf == SERIES_CF && codec::key_kind(&key) == Some(KeyKind::Series) =>
+        {
+          if let Some(series_id) = codec::decode_series_key(&key) {
+            self.retention.remove(series_id);
+          }
+        }
+        _ => (),
+      }
+    }
+    Ok(())
+  }
+
+  /// Stops following the primary, so that the database can be written. What
+  /// is kept in memory is loaded again, as the primary wrote it meanwhile.
+  pub fn promote(&mut self) -> Result<(), Error> {
+    self
+      .replication
+      .as_ref()
+      .ok_or(Error::NotFollowing)?
+      .promote()?;
+
+    self.catalog = Arc::new(Catalog::load(self.db.as_ref())?);
+    self.annotations = Arc::new(Annotations::load(self.db.as_ref())?);
+    self.stats = Arc::new(StatsTable::load(self.db.as_ref())?);
+    for series in self.list_series()? {
+      self.retention.set(&series);
+      // The primary counted what it dropped, but not what compactions
+      // dropped here
+      self.retention.mark_uncounted(series.id);
+    }
+    Ok(())
+  }
+
+  pub fn set_durability(&mut self, durability: Durability) {
+    self.durability = durability;
+  }
+
+  pub fn create_annotation(&self, new_annotation: NewAnnotation) -> Result<Annotation, Error> {
+    let series_id = match &new_annotation.series_name {
+      Some(series_name) => Some(
+        self
+          .catalog
+          .resolve(self.db.as_ref(), series_name)?
+          .ok_or_else(|| Error::SeriesMissing(series_name.to_string()))?,
+      ),
+      None => None,
+    };
+    let until = new_annotation.until.unwrap_or(new_annotation.since);
+    check_time(&new_annotation.since)?;
+    check_time(&until)?;
+    if until < new_annotation.since {
+      return Err(Error::InvalidAnnotation("It can not end before it starts"));
+    }
+    let mut tags = new_annotation
+      .tags
+      .unwrap_or_default()
+      .into_iter()
+      .map(Tag::from)
+      .collect::<Vec<_>>();
+    tags.sort_by(|a, b| a.key.cmp(&b.key));
+    if let Some(pair) = tags.windows(2).find(|pair| pair[0].key == pair[1].key) {
+      return Err(Error::DuplicateTag(pair[0].key.clone()));
+    }
+
+    let since = new_annotation.since;
+    let title = new_annotation.title;
+    let text = new_annotation.text;
+    self.annotations.create(self.db.as_ref(), |id| Annotation {
+      id,
+      series_id,
+      since,
+      until,
+      title,
+      text,
+      tags,
+    })
+  }
+
+  /// The annotations overlapping the range of `options`, ordered by their
+  /// start. Global annotations apply to every series, so the annotations of
+  /// a series include them.
+  pub fn list_annotations(
+    &self,
+    series_name: Option<&str>,
+    options: &QueryOptions,
+  ) -> Result<Vec<Annotation>, Error> {
+    let db = self.db.as_ref();
+    let mut annotations = self
+      .annotations
+      .list(db, None, options.since, options.until)?;
+    if let Some(series_name) = series_name {
+      let series_id = self
+        .catalog
+        .resolve(db, series_name)?
+        .ok_or_else(|| Error::SeriesMissing(series_name.to_string()))?;
+      annotations.extend(self.annotations.list(
+        db,
+        Some(series_id),
+        options.since,
+        options.until,
+      )?);
+      annotations.sort_by(|a, b| a.since.cmp(&b.since).then(a.id.cmp(&b.id)));
+    }
+    Ok(annotations)
+  }
+
+  /// Returns if there was an annotation with the id
+  pub fn delete_annotation(&self, annotation_id: AnnotationId) -> Result<bool, Error> {
+    self.annotations.delete(self.db.as_ref(), annotation_id)
+  }
+
+  /// Writes a point with `durability`, or the database's durability if none.
+  /// Returns the point as it is stored.
+  pub fn create_point(
+    &self,
+    series_name: &str,
+    new_point: NewPoint,
+    durability: Option<Durability>,
+  ) -> Result<Point, Error> {
+    let mut points = self.create_points(series_name, vec![new_point], durability)?;
+    Ok(points.remove(0))
+  }
+
+  /// Writes the points in a single batch, either all of them or none.
+  /// Points at the same time as another point, written before or in the
+  /// same batch, are handled by the duplicate policy of the series. Returns
+  /// the points as they are stored, in chronological order.
+  pub fn create_points(
+    &self,
+    series_name: &str,
+    new_points: Vec<NewPoint>,
+    durability: Option<Durability>,
+  ) -> Result<Vec<Point>, Error> {
+    let series = self
+      .get_series(series_name)?
+      .ok_or_else(|| Error::SeriesMissing(series_name.to_string()))?;
+    // Also keeps concurrent writes from checking for duplicates at once
+    let mut stats = self.stats.lock();
+
+    // The value at each time and whether it has to be written
+    let mut points: BTreeMap<DateTime<Utc>, (Value, bool)> = BTreeMap::new();
+    // The times there was no point stored at
+    let mut created = HashSet::new();
+    for new_point in new_points {
+      check_time(&new_point.time)?;
+      let value = check_value(&series, new_point.value)?;
+      let time = series.precision.truncate(&new_point.time);
+      let existing = match points.remove(&time) {
+        Some(point) => Some(point),
+        None => {
+          let existing = self.get_point(&series, &time)?;
+          if existing.is_none() {
+            created.insert(time);
+          }
+          existing.map(|point| (point.value, false))
+        }
+      };
+      let point = match (series.duplicate_policy, existing) {
+        (DuplicatePolicy::Overwrite, _) | (_, None) => (value, true),
+        (DuplicatePolicy::Reject, Some(_)) => return Err(Error::DuplicatePoint(series.name, time)),
+        (DuplicatePolicy::KeepFirst, Some(existing)) => existing,
+        (DuplicatePolicy::Sum, Some((existing, _))) => {
+          let sum = AggregationFunction::Sum;
+          let value = sum
+            .finish(sum.reduce(sum.start(existing), value), 2)
+            .ok_or_else(|| Error::AggregationOverflow(series.name.clone()))?;
+          (value, true)
+        }
+      };
+      points.insert(time, point);
+    }
+
+    let points = points
+      .into_iter()
+      .map(|(time, (value, written))| (Point { time, value }, written))
+      .collect::<Vec<_>>();
+    let mut batch = Batch::with_durability(durability.unwrap_or(self.durability));
+    let mut series_stats = stats.get(series.id);
+    for (point, written) in &points {
+      if !written {
+        continue;
+      }
+      let shard_start = shard::shard_start(&point.time, &series.shard_duration);
+      let key = codec::point_key(series.id, &point.time);
+      let value = StoragePoint {
+        value: point.value.clone(),
+      }
+      .encode();
+
+      let new = created.contains(&point.time);
+      series_stats.add(point, new);
+      if new {
+        series_stats.bytes += (key.len() + value.len()) as u64;
+      }
+      batch.put(POINTS_CF, key, value);
+      batch.put(SERIES_CF, codec::shard_key(series.id, &shard_start), []);
+    }
+    stats.set(&mut batch, series.id, series_stats);
+    stats.write(self.db.as_ref(), batch)?;
+
+    Ok(points.into_iter().map(|(point, _)| point).collect())
+  }
+
+  /// The point of the series at exactly `time`, wherever it is stored
+  fn get_point(&self, series: &Series, time: &DateTime<Utc>) -> Result<Option<Point>, Error> {
+    let options = QueryOptions::with(|options| {
+      options.since = Some(*time);
+      options.until = Some(*time);
+    });
+    self.iter_points(series, Some(options)).next().transpose()
+  }
+}
+
+fn check_aggregation(
+  function: AggregationFunction,
+  value_types: &[ValueType],
+) -> Result<(), Error> {
+  match value_types
+    .iter()
+    .find(|&&value_type| !function.applies_to(value_type))
+  {
+    Some(&value_type) => Err(Error::UnsupportedAggregation(function, value_type)),
+    None => Ok(()),
+  }
+}
+
+/// Checks that `time` can be stored in a key
+fn check_time(time: &DateTime<Utc>) -> Result<(), Error> {
+  match codec::checked_time_to_nanos(time) {
+    Some(_) => Ok(()),
+    None => Err(Error::TimeOutOfRange(*time)),
+  }
+}
+
+/// Checks that `value` fits the value type of `series`, and puts the values
+/// of fields in the order of the fields of the series
+fn check_value(series: &Series, value: Value) -> Result<Value, Error> {
+  let mut field_values = match value {
+    Value::Fields(field_values) if series.value_type == ValueType::Fields => field_values,
+    value if value.value_type() == series.value_type => return Ok(value),
+    _ => {
+      return Err(Error::ValueTypeMismatch(
+        series.name.clone(),
+        series.value_type,
+      ))
+    }
+  };
+
+  let mut ordered = Vec::with_capacity(series.fields.len());
+  for field in &series.fields {
+    let index = field_values
+      .iter()
+      .position(|field_value| field_value.name == field.name)
+      .ok_or_else(|| Error::MissingField(series.name.clone(), field.name.clone()))?;
+    let field_value = field_values.swap_remove(index);
+    if field_value.value.value_type() != field.value_type {
+      return Err(Error::FieldValueTypeMismatch(
+        series.name.clone(),
+        field.name.clone(),
+        field.value_type,
+      ));
+    }
+    ordered.push(field_value);
+  }
+  if let Some(unknown) = field_values.into_iter().next() {
+    return Err(Error::UnknownField(series.name.clone(), unknown.name));
+  }
+
+  Ok(Value::Fields(ordered))
+}
+
+/// Keeps only the fields of `value` named in `selected`
+fn select_fields(value: Value, selected: &[String]) -> Value {
+  match value {
+    Value::Fields(fields) => Value::Fields(
+      fields
+        .into_iter()
+        .filter(|field| selected.contains(&field.name))
+        .collect(),
+    ),
+    value => value,
+  }
+}
+
+/// The range of point keys `[from, to)` within the range of `options`
+fn point_range(series_id: SeriesId, options: &QueryOptions) -> (Vec<u8>, Vec<u8>) {
+  let from = match options.since {
+    Some(since) => codec::point_key(series_id, &since),
+    None => codec::point_prefix(series_id),
+  };
+  let to = match options.until {
+    Some(until) => codec::prefix_end(&codec::point_key(series_id, &until)),
+    None => codec::prefix_end(&codec::point_prefix(series_id)),
+  };
+  (from, to)
+}
+
+/// Merges two chronologically ordered iterators of points. Points of the
+/// left iterator win over points at the same time in the right iterator.
+struct MergeByTime<L: Iterator, R: Iterator> {
+  left: Peekable<L>,
+  right: Peekable<R>,
+}
+
+impl<L, R> Iterator for MergeByTime<L, R>
+where
+  L: Iterator<Item = Result<Point, Error>>,
+  R: Iterator<Item = Result<Point, Error>>,
+{
+  type Item = Result<Point, Error>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    let ordering = match (self.left.peek(), self.right.peek()) {
+      // Errors are passed on first
+      (Some(Err(_)), _) => Ordering::Less,
+      (_, Some(Err(_))) => Ordering::Greater,
+      (Some(Ok(left)), Some(Ok(right))) => left.time.cmp(&right.time),
+      (Some(_), None) => Ordering::Less,
+      (None, Some(_)) => Ordering::Greater,
+      (None, None) => return None,
+    };
+
+    match ordering {
+      Ordering::Less => self.left.next(),
+      Ordering::Greater => self.right.next(),
+      Ordering::Equal => {
+        self.right.next();
+        self.left.next()
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::entities::aggregation::NewAggregationStrategy;
+  use crate::entities::duration::Duration;
+  use crate::entities::histogram::Histogram;
+  use crate::entities::point::{NewPoint, Point};
+  use crate::entities::precision::Precision;
+  use crate::entities::series::{
+    NewCompactionStrategy, NewField, NewRetentionPolicy, NewSeries, Series,
+  };
+  use crate::entities::tag::{MatchOperator, NewTag, Tag};
+  use crate::entities::value::FieldValue;
+  use crate::storage::Operation;
+  use chrono::{TimeZone, Utc};
+  use std::sync::{Arc, Mutex};
+  use tempdir::TempDir;
+
+  /// Runs the test against the in-memory and the RocksDB backend
   fn db_test<T>(test: T)
   where
-    T: FnOnce(&Database) -> (),
+    T: Fn(&mut Database),
   {
-    let tmp_dir = TempDir::new("kakoi_db_test").unwrap();
-    let db_path = tmp_dir.path().join("db");
-    let db = Database::open(db_path);
-
-    test(&db);
+    test(&mut Database::in_memory());
 
-    tmp_dir.close().unwrap();
+    let tmp_dir = TempDir::new("kakoi_db_test").unwrap();
+    let mut db = Database::open(tmp_dir.path().join("db"), &Default::default()).unwrap();
+    test(&mut db);
   }
 
   #[test]
@@ -277,25 +1691,88 @@ mod tests {
     db_test(|db| {
       let created_series = db.create_series(NewSeries {
         name: "test-series".to_string(),
-        retention_policy: None,
+        ..Default::default()
       });
 
       assert_eq!(
         created_series,
-        Ok(Series::from(NewSeries {
-          name: "test-series".to_string(),
-          retention_policy: None,
-        }))
+        Ok(Series::new(
+          1,
+          NewSeries {
+            name: "test-series".to_string(),
+            ..Default::default()
+          }
+        ))
       );
 
       let all_series = db.list_series();
 
       assert_eq!(
         all_series,
-        Ok(vec![Series::from(NewSeries {
-          name: "test-series".to_string(),
-          retention_policy: None,
-        })])
+        Ok(vec![Series::new(
+          1,
+          NewSeries {
+            name: "test-series".to_string(),
+            ..Default::default()
+          }
+        )])
+      );
+    });
+  }
+
+  #[test]
+  fn test_create_series_exists() {
+    db_test(|db| {
+      db.create_series(NewSeries {
+        name: "test-series".to_string(),
+        ..Default::default()
+      })
+      .unwrap();
+
+      let created_series = db.create_series(NewSeries {
+        name: "test-series".to_string(),
+        ..Default::default()
+      });
+
+      assert_eq!(
+        created_series,
+        Err(Error::SeriesExists("test-series".to_string()))
+      );
+    });
+  }
+
+  #[test]
+  fn test_rename_series() {
+    db_test(|db| {
+      db.create_series(NewSeries {
+        name: "test-series".to_string(),
+        ..Default::default()
+      })
+      .unwrap();
+
+      let now = Utc::now();
+      db.create_point(
+        "test-series",
+        NewPoint {
+          time: now,
+          value: Value::Float(1.0),
+        },
+        None,
+      )
+      .unwrap();
+
+      let renamed_series = db.rename_series("test-series", "renamed-series").unwrap();
+
+      assert_eq!(renamed_series.id, 1);
+      assert_eq!(renamed_series.name, "renamed-series");
+      assert_eq!(db.get_series("test-series"), Ok(None));
+      assert_eq!(db.query("test-series", None), Ok(vec![]));
+      assert_eq!(
+        db.query("renamed-series", None),
+        Ok(vec![Point {
+          time: now,
+          value: Value::Float(1.0)
+        }])
       );
     });
   }
@@ -305,7 +1782,7 @@ mod tests {
     db_test(|db| {
       db.create_series(NewSeries {
         name: "test-series".to_string(),
-        retention_policy: None,
+        ..Default::default()
       })
       .unwrap();
 
@@ -314,15 +1791,16 @@ mod tests {
         "test-series",
         NewPoint {
           time: now,
-          value: 1.0,
+          value: Value::Float(1.0),
         },
+        None,
       );
 
       assert_eq!(
         created_point,
         Ok(Point {
           time: now,
-          value: 1.0
+          value: Value::Float(1.0)
         })
       );
 
@@ -332,23 +1810,247 @@ mod tests {
         all_points,
         Ok(vec![Point {
           time: now,
-          value: 1.0
+          value: Value::Float(1.0)
         }])
       );
     });
   }
 
+  #[test]
+  fn test_create_point_out_of_range() {
+    db_test(|db| {
+      db.create_series(NewSeries {
+        name: "test-series".to_string(),
+        ..Default::default()
+      })
+      .unwrap();
+
+      let before = Utc.ymd(1000, 1, 1).and_hms(0, 0, 0);
+      let after = Utc.ymd(3000, 1, 1).and_hms(0, 0, 0);
+      let point = |time| NewPoint {
+        time,
+        value: Value::Float(1.0),
+      };
+      assert_eq!(
+        db.create_point("test-series", point(after), None),
+        Err(Error::TimeOutOfRange(after))
+      );
+      db.create_point("test-series", point(Utc.timestamp(0, 0)), None)
+        .unwrap();
+
+      // Query bounds out of range are clamped
+      let options = QueryOptions::with(|options| {
+        options.since = Some(before);
+        options.until = Some(after);
+      });
+      assert_eq!(db.query("test-series", Some(options)).unwrap().len(), 1);
+    });
+  }
+
+  #[test]
+  fn test_create_point_durability() {
+    let tmp_dir = TempDir::new("test_create_point_durability").unwrap();
+    let mut db = Database::open(tmp_dir.path().join("db"), &Default::default()).unwrap();
+    db.set_durability(Durability::Sync);
+    db.create_series(NewSeries {
+      name: "test-series".to_string(),
+      ..Default::default()
+    })
+    .unwrap();
+
+    let times = vec![
+      Utc.timestamp(1_000, 0),
+      Utc.timestamp(2_000, 0),
+      Utc.timestamp(3_000, 0),
+    ];
+    let durabilities = vec![None, Some(Durability::Async), Some(Durability::Disabled)];
+    for (time, durability) in times.iter().zip(durabilities) {
+      db.create_point(
+        "test-series",
+        NewPoint {
+          time: *time,
+          value: Value::Float(1.0),
+        },
+        durability,
+      )
+      .unwrap();
+    }
+
+    let queried_times = db
+      .query("test-series", None)
+      .unwrap()
+      .into_iter()
+      .map(|point| point.time)
+      .collect::<Vec<_>>();
+    assert_eq!(queried_times, times);
+  }
+
+  /// Records the durability of every batch that writes a point
+  struct DurabilityRecorder {
+    inner: MemoryBackend,
+    durabilities: Arc<Mutex<Vec<Durability>>>,
+  }
+
+  impl StorageBackend for DurabilityRecorder {
+    fn get(&self, cf: &str, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+      self.inner.get(cf, key)
+    }
+
+    fn put(&self, cf: &str, key: &[u8], value: &[u8]) -> Result<(), Error> {
+      self.inner.put(cf, key, value)
+    }
+
+    fn delete(&self, cf: &str, key: &[u8]) -> Result<(), Error> {
+      self.inner.delete(cf, key)
+    }
+
+    fn iter_from<'a>(
+      &'a self,
+      cf: &str,
+      start: &[u8],
+    ) -> Box<dyn Iterator<Item = Result<KeyValue, Error>> + 'a> {
+      self.inner.iter_from(cf, start)
+    }
+
+    fn write(&self, batch: Batch) -> Result<(), Error> {
+      let durability = batch.durability();
+      let operations = batch.into_operations();
+      if operations
+        .iter()
+        .any(|operation| matches!(operation, Operation::Put { cf, .. } if *cf == POINTS_CF))
+      {
+        self.durabilities.lock().unwrap().push(durability);
+      }
+
+      let mut batch = Batch::with_durability(durability);
+      for operation in operations {
+        batch.push(operation);
+      }
+      self.inner.write(batch)
+    }
+
+    fn snapshot(&self) -> Box<dyn StorageBackend> {
+      self.inner.snapshot()
+    }
+  }
+
+  #[test]
+  fn test_create_point_batch_durability() {
+    let durabilities = Arc::new(Mutex::new(vec![]));
+    let backend = DurabilityRecorder {
+      inner: MemoryBackend::new(),
+      durabilities: durabilities.clone(),
+    };
+    let mut db = Database::with_backend(Box::new(backend), Default::default()).unwrap();
+    db.set_durability(Durability::Sync);
+    db.create_series(NewSeries {
+      name: "test-series".to_string(),
+      ..Default::default()
+    })
+    .unwrap();
+
+    for (seconds, durability) in vec![None, Some(Durability::Async), Some(Durability::Disabled)]
+      .into_iter()
+      .enumerate()
+    {
+      db.create_point(
+        "test-series",
+        NewPoint {
+          time: Utc.timestamp(seconds as i64, 0),
+          value: Value::Float(1.0),
+        },
+        durability,
+      )
+      .unwrap();
+    }
+
+    assert_eq!(
+      *durabilities.lock().unwrap(),
+      vec![Durability::Sync, Durability::Async, Durability::Disabled]
+    );
+  }
+
+  #[test]
+  fn test_create_point_precision() {
+    db_test(|db| {
+      db.create_series(NewSeries {
+        name: "test-series".to_string(),
+        precision: Some(Precision::Milliseconds),
+        ..Default::default()
+      })
+      .unwrap();
+
+      let created_point = db.create_point(
+        "test-series",
+        NewPoint {
+          time: Utc.timestamp(1_560_000_000, 123_456_789),
+          value: Value::Float(1.0),
+        },
+        None,
+      );
+
+      assert_eq!(
+        created_point.map(|point| point.time),
+        Ok(Utc.timestamp(1_560_000_000, 123_000_000))
+      );
+    });
+  }
+
+  #[test]
+  fn test_query_nanosecond_order() {
+    db_test(|db| {
+      db.create_series(NewSeries {
+        name: "test-series".to_string(),
+        ..Default::default()
+      })
+      .unwrap();
+
+      // Written out of order, one nanosecond apart and across the epoch
+      let times = [
+        Utc.timestamp(0, 1),
+        Utc.timestamp(-1, 999_999_999),
+        Utc.timestamp(0, 0),
+        Utc.timestamp(0, 2),
+      ];
+      for (index, time) in times.iter().enumerate() {
+        db.create_point(
+          "test-series",
+          NewPoint {
+            time: *time,
+            value: Value::Float(index as f64),
+          },
+          None,
+        )
+        .unwrap();
+      }
+
+      let values = db
+        .query("test-series", None)
+        .unwrap()
+        .into_iter()
+        .map(|point| point.value)
+        .collect::<Vec<_>>();
+      assert_eq!(
+        values,
+        [1.0, 2.0, 0.0, 3.0]
+          .iter()
+          .map(|&value| Value::Float(value))
+          .collect::<Vec<_>>()
+      );
+    });
+  }
+
   #[test]
   fn test_create_point_series() {
     db_test(|db| {
       db.create_series(NewSeries {
         name: "test-series1".to_string(),
-        retention_policy: None,
+        ..Default::default()
       })
       .unwrap();
       db.create_series(NewSeries {
         name: "test-series2".to_string(),
-        retention_policy: None,
+        ..Default::default()
       })
       .unwrap();
 
@@ -357,8 +2059,9 @@ mod tests {
         "test-series1",
         NewPoint {
           time: now,
-          value: 1.0,
+          value: Value::Float(1.0),
         },
+        None,
       )
       .unwrap();
       let five_minutes_ago = Utc::now() - &Duration::from_string("5 minutes").unwrap();
@@ -366,8 +2069,9 @@ mod tests {
         "test-series2",
         NewPoint {
           time: five_minutes_ago,
-          value: 42.0,
+          value: Value::Float(42.0),
         },
+        None,
       )
       .unwrap();
 
@@ -377,7 +2081,7 @@ mod tests {
         test_series1,
         Ok(vec![Point {
           time: now,
-          value: 1.0
+          value: Value::Float(1.0)
         }])
       );
 
@@ -387,12 +2091,1052 @@ mod tests {
         test_series2,
         Ok(vec![Point {
           time: five_minutes_ago,
-          value: 42.0
+          value: Value::Float(42.0)
+        }])
+      );
+    });
+  }
+
+  #[test]
+  fn test_create_point_prefix_series() {
+    db_test(|db| {
+      db.create_series(NewSeries {
+        name: "cpu".to_string(),
+        ..Default::default()
+      })
+      .unwrap();
+      db.create_series(NewSeries {
+        name: "cpu::x".to_string(),
+        ..Default::default()
+      })
+      .unwrap();
+
+      let now = Utc::now();
+      db.create_point(
+        "cpu::x",
+        NewPoint {
+          time: now,
+          value: Value::Float(1.0),
+        },
+        None,
+      )
+      .unwrap();
+
+      assert_eq!(db.query("cpu", None), Ok(vec![]));
+      assert_eq!(
+        db.query("cpu::x", None),
+        Ok(vec![Point {
+          time: now,
+          value: Value::Float(1.0)
         }])
       );
     });
   }
 
+  #[test]
+  fn test_query_merges_rollups() {
+    db_test(|db| {
+      let series = db
+        .create_series(NewSeries {
+          name: "test-series".to_string(),
+          ..Default::default()
+        })
+        .unwrap();
+
+      let now = Utc::now();
+      let five_minutes_ago = now - &Duration::from_string("5 minutes").unwrap();
+      db.create_point(
+        "test-series",
+        NewPoint {
+          time: now,
+          value: Value::Float(1.0),
+        },
+        None,
+      )
+      .unwrap();
+
+      let mut batch = Batch::default();
+      batch.put(
+        ROLLUPS_CF,
+        codec::point_key(series.id, &five_minutes_ago),
+        StoragePoint {
+          value: Value::Float(42.0),
+        }
+        .encode(),
+      );
+      db.backend().write(batch).unwrap();
+
+      assert_eq!(
+        db.query("test-series", None),
+        Ok(vec![
+          Point {
+            time: five_minutes_ago,
+            value: Value::Float(42.0)
+          },
+          Point {
+            time: now,
+            value: Value::Float(1.0)
+          }
+        ])
+      );
+    });
+  }
+
+  #[test]
+  fn test_typed_values() {
+    db_test(|db| {
+      let counter = db
+        .create_series(NewSeries {
+          name: "counter".to_string(),
+          value_type: Some(ValueType::Int),
+          ..Default::default()
+        })
+        .unwrap();
+      let start = Utc.ymd(2019, 6, 10).and_hms(8, 0, 0);
+      for i in 0..3 {
+        db.create_point(
+          "counter",
+          NewPoint {
+            time: start + chrono::Duration::hours(i),
+            value: Value::Int(i64::MAX - 2 + i),
+          },
+          None,
+        )
+        .unwrap();
+      }
+      db.seal_blocks(&counter, start + chrono::Duration::hours(4))
+        .unwrap();
+
+      assert_eq!(
+        db.create_point(
+          "counter",
+          NewPoint {
+            time: start,
+            value: Value::Float(1.0),
+          },
+          None,
+        ),
+        Err(Error::ValueTypeMismatch(
+          "counter".to_string(),
+          ValueType::Int
+        ))
+      );
+      assert_eq!(
+        db.query(
+          "counter",
+          Some(QueryOptions::with(|options| {
+            options.aggregate = Some(NewAggregationStrategy {
+              function: AggregationFunction::Max,
+              over: Duration::from_string("1 day").unwrap(),
+            });
+          }))
+        ),
+        Ok(vec![Point {
+          time: start,
+          value: Value::Int(i64::MAX),
+        }])
+      );
+
+      let states = db.create_series(NewSeries {
+        name: "valve".to_string(),
+        retention_policy: Some(NewRetentionPolicy {
+          compact: Some(vec![NewCompactionStrategy {
+            after: Duration::from_string("1 day").unwrap(),
+            aggregate: NewAggregationStrategy {
+              function: AggregationFunction::Avg,
+              over: Duration::from_string("1 hour").unwrap(),
+            },
+          }]),
+          drop_after: None,
+        }),
+        value_type: Some(ValueType::String),
+        ..Default::default()
+      });
+
+      assert_eq!(
+        states,
+        Err(Error::UnsupportedAggregation(
+          AggregationFunction::Avg,
+          ValueType::String
+        ))
+      );
+    });
+  }
+
+  #[test]
+  fn test_fields() {
+    db_test(|db| {
+      let weather = db
+        .create_series(NewSeries {
+          name: "weather".to_string(),
+          fields: Some(vec![
+            NewField {
+              name: "temperature".to_string(),
+              value_type: ValueType::Float,
+            },
+            NewField {
+              name: "raining".to_string(),
+              value_type: ValueType::Bool,
+            },
+          ]),
+          ..Default::default()
+        })
+        .unwrap();
+      assert_eq!(weather.value_type, ValueType::Fields);
+
+      let field = |name: &str, value| FieldValue {
+        name: name.to_string(),
+        value,
+      };
+      let start = Utc.ymd(2019, 6, 10).and_hms(8, 0, 0);
+      for i in 0..2 {
+        let point = db
+          .create_point(
+            "weather",
+            NewPoint {
+              time: start + chrono::Duration::hours(i),
+              value: Value::Fields(vec![
+                field("raining", Value::Bool(i == 1)),
+                field("temperature", Value::Float(20.0 + i as f64)),
+              ]),
+            },
+            None,
+          )
+          .unwrap();
+        assert_eq!(
+          point.value,
+          Value::Fields(vec![
+            field("temperature", Value::Float(20.0 + i as f64)),
+            field("raining", Value::Bool(i == 1)),
+          ])
+        );
+      }
+
+      assert_eq!(
+        db.query(
+          "weather",
+          Some(QueryOptions::with(|options| {
+            options.fields = Some(vec!["raining".to_string()]);
+          }))
+        ),
+        Ok(vec![
+          Point {
+            time: start,
+            value: Value::Fields(vec![field("raining", Value::Bool(false))]),
+          },
+          Point {
+            time: start + chrono::Duration::hours(1),
+            value: Value::Fields(vec![field("raining", Value::Bool(true))]),
+          },
+        ])
+      );
+      assert_eq!(
+        db.query(
+          "weather",
+          Some(QueryOptions::with(|options| {
+            options.fields = Some(vec!["temperature".to_string()]);
+            options.aggregate = Some(NewAggregationStrategy {
+              function: AggregationFunction::Avg,
+              over: Duration::from_string("1 day").unwrap(),
+            });
+          }))
+        ),
+        Ok(vec![Point {
+          time: start,
+          value: Value::Fields(vec![field("temperature", Value::Float(20.5))]),
+        }])
+      );
+      assert_eq!(
+        db.query(
+          "weather",
+          Some(QueryOptions::with(|options| {
+            options.aggregate = Some(NewAggregationStrategy {
+              function: AggregationFunction::Avg,
+              over: Duration::from_string("1 day").unwrap(),
+            });
+          }))
+        ),
+        Err(Error::UnsupportedAggregation(
+          AggregationFunction::Avg,
+          ValueType::Bool
+        ))
+      );
+      assert_eq!(
+        db.query(
+          "weather",
+          Some(QueryOptions::with(|options| {
+            options.fields = Some(vec!["wind".to_string()]);
+          }))
+        ),
+        Err(Error::UnknownField(
+          "weather".to_string(),
+          "wind".to_string()
+        ))
+      );
+
+      let write = |value| {
+        db.create_point(
+          "weather",
+          NewPoint {
+            time: start,
+            value: Value::Fields(value),
+          },
+          None,
+        )
+      };
+      assert_eq!(
+        write(vec![field("temperature", Value::Float(1.0))]),
+        Err(Error::MissingField(
+          "weather".to_string(),
+          "raining".to_string()
+        ))
+      );
+      assert_eq!(
+        write(vec![
+          field("temperature", Value::Int(1)),
+          field("raining", Value::Bool(true)),
+        ]),
+        Err(Error::FieldValueTypeMismatch(
+          "weather".to_string(),
+          "temperature".to_string(),
+          ValueType::Float
+        ))
+      );
+      assert_eq!(
+        write(vec![
+          field("temperature", Value::Float(1.0)),
+          field("raining", Value::Bool(true)),
+          field("wind", Value::Float(3.0)),
+        ]),
+        Err(Error::UnknownField(
+          "weather".to_string(),
+          "wind".to_string()
+        ))
+      );
+    });
+  }
+
+  #[test]
+  fn test_tags() {
+    db_test(|db| {
+      let tag = |key: &str, value: &str| NewTag {
+        key: key.to_string(),
+        value: value.to_string(),
+      };
+      let hosts = [("web-1", "north"), ("web-2", "south"), ("db-1", "north")];
+      for (host, plant) in hosts.iter() {
+        db.create_series(NewSeries {
+          name: format!("{}.temperature", host),
+          tags: Some(vec![tag("plant", plant), tag("host", host)]),
+          ..Default::default()
+        })
+        .unwrap();
+      }
+      db.create_point(
+        "web-1.temperature",
+        NewPoint {
+          time: Utc.ymd(2019, 6, 10).and_hms(8, 0, 0),
+          value: Value::Float(21.5),
+        },
+        None,
+      )
+      .unwrap();
+
+      let matcher = |key: &str, operator, value: &str| TagMatcher {
+        key: key.to_string(),
+        operator,
+        value: value.to_string(),
+      };
+      let select = |matchers: &[TagMatcher]| {
+        db.select_series(matchers).map(|series| {
+          series
+            .into_iter()
+            .map(|series| series.name)
+            .collect::<Vec<_>>()
+        })
+      };
+
+      assert_eq!(
+        select(&[matcher("plant", MatchOperator::Equal, "north")]),
+        Ok(vec![
+          "web-1.temperature".to_string(),
+          "db-1.temperature".to_string()
+        ])
+      );
+      assert_eq!(
+        select(&[
+          matcher("host", MatchOperator::Regex, "web-.*"),
+          matcher("plant", MatchOperator::NotEqual, "south"),
+        ]),
+        Ok(vec!["web-1.temperature".to_string()])
+      );
+      assert_eq!(
+        select(&[matcher("host", MatchOperator::NotRegex, "web-.*")]),
+        Ok(vec!["db-1.temperature".to_string()])
+      );
+      assert!(select(&[matcher("host", MatchOperator::Regex, "(")]).is_err());
+
+      let selected = db
+        .query_by_tags(&[matcher("host", MatchOperator::Regex, "web-.*")], None)
+        .unwrap();
+      assert_eq!(selected.len(), 2);
+      assert_eq!(
+        selected[0].series.tags,
+        vec![
+          Tag {
+            key: "host".to_string(),
+            value: "web-1".to_string()
+          },
+          Tag {
+            key: "plant".to_string(),
+            value: "north".to_string()
+          },
+        ]
+      );
+      assert_eq!(selected[0].points.len(), 1);
+      assert_eq!(selected[1].points.len(), 0);
+
+      db.delete_series("db-1.temperature").unwrap();
+      assert_eq!(
+        select(&[matcher("plant", MatchOperator::Equal, "north")]),
+        Ok(vec!["web-1.temperature".to_string()])
+      );
+
+      assert_eq!(
+        db.create_series(NewSeries {
+          name: "duplicate".to_string(),
+          tags: Some(vec![tag("host", "a"), tag("host", "b")]),
+          ..Default::default()
+        }),
+        Err(Error::DuplicateTag("host".to_string()))
+      );
+    });
+  }
+
+  #[test]
+  fn test_histograms() {
+    db_test(|db| {
+      db.create_series(NewSeries {
+        name: "latency".to_string(),
+        value_type: Some(ValueType::Histogram),
+        ..Default::default()
+      })
+      .unwrap();
+      let start = Utc.ymd(2019, 6, 10).and_hms(8, 0, 0);
+      let samples = (1..=100).map(f64::from).collect::<Vec<_>>();
+      for (i, samples) in samples.chunks(50).enumerate() {
+        db.create_point(
+          "latency",
+          NewPoint {
+            time: start + chrono::Duration::minutes(i as i64),
+            value: Value::Histogram(Histogram::from_samples(samples)),
+          },
+          None,
+        )
+        .unwrap();
+      }
+
+      let points = db
+        .query(
+          "latency",
+          Some(QueryOptions::with(|options| {
+            options.aggregate = Some(NewAggregationStrategy {
+              function: AggregationFunction::Avg,
+              over: Duration::from_string("1 hour").unwrap(),
+            });
+          })),
+        )
+        .unwrap();
+      assert_eq!(
+        points,
+        vec![Point {
+          time: start,
+          value: Value::Histogram(Histogram::from_samples(&samples)),
+        }]
+      );
+      assert_eq!(
+        db.query(
+          "latency",
+          Some(QueryOptions::with(|options| {
+            options.aggregate = Some(NewAggregationStrategy {
+              function: AggregationFunction::Max,
+              over: Duration::from_string("1 hour").unwrap(),
+            });
+          }))
+        ),
+        Err(Error::UnsupportedAggregation(
+          AggregationFunction::Max,
+          ValueType::Histogram
+        ))
+      );
+    });
+  }
+
+  #[test]
+  fn test_duplicate_policies() {
+    db_test(|db| {
+      let time = Utc.ymd(2019, 6, 10).and_hms(8, 0, 0);
+      let point = |value| NewPoint {
+        time,
+        value: Value::Int(value),
+      };
+      let policies = [
+        DuplicatePolicy::Overwrite,
+        DuplicatePolicy::Reject,
+        DuplicatePolicy::KeepFirst,
+        DuplicatePolicy::Sum,
+      ];
+      for policy in policies.iter() {
+        let series_name = format!("{:?}", policy);
+        db.create_series(NewSeries {
+          name: series_name.clone(),
+          value_type: Some(ValueType::Int),
+          duplicate_policy: Some(*policy),
+          ..Default::default()
+        })
+        .unwrap();
+        db.create_point(&series_name, point(1), None).unwrap();
+      }
+
+      let write = |series_name: &str, values: Vec<i64>| {
+        db.create_points(series_name, values.into_iter().map(point).collect(), None)
+          .map(|points| {
+            points
+              .into_iter()
+              .map(|point| point.value)
+              .collect::<Vec<_>>()
+          })
+      };
+      assert_eq!(write("Overwrite", vec![2, 3]), Ok(vec![Value::Int(3)]));
+      assert_eq!(
+        write("Reject", vec![2]),
+        Err(Error::DuplicatePoint("Reject".to_string(), time))
+      );
+      assert_eq!(write("KeepFirst", vec![2, 3]), Ok(vec![Value::Int(1)]));
+      assert_eq!(write("Sum", vec![2, 3]), Ok(vec![Value::Int(6)]));
+
+      for (series_name, value) in &[
+        ("Overwrite", 3),
+        ("Reject", 1),
+        ("KeepFirst", 1),
+        ("Sum", 6),
+      ] {
+        assert_eq!(
+          db.query(series_name, None),
+          Ok(vec![Point {
+            time,
+            value: Value::Int(*value)
+          }])
+        );
+      }
+
+      assert_eq!(
+        db.create_series(NewSeries {
+          name: "states".to_string(),
+          value_type: Some(ValueType::String),
+          duplicate_policy: Some(DuplicatePolicy::Sum),
+          ..Default::default()
+        }),
+        Err(Error::UnsupportedAggregation(
+          AggregationFunction::Sum,
+          ValueType::String
+        ))
+      );
+    });
+  }
+
+  #[test]
+  fn test_annotations() {
+    db_test(|db| {
+      db.create_series(NewSeries {
+        name: "test-series".to_string(),
+        ..Default::default()
+      })
+      .unwrap();
+      let start = Utc.ymd(2019, 6, 10).and_hms(8, 0, 0);
+      let annotate = |series_name: Option<&str>, hours: i64, length: Option<i64>| {
+        db.create_annotation(NewAnnotation {
+          series_name: series_name.map(str::to_string),
+          since: start + chrono::Duration::hours(hours),
+          until: length.map(|length| start + chrono::Duration::hours(hours + length)),
+          title: "deploy".to_string(),
+          text: None,
+          tags: None,
+        })
+      };
+      let deploy = annotate(None, 0, Some(2)).unwrap();
+      let scenario = annotate(Some("test-series"), 1, None).unwrap();
+      let restart = annotate(Some("test-series"), 4, None).unwrap();
+
+      let list = |series_name, since: i64, until: i64| {
+        db.list_annotations(
+          series_name,
+          &QueryOptions::with(|options| {
+            options.since = Some(start + chrono::Duration::hours(since));
+            options.until = Some(start + chrono::Duration::hours(until));
+          }),
+        )
+        .map(|annotations| {
+          annotations
+            .into_iter()
+            .map(|annotation| annotation.id)
+            .collect::<Vec<_>>()
+        })
+      };
+      assert_eq!(
+        list(Some("test-series"), 1, 3),
+        Ok(vec![deploy.id, scenario.id])
+      );
+      assert_eq!(list(Some("test-series"), 3, 5), Ok(vec![restart.id]));
+      assert_eq!(list(None, 0, 5), Ok(vec![deploy.id]));
+      assert_eq!(
+        list(Some("missing"), 0, 5),
+        Err(Error::SeriesMissing("missing".to_string()))
+      );
+      assert_eq!(
+        annotate(None, 1, Some(-1)),
+        Err(Error::InvalidAnnotation("It can not end before it starts"))
+      );
+
+      assert_eq!(db.delete_annotation(scenario.id), Ok(true));
+      assert_eq!(db.delete_annotation(scenario.id), Ok(false));
+      assert_eq!(
+        list(Some("test-series"), 0, 5),
+        Ok(vec![deploy.id, restart.id])
+      );
+
+      // Annotations that can not be parsed are left out, and deleted with
+      // their series
+      let series = db.get_series("test-series").unwrap().unwrap();
+      let damaged = codec::annotation_key(series.id, &(start + chrono::Duration::hours(2)), 99);
+      db.backend().put(SERIES_CF, &damaged, b"damaged").unwrap();
+      assert_eq!(
+        list(Some("test-series"), 0, 5),
+        Ok(vec![deploy.id, restart.id])
+      );
+
+      db.delete_series("test-series").unwrap();
+      assert_eq!(db.backend().get(SERIES_CF, &damaged), Ok(None));
+      assert_eq!(db.delete_annotation(restart.id), Ok(false));
+      assert_eq!(list(None, 0, 5), Ok(vec![deploy.id]));
+    });
+  }
+
+  #[test]
+  fn test_stats() {
+    let dir = TempDir::new("kakoidb-stats").unwrap();
+    let start = Utc.ymd(2019, 6, 10).and_hms(8, 0, 0);
+    let stats = {
+      let mut db = Database::open(dir.path(), &RocksDbConfig::default()).unwrap();
+      db.create_series(NewSeries {
+        name: "test-series".to_string(),
+        ..Default::default()
+      })
+      .unwrap();
+      let new_points = (0..10)
+        .map(|i| NewPoint {
+          time: start + chrono::Duration::hours(i),
+          value: Value::Float(i as f64),
+        })
+        .collect();
+      db.create_points("test-series", new_points, None).unwrap();
+      db.create_point(
+        "test-series",
+        NewPoint {
+          time: start,
+          value: Value::Float(-1.0),
+        },
+        None,
+      )
+      .unwrap();
+
+      let stats = db.get_series("test-series").unwrap().unwrap().stats;
+      assert_eq!(stats.count, 10);
+      assert_eq!(stats.first, Some(start));
+      assert_eq!(stats.last, Some(start + chrono::Duration::hours(9)));
+      assert_eq!(stats.min, Some(-1.0));
+      assert_eq!(stats.max, Some(9.0));
+      assert!(stats.bytes > 0);
+
+      db.delete_by_query(
+        "test-series",
+        QueryOptions::with(|options| {
+          options.since = Some(start + chrono::Duration::hours(8));
+        }),
+      )
+      .unwrap();
+      let series = db.get_series("test-series").unwrap().unwrap();
+      assert_eq!(series.stats.count, 8);
+      assert_eq!(series.stats.last, Some(start + chrono::Duration::hours(7)));
+      assert_eq!(series.stats.max, Some(7.0));
+
+      db.seal_blocks(&series, start + chrono::Duration::days(1))
+        .unwrap();
+      let stats = db.get_series("test-series").unwrap().unwrap().stats;
+      assert_eq!(stats.count, 8);
+      assert!(stats.bytes < series.stats.bytes);
+      stats
+    };
+
+    let db = Database::open(dir.path(), &RocksDbConfig::default()).unwrap();
+    assert_eq!(db.get_series("test-series").unwrap().unwrap().stats, stats);
+  }
+
+  #[test]
+  fn test_open_corrupted() {
+    let dir = TempDir::new("kakoidb-corrupted").unwrap();
+    {
+      let db = Database::open(dir.path(), &RocksDbConfig::default()).unwrap();
+      db.create_series(NewSeries {
+        name: "test-series".to_string(),
+        ..Default::default()
+      })
+      .unwrap();
+      // Repairing recovers tables, but not the log of a column family
+      db.backend().compact_range(SERIES_CF, &[], &[0xff]);
+    }
+    std::fs::write(dir.path().join("CURRENT"), "garbage").unwrap();
+
+    match Database::open(dir.path(), &RocksDbConfig::default()) {
+      Err(Error::Corrupted(_)) => (),
+      Err(err) => panic!("Unexpected error {}", err),
+      Ok(_) => panic!("Opened a corrupted database"),
+    }
+    // Nothing is repaired without being asked to
+    assert_eq!(
+      std::fs::read_to_string(dir.path().join("CURRENT")).unwrap(),
+      "garbage"
+    );
+
+    Database::repair_rocksdb(dir.path(), &RocksDbConfig::default()).unwrap();
+    let db = Database::open(dir.path(), &RocksDbConfig::default()).unwrap();
+    assert!(db.get_series("test-series").unwrap().is_some());
+  }
+
+  #[test]
+  fn test_stats_match_refresh() {
+    db_test(|db| {
+      let start = Utc.ymd(2019, 6, 10).and_hms(0, 0, 0);
+      let series = db
+        .create_series(NewSeries {
+          name: "test-series".to_string(),
+          ..Default::default()
+        })
+        .unwrap();
+      let new_points = (0..96)
+        .map(|i| NewPoint {
+          time: start + chrono::Duration::hours(i),
+          value: Value::Float(((i * 7) % 13) as f64),
+        })
+        .collect();
+      db.create_points("test-series", new_points, None).unwrap();
+      db.seal_blocks(&series, start + chrono::Duration::hours(60))
+        .unwrap();
+
+      let assert_refreshed = || {
+        let stats = db.get_series("test-series").unwrap().unwrap().stats;
+        db.refresh_stats(&series).unwrap();
+        assert_eq!(db.get_series("test-series").unwrap().unwrap().stats, stats);
+      };
+      assert_refreshed();
+
+      // Covers some blocks whole and cuts others
+      db.delete_by_query(
+        "test-series",
+        QueryOptions::with(|options| {
+          options.since = Some(start + chrono::Duration::minutes(5 * 60 + 30));
+          options.until = Some(start + chrono::Duration::hours(13));
+        }),
+      )
+      .unwrap();
+      assert_refreshed();
+
+      db.drop_shards(&series, start + chrono::Duration::days(2))
+        .unwrap();
+      assert_refreshed();
+
+      let options = QueryOptions::with(|options| {
+        options.until = Some(start + chrono::Duration::hours(70));
+      });
+      let rollups = vec![Point {
+        time: start + chrono::Duration::hours(48),
+        value: Value::Float(100.0),
+      }];
+      db.replace_points(&series, &options, rollups).unwrap();
+      assert_refreshed();
+      let stats = db.get_series("test-series").unwrap().unwrap().stats;
+      assert_eq!(stats.count, 26);
+      assert_eq!(stats.first, Some(start + chrono::Duration::hours(48)));
+      assert_eq!(stats.max, Some(100.0));
+    });
+  }
+
+  #[test]
+  fn test_snapshot() {
+    let dir = TempDir::new("kakoidb-snapshot").unwrap();
+    let start = Utc.ymd(2019, 6, 10).and_hms(8, 0, 0);
+    let point = |hours, value| NewPoint {
+      time: start + chrono::Duration::hours(hours),
+      value: Value::Float(value),
+    };
+    let new_series = |name: &str| NewSeries {
+      name: name.to_string(),
+      ..Default::default()
+    };
+
+    for db in [
+      Database::in_memory(),
+      Database::open(dir.path(), &RocksDbConfig::default()).unwrap(),
+    ] {
+      for name in &["cpu", "memory"] {
+        db.create_series(new_series(name)).unwrap();
+        db.create_point(name, point(0, 1.0), None).unwrap();
+      }
+
+      let snapshot = db.snapshot().unwrap();
+      assert!(snapshot.is_read_only());
+      assert!(!db.is_read_only());
+      assert_eq!(
+        snapshot.create_point("cpu", point(3, 4.0), None),
+        Err(Error::ReadOnly("be written"))
+      );
+
+      for name in &["cpu", "memory"] {
+        db.create_points(name, vec![point(1, 2.0), point(2, 3.0)], None)
+          .unwrap();
+      }
+      db.create_series(new_series("disk")).unwrap();
+      db.delete_by_query("memory", Default::default()).unwrap();
+
+      let nested = snapshot.snapshot().unwrap();
+      for snapshot in &[snapshot, nested] {
+        assert_eq!(snapshot.query("cpu", None).unwrap().len(), 1);
+        assert_eq!(snapshot.query("memory", None).unwrap().len(), 1);
+        let series = snapshot.get_series("cpu").unwrap().unwrap();
+        assert_eq!(series.stats.count, 1);
+        assert_eq!(snapshot.list_series().unwrap().len(), 2);
+      }
+      assert_eq!(db.query("cpu", None).unwrap().len(), 3);
+      assert_eq!(db.query("memory", None).unwrap().len(), 0);
+      assert_eq!(db.list_series().unwrap().len(), 3);
+    }
+  }
+
+  #[test]
+  fn test_seal_blocks() {
+    db_test(|db| {
+      let series = db
+        .create_series(NewSeries {
+          name: "test-series".to_string(),
+          ..Default::default()
+        })
+        .unwrap();
+
+      let start = Utc.ymd(2019, 6, 10).and_hms(8, 0, 0);
+      let points = (0..10)
+        .map(|i| Point {
+          time: start + chrono::Duration::minutes(i * 30),
+          value: Value::Float(i as f64),
+        })
+        .collect::<Vec<_>>();
+      for point in &points {
+        db.create_point(
+          "test-series",
+          NewPoint {
+            time: point.time,
+            value: point.value.clone(),
+          },
+          None,
+        )
+        .unwrap();
+      }
+
+      let sealed = db
+        .seal_blocks(&series, start + chrono::Duration::hours(4))
+        .unwrap();
+
+      assert_eq!(sealed, 8);
+      assert_eq!(db.query("test-series", None), Ok(points));
+
+      db.create_point(
+        "test-series",
+        NewPoint {
+          time: start,
+          value: Value::Float(42.0),
+        },
+        None,
+      )
+      .unwrap();
+      db.seal_blocks(&series, start + chrono::Duration::hours(4))
+        .unwrap();
+      let deleted = db.delete_by_query(
+        "test-series",
+        QueryOptions::with(|options| {
+          options.since = Some(start + chrono::Duration::minutes(30));
+          options.until = Some(start + chrono::Duration::hours(3));
+        }),
+      );
+
+      assert_eq!(deleted, Ok(6));
+
+      assert_eq!(
+        db.query("test-series", None),
+        Ok(vec![
+          Point {
+            time: start,
+            value: Value::Float(42.0)
+          },
+          Point {
+            time: start + chrono::Duration::hours(3) + chrono::Duration::minutes(30),
+            value: Value::Float(7.0)
+          },
+          Point {
+            time: start + chrono::Duration::hours(4),
+            value: Value::Float(8.0)
+          },
+          Point {
+            time: start + chrono::Duration::hours(4) + chrono::Duration::minutes(30),
+            value: Value::Float(9.0)
+          }
+        ])
+      );
+    });
+  }
+
+  #[test]
+  fn test_delete_corrupt_block() {
+    db_test(|db| {
+      let series = db
+        .create_series(NewSeries {
+          name: "test-series".to_string(),
+          ..Default::default()
+        })
+        .unwrap();
+
+      let start = Utc.ymd(2019, 6, 10).and_hms(8, 0, 0);
+      for i in 0..4 {
+        db.create_point(
+          "test-series",
+          NewPoint {
+            time: start + chrono::Duration::minutes(i * 30),
+            value: Value::Float(i as f64),
+          },
+          None,
+        )
+        .unwrap();
+      }
+      db.seal_blocks(&series, start + chrono::Duration::hours(4))
+        .unwrap();
+      let key = codec::block_key(series.id, &start);
+      db.backend().put(BLOCKS_CF, &key, b"corrupt").unwrap();
+
+      // The points outside of the range are kept with the block
+      let deleted = db.delete_by_query(
+        "test-series",
+        QueryOptions::with(|options| {
+          options.since = Some(start);
+          options.until = Some(start + chrono::Duration::minutes(30));
+        }),
+      );
+      assert_eq!(deleted, Ok(0));
+      assert_eq!(
+        db.backend().get(BLOCKS_CF, &key),
+        Ok(Some(b"corrupt".to_vec()))
+      );
+    });
+  }
+
+  #[test]
+  fn test_drop_shards() {
+    db_test(|db| {
+      let series = db
+        .create_series(NewSeries {
+          name: "test-series".to_string(),
+          shard_duration: Some(Duration::from_string("1 day").unwrap()),
+          ..Default::default()
+        })
+        .unwrap();
+
+      let start = Utc.ymd(2019, 6, 10).and_hms(0, 0, 0);
+      for hour in 0..72 {
+        db.create_point(
+          "test-series",
+          NewPoint {
+            time: start + chrono::Duration::hours(hour),
+            value: Value::Float(hour as f64),
+          },
+          None,
+        )
+        .unwrap();
+      }
+      db.seal_blocks(&series, start + chrono::Duration::days(2))
+        .unwrap();
+
+      // The second shard has not ended yet
+      let dropped = db
+        .drop_shards(&series, start + chrono::Duration::hours(36))
+        .unwrap();
+      let points = db.query("test-series", None).unwrap();
+
+      assert_eq!(dropped, 1);
+      assert_eq!(points.len(), 48);
+      assert_eq!(points[0].time, start + chrono::Duration::days(1));
+    });
+  }
+
+  #[test]
+  fn test_delete_series() {
+    db_test(|db| {
+      let series = db
+        .create_series(NewSeries {
+          name: "test-series".to_string(),
+          ..Default::default()
+        })
+        .unwrap();
+
+      let start = Utc.ymd(2019, 6, 10).and_hms(8, 0, 0);
+      for i in 0..10 {
+        db.create_point(
+          "test-series",
+          NewPoint {
+            time: start + chrono::Duration::minutes(i * 30),
+            value: Value::Float(i as f64),
+          },
+          None,
+        )
+        .unwrap();
+      }
+      db.seal_blocks(&series, start + chrono::Duration::hours(4))
+        .unwrap();
+
+      assert_eq!(db.delete_series("test-series"), Ok(10));
+      assert_eq!(db.get_series("test-series"), Ok(None));
+      assert_eq!(
+        db.iter_points(&series, None)
+          .collect::<Result<Vec<_>, _>>()
+          .unwrap(),
+        vec![]
+      );
+    });
+  }
+
+  #[test]
+  fn test_create_series_invalid_shard_duration() {
+    db_test(|db| {
+      let shard_duration = Duration::from_string("90 minutes").unwrap();
+      let created_series = db.create_series(NewSeries {
+        name: "test-series".to_string(),
+        shard_duration: Some(shard_duration.clone()),
+        ..Default::default()
+      });
+
+      assert_eq!(
+        created_series,
+        Err(Error::InvalidShardDuration(shard_duration))
+      );
+    });
+  }
+
   #[test]
   fn test_create_point_no_series() {
     db_test(|db| {
@@ -401,8 +3145,9 @@ mod tests {
         "test-series",
         NewPoint {
           time: now,
-          value: 1.0,
+          value: Value::Float(1.0),
         },
+        None,
       );
 
       assert_eq!(
@@ -1,6 +1,8 @@
 use crate::entities::duration::Duration;
+use crate::entities::value::{FieldValue, Value, ValueType};
+use std::convert::TryFrom;
 
-#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, GraphQLEnum)]
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy, GraphQLEnum)]
 pub enum AggregationFunction {
   Oldest,
   Newest,
@@ -11,29 +13,133 @@ pub enum AggregationFunction {
 }
 
 impl AggregationFunction {
-  pub fn reduce(&self, prev: f64, current: f64) -> f64 {
+  /// If the function can aggregate values of `value_type`. Booleans are
+  /// ordered with false before true, strings can only be picked. Fields are
+  /// aggregated one by one, so the function has to apply to each of them.
+  /// The sum and average of histograms are the merged distribution.
+  pub fn applies_to(&self, value_type: ValueType) -> bool {
     match self {
-      AggregationFunction::Oldest => prev,
-      AggregationFunction::Newest => current,
-      AggregationFunction::Max => prev.max(current),
-      AggregationFunction::Min => prev.min(current),
-      AggregationFunction::Sum => prev + current,
-      AggregationFunction::Avg => prev + current,
+      AggregationFunction::Oldest | AggregationFunction::Newest => true,
+      AggregationFunction::Max | AggregationFunction::Min => match value_type {
+        ValueType::Float | ValueType::Int | ValueType::Bool => true,
+        ValueType::String | ValueType::Fields | ValueType::Histogram => false,
+      },
+      AggregationFunction::Sum | AggregationFunction::Avg => match value_type {
+        ValueType::Float | ValueType::Int | ValueType::Histogram => true,
+        ValueType::Bool | ValueType::String | ValueType::Fields => false,
+      },
     }
   }
 
-  pub fn finish(&self, value: f64, count: u64) -> f64 {
-    match self {
-      AggregationFunction::Oldest => value,
-      AggregationFunction::Newest => value,
-      AggregationFunction::Max => value,
-      AggregationFunction::Min => value,
-      AggregationFunction::Sum => value,
-      AggregationFunction::Avg => value / (count as f64),
+  /// Starts aggregating with `value`
+  pub fn start(&self, value: Value) -> Partial {
+    match (self, value) {
+      (AggregationFunction::Sum | AggregationFunction::Avg, Value::Int(value)) => {
+        Partial::IntSum(i128::from(value))
+      }
+      (function, Value::Fields(fields)) => Partial::Fields(
+        fields
+          .into_iter()
+          .map(|field| (field.name, function.start(field.value)))
+          .collect(),
+      ),
+      (_, value) => Partial::Value(value),
+    }
+  }
+
+  /// Values must be of a type the function `applies_to`, other values leave
+  /// `prev` as it is
+  pub fn reduce(&self, prev: Partial, current: Value) -> Partial {
+    match (self, prev, current) {
+      (function, Partial::Fields(prev), Value::Fields(current)) => Partial::Fields(
+        prev
+          .into_iter()
+          .zip(current)
+          .map(|((name, prev), current)| (name, function.reduce(prev, current.value)))
+          .collect(),
+      ),
+      (_, Partial::IntSum(prev), Value::Int(current)) => {
+        Partial::IntSum(prev + i128::from(current))
+      }
+      (function, Partial::Value(prev), current) => {
+        Partial::Value(function.reduce_values(prev, current))
+      }
+      (_, prev, _) => prev,
+    }
+  }
+
+  fn reduce_values(&self, prev: Value, current: Value) -> Value {
+    match (self, prev, current) {
+      (AggregationFunction::Oldest, prev, _) => prev,
+      (AggregationFunction::Newest, _, current) => current,
+      (AggregationFunction::Max, Value::Float(prev), Value::Float(current)) => {
+        Value::Float(prev.max(current))
+      }
+      (AggregationFunction::Max, Value::Int(prev), Value::Int(current)) => {
+        Value::Int(prev.max(current))
+      }
+      (AggregationFunction::Max, Value::Bool(prev), Value::Bool(current)) => {
+        Value::Bool(prev || current)
+      }
+      (AggregationFunction::Min, Value::Float(prev), Value::Float(current)) => {
+        Value::Float(prev.min(current))
+      }
+      (AggregationFunction::Min, Value::Int(prev), Value::Int(current)) => {
+        Value::Int(prev.min(current))
+      }
+      (AggregationFunction::Min, Value::Bool(prev), Value::Bool(current)) => {
+        Value::Bool(prev && current)
+      }
+      (
+        AggregationFunction::Sum | AggregationFunction::Avg,
+        Value::Float(prev),
+        Value::Float(current),
+      ) => Value::Float(prev + current),
+      (
+        AggregationFunction::Sum | AggregationFunction::Avg,
+        Value::Histogram(prev),
+        Value::Histogram(current),
+      ) => Value::Histogram(prev.merge(current)),
+      (_, prev, _) => prev,
+    }
+  }
+
+  /// The aggregate of `count` values. The average of integers is rounded
+  /// towards zero. None if the sum of integers does not fit an `i64`.
+  pub fn finish(&self, partial: Partial, count: u64) -> Option<Value> {
+    match (self, partial) {
+      (AggregationFunction::Avg, Partial::Value(Value::Float(value))) => {
+        Some(Value::Float(value / (count as f64)))
+      }
+      (AggregationFunction::Avg, Partial::IntSum(sum)) => {
+        i64::try_from(sum / i128::from(count)).ok().map(Value::Int)
+      }
+      (_, Partial::IntSum(sum)) => i64::try_from(sum).ok().map(Value::Int),
+      (function, Partial::Fields(fields)) => fields
+        .into_iter()
+        .map(|(name, partial)| {
+          Some(FieldValue {
+            name,
+            value: function.finish(partial, count)?,
+          })
+        })
+        .collect::<Option<Vec<_>>>()
+        .map(Value::Fields),
+      (_, Partial::Value(value)) => Some(value),
     }
   }
 }
 
+/// A value while it is being aggregated. Integers are summed in 128 bits, so
+/// that an average does not overflow when the sum exceeds an `i64`.
+#[derive(PartialEq, Debug, Clone)]
+pub enum Partial {
+  Value(Value),
+  IntSum(i128),
+  /// The partial value of each field, by field name
+  Fields(Vec<(String, Partial)>),
+}
+
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone, GraphQLObject)]
 pub struct AggregationStrategy {
   pub function: AggregationFunction,
@@ -54,3 +160,71 @@ impl From<NewAggregationStrategy> for AggregationStrategy {
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn aggregate(function: AggregationFunction, values: Vec<Value>) -> Value {
+    let count = values.len() as u64;
+    let mut values = values.into_iter();
+    let first = function.start(values.next().unwrap());
+    let partial = values.fold(first, |prev, current| function.reduce(prev, current));
+    function.finish(partial, count).unwrap()
+  }
+
+  #[test]
+  fn test_aggregate_ints() {
+    let values = vec![Value::Int(i64::MAX - 1), Value::Int(-3), Value::Int(2)];
+
+    assert_eq!(
+      aggregate(AggregationFunction::Max, values.clone()),
+      Value::Int(i64::MAX - 1)
+    );
+    assert_eq!(
+      aggregate(AggregationFunction::Min, values.clone()),
+      Value::Int(-3)
+    );
+    assert_eq!(
+      aggregate(AggregationFunction::Sum, values),
+      Value::Int(i64::MAX - 2)
+    );
+    assert_eq!(
+      aggregate(AggregationFunction::Avg, vec![Value::Int(3), Value::Int(4)]),
+      Value::Int(3)
+    );
+  }
+
+  #[test]
+  fn test_aggregate_large_ints() {
+    let values = vec![Value::Int(i64::MAX), Value::Int(i64::MAX - 2)];
+
+    assert_eq!(
+      aggregate(AggregationFunction::Avg, values.clone()),
+      Value::Int(i64::MAX - 1)
+    );
+    let function = AggregationFunction::Sum;
+    let partial = function.reduce(function.start(values[0].clone()), values[1].clone());
+    assert_eq!(function.finish(partial, 2), None);
+  }
+
+  #[test]
+  fn test_aggregate_bools() {
+    let values = vec![Value::Bool(false), Value::Bool(true), Value::Bool(false)];
+
+    assert_eq!(
+      aggregate(AggregationFunction::Max, values.clone()),
+      Value::Bool(true)
+    );
+    assert_eq!(
+      aggregate(AggregationFunction::Min, values.clone()),
+      Value::Bool(false)
+    );
+    assert_eq!(
+      aggregate(AggregationFunction::Newest, values),
+      Value::Bool(false)
+    );
+    assert!(!AggregationFunction::Sum.applies_to(ValueType::Bool));
+    assert!(!AggregationFunction::Max.applies_to(ValueType::String));
+  }
+}
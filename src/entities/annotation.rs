@@ -0,0 +1,72 @@
+use crate::catalog::SeriesId;
+use crate::entities::tag::{NewTag, Tag};
+use chrono::{DateTime, Utc};
+use juniper::ID;
+
+pub type AnnotationId = u64;
+
+/// An event over a range of time, like a deploy or an operator action
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct Annotation {
+  pub id: AnnotationId,
+  /// The series the annotation belongs to, none for global annotations
+  pub series_id: Option<SeriesId>,
+  pub since: DateTime<Utc>,
+  pub until: DateTime<Utc>,
+  pub title: String,
+  pub text: Option<String>,
+  /// Sorted by key
+  pub tags: Vec<Tag>,
+}
+
+graphql_object!(Annotation: () as "Annotation" |&self| {
+    description: "An event over a range of time, like a deploy or an operator action"
+
+    field id() -> ID {
+        ID::from(self.id.to_string())
+    }
+
+    field global() -> bool as "If the annotation belongs to every series" {
+        self.series_id.is_none()
+    }
+
+    field since() -> &DateTime<Utc> {
+        &self.since
+    }
+
+    field until() -> &DateTime<Utc> {
+        &self.until
+    }
+
+    field title() -> &str {
+        &self.title
+    }
+
+    field text() -> Option<&str> {
+        self.text.as_deref()
+    }
+
+    field tags() -> &[Tag] {
+        &self.tags
+    }
+});
+
+impl Annotation {
+  pub fn overlaps(&self, since: Option<DateTime<Utc>>, until: Option<DateTime<Utc>>) -> bool {
+    since.is_none_or(|since| self.until >= since) && until.is_none_or(|until| self.since <= until)
+  }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, GraphQLInputObject)]
+#[graphql(description = "An event over a range of time, like a deploy or an operator action")]
+pub struct NewAnnotation {
+  /// The series the annotation belongs to, it is global if left out
+  pub series_name: Option<String>,
+  pub since: DateTime<Utc>,
+  /// The end of the event, which is a single moment if left out
+  pub until: Option<DateTime<Utc>>,
+  pub title: String,
+  pub text: Option<String>,
+  /// Every key can only be given once
+  pub tags: Option<Vec<NewTag>>,
+}
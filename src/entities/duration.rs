@@ -65,7 +65,7 @@ impl Duration {
   }
 }
 
-impl<'a> From<&'a Duration> for chrono::Duration {
+impl From<&Duration> for chrono::Duration {
   fn from(duration: &Duration) -> Self {
     match duration.time_unit {
       TimeUnit::Minutes => chrono::Duration::minutes(i64::from(duration.value)),
@@ -77,7 +77,7 @@ impl<'a> From<&'a Duration> for chrono::Duration {
   }
 }
 
-impl<'a> Add<&'a Duration> for DateTime<Utc> {
+impl Add<&Duration> for DateTime<Utc> {
   type Output = DateTime<Utc>;
 
   fn add(self, rhs: &Duration) -> DateTime<Utc> {
@@ -87,7 +87,7 @@ impl<'a> Add<&'a Duration> for DateTime<Utc> {
   }
 }
 
-impl<'a> Sub<&'a Duration> for DateTime<Utc> {
+impl Sub<&Duration> for DateTime<Utc> {
   type Output = DateTime<Utc>;
 
   fn sub(self, rhs: &Duration) -> DateTime<Utc> {
@@ -0,0 +1,230 @@
+//! A mergeable sketch of a distribution of numbers.
+//!
+//! Samples are counted in logarithmically sized buckets, as in DDSketch, so
+//! every quantile is estimated within `RELATIVE_ACCURACY` of a sample near
+//! it, however many samples or merges it is made of. Merging two histograms
+//! adds up their buckets, which makes it exact and order independent.
+
+use crate::entities::value::Int64;
+use std::collections::BTreeMap;
+
+const RELATIVE_ACCURACY: f64 = 0.01;
+
+/// Samples closer to zero than this are counted as zero
+const MIN_INDEXABLE: f64 = 1e-9;
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Default)]
+pub struct Histogram {
+  count: u64,
+  sum: f64,
+  min: f64,
+  max: f64,
+  zero_count: u64,
+  /// Counts of positive samples by bucket
+  positive: BTreeMap<i32, u64>,
+  /// Counts of negative samples by the bucket of their magnitude
+  negative: BTreeMap<i32, u64>,
+}
+
+fn gamma() -> f64 {
+  (1.0 + RELATIVE_ACCURACY) / (1.0 - RELATIVE_ACCURACY)
+}
+
+/// The bucket that `magnitude` falls in
+fn bucket(magnitude: f64) -> i32 {
+  (magnitude.ln() / gamma().ln()).ceil() as i32
+}
+
+/// The value every sample in `bucket` is estimated as
+fn bucket_value(bucket: i32) -> f64 {
+  2.0 * gamma().powi(bucket) / (gamma() + 1.0)
+}
+
+impl Histogram {
+  pub fn from_samples(samples: &[f64]) -> Histogram {
+    let mut histogram = Histogram::default();
+    for &sample in samples {
+      histogram.add(sample);
+    }
+    histogram
+  }
+
+  /// Non finite samples are ignored
+  pub fn add(&mut self, sample: f64) {
+    if !sample.is_finite() {
+      return;
+    }
+    if self.count == 0 {
+      self.min = sample;
+      self.max = sample;
+    } else {
+      self.min = self.min.min(sample);
+      self.max = self.max.max(sample);
+    }
+    self.count += 1;
+    self.sum += sample;
+
+    if sample > MIN_INDEXABLE {
+      *self.positive.entry(bucket(sample)).or_insert(0) += 1;
+    } else if sample < -MIN_INDEXABLE {
+      *self.negative.entry(bucket(-sample)).or_insert(0) += 1;
+    } else {
+      self.zero_count += 1;
+    }
+  }
+
+  pub fn merge(mut self, other: Histogram) -> Histogram {
+    if other.count == 0 {
+      return self;
+    }
+    if self.count == 0 {
+      return other;
+    }
+    self.count += other.count;
+    self.sum += other.sum;
+    self.min = self.min.min(other.min);
+    self.max = self.max.max(other.max);
+    self.zero_count += other.zero_count;
+    for (bucket, count) in other.positive {
+      *self.positive.entry(bucket).or_insert(0) += count;
+    }
+    for (bucket, count) in other.negative {
+      *self.negative.entry(bucket).or_insert(0) += count;
+    }
+    self
+  }
+
+  pub fn count(&self) -> u64 {
+    self.count
+  }
+
+  pub fn sum(&self) -> f64 {
+    self.sum
+  }
+
+  pub fn min(&self) -> Option<f64> {
+    if self.count == 0 {
+      None
+    } else {
+      Some(self.min)
+    }
+  }
+
+  pub fn max(&self) -> Option<f64> {
+    if self.count == 0 {
+      None
+    } else {
+      Some(self.max)
+    }
+  }
+
+  pub fn mean(&self) -> Option<f64> {
+    if self.count == 0 {
+      None
+    } else {
+      Some(self.sum / self.count as f64)
+    }
+  }
+
+  /// The estimated value below which a `quantile` of the samples fall, for a
+  /// quantile from 0 to 1
+  pub fn quantile(&self, quantile: f64) -> Option<f64> {
+    if self.count == 0 || !(0.0..=1.0).contains(&quantile) {
+      return None;
+    }
+    let rank = (quantile * (self.count - 1) as f64).round() as u64;
+
+    // Samples from the most negative to the most positive
+    let buckets = self
+      .negative
+      .iter()
+      .rev()
+      .map(|(&bucket, &count)| (-bucket_value(bucket), count))
+      .chain(Some((0.0, self.zero_count)))
+      .chain(
+        self
+          .positive
+          .iter()
+          .map(|(&bucket, &count)| (bucket_value(bucket), count)),
+      );
+
+    let mut seen = 0;
+    for (value, count) in buckets {
+      seen += count;
+      if seen > rank {
+        return Some(value.max(self.min).min(self.max));
+      }
+    }
+    Some(self.max)
+  }
+}
+
+graphql_object!(Histogram: () as "Histogram" |&self| {
+    description: "A distribution of numbers, with quantiles estimated within 1%"
+
+    field count() -> Int64 {
+        Int64(self.count() as i64)
+    }
+
+    field sum() -> f64 {
+        self.sum()
+    }
+
+    field min() -> Option<f64> {
+        self.min()
+    }
+
+    field max() -> Option<f64> {
+        self.max()
+    }
+
+    field mean() -> Option<f64> {
+        self.mean()
+    }
+
+    field quantile(quantile: f64) -> Option<f64> as "The value below which the quantile, from 0 to 1, of the numbers fall" {
+        self.quantile(quantile)
+    }
+});
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn assert_close(estimate: Option<f64>, expected: f64) {
+    let estimate = estimate.unwrap();
+    assert!(
+      (estimate - expected).abs() <= expected.abs() * RELATIVE_ACCURACY,
+      "{} is not within 1% of {}",
+      estimate,
+      expected
+    );
+  }
+
+  #[test]
+  fn test_quantiles() {
+    let samples = (1..=1000).map(f64::from).collect::<Vec<_>>();
+    let histogram = Histogram::from_samples(&samples);
+
+    assert_eq!(histogram.count(), 1000);
+    assert_eq!(histogram.min(), Some(1.0));
+    assert_eq!(histogram.max(), Some(1000.0));
+    assert_close(histogram.quantile(0.5), 500.0);
+    assert_close(histogram.quantile(0.99), 990.0);
+    assert_eq!(histogram.quantile(1.0), Some(1000.0));
+    assert_eq!(histogram.quantile(1.5), None);
+  }
+
+  #[test]
+  fn test_merge() {
+    let samples = (-500..500).map(f64::from).collect::<Vec<_>>();
+    let (low, high) = samples.split_at(300);
+
+    let merged = Histogram::from_samples(low).merge(Histogram::from_samples(high));
+
+    assert_eq!(merged, Histogram::from_samples(&samples));
+    assert_close(merged.quantile(0.1), -400.0);
+    assert_eq!(merged.quantile(0.5), Some(0.0));
+    assert_eq!(Histogram::default().quantile(0.5), None);
+  }
+}
@@ -1,4 +1,9 @@
 pub mod aggregation;
+pub mod annotation;
 pub mod duration;
+pub mod histogram;
 pub mod point;
+pub mod precision;
 pub mod series;
+pub mod tag;
+pub mod value;
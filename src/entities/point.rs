@@ -1,22 +1,237 @@
 use crate::entities::aggregation::NewAggregationStrategy;
+use crate::entities::histogram::Histogram;
+use crate::entities::series::{Field, Series};
+use crate::entities::value::{FieldValue, Int64, Value, ValueType};
+use bincode::{deserialize_from, serialize_into};
 use chrono::{DateTime, Utc};
+use std::io::Read;
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+/// A point as it is stored. Values are stored without their type, which is
+/// the value type of their series. The values of fields follow each other in
+/// the order of the fields of the series.
+#[derive(PartialEq, Debug)]
 pub struct StoragePoint {
-  pub value: f64,
+  pub value: Value,
+}
+
+impl StoragePoint {
+  pub fn encode(&self) -> Vec<u8> {
+    let mut bytes = vec![];
+    encode_value(&mut bytes, &self.value);
+    bytes
+  }
+
+  /// `fields` are the fields of the series, if its values are fields
+  pub fn decode(
+    value_type: ValueType,
+    fields: &[Field],
+    mut bytes: &[u8],
+  ) -> bincode::Result<StoragePoint> {
+    let value = match value_type {
+      ValueType::Fields => Value::Fields(
+        fields
+          .iter()
+          .map(|field| {
+            Ok(FieldValue {
+              name: field.name.clone(),
+              value: decode_value(&mut bytes, field.value_type)?,
+            })
+          })
+          .collect::<bincode::Result<_>>()?,
+      ),
+      value_type => decode_value(&mut bytes, value_type)?,
+    };
+    Ok(StoragePoint { value })
+  }
 }
-#[derive(Serialize, Deserialize, PartialEq, Debug, GraphQLObject)]
-#[graphql(description = "Data at a specific time")]
+
+fn encode_value(bytes: &mut Vec<u8>, value: &Value) {
+  match value {
+    Value::Float(value) => serialize_into(bytes, value),
+    Value::Int(value) => serialize_into(bytes, value),
+    Value::Bool(value) => serialize_into(bytes, value),
+    Value::String(value) => serialize_into(bytes, value),
+    Value::Histogram(value) => serialize_into(bytes, value),
+    Value::Fields(fields) => {
+      for field in fields {
+        encode_value(bytes, &field.value);
+      }
+      Ok(())
+    }
+  }
+  .unwrap()
+}
+
+fn decode_value<R: Read>(reader: R, value_type: ValueType) -> bincode::Result<Value> {
+  Ok(match value_type {
+    ValueType::Float => Value::Float(deserialize_from(reader)?),
+    ValueType::Int => Value::Int(deserialize_from(reader)?),
+    ValueType::Bool => Value::Bool(deserialize_from(reader)?),
+    ValueType::String => Value::String(deserialize_from(reader)?),
+    ValueType::Histogram => Value::Histogram(deserialize_from(reader)?),
+    // Fields do not nest
+    ValueType::Fields => Value::Fields(vec![]),
+  })
+}
+
+/// Data at a specific time
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub struct Point {
   pub time: DateTime<Utc>,
-  pub value: f64,
+  pub value: Value,
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug, GraphQLInputObject)]
-#[graphql(description = "Data at a specific time")]
+graphql_object!(Point: () as "Point" |&self| {
+    description: "Data at a specific time"
+
+    field time() -> &DateTime<Utc> {
+        &self.time
+    }
+
+    field value() -> Option<f64> as "The value as a number, booleans are 0 or 1" {
+        self.value.as_f64()
+    }
+
+    field int_value() -> Option<Int64> {
+        self.value.as_int64()
+    }
+
+    field bool_value() -> Option<bool> {
+        self.value.as_bool()
+    }
+
+    field string_value() -> Option<&str> {
+        self.value.as_str()
+    }
+
+    field fields() -> Option<&[FieldValue]> {
+        self.value.as_fields()
+    }
+
+    field histogram() -> Option<&Histogram> {
+        self.value.as_histogram()
+    }
+});
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub struct NewPoint {
   pub time: DateTime<Utc>,
-  pub value: f64,
+  pub value: Value,
+}
+
+/// A point as it is written through the API, with exactly one of the values
+/// set to match the value type of the series
+#[derive(PartialEq, Debug, GraphQLInputObject)]
+#[graphql(name = "NewPoint", description = "Data at a specific time")]
+pub struct NewPointInput {
+  pub time: DateTime<Utc>,
+  pub value: Option<f64>,
+  pub int_value: Option<Int64>,
+  pub bool_value: Option<bool>,
+  pub string_value: Option<String>,
+  /// The samples of the distribution, for series of histograms
+  pub histogram: Option<Vec<f64>>,
+  /// The values of every field, for series with fields
+  pub fields: Option<Vec<NewFieldValue>>,
+}
+
+#[derive(PartialEq, Debug, GraphQLInputObject)]
+#[graphql(description = "The value of one field of a point")]
+pub struct NewFieldValue {
+  pub name: String,
+  pub value: Option<f64>,
+  pub int_value: Option<Int64>,
+  pub bool_value: Option<bool>,
+  pub string_value: Option<String>,
+  /// The samples of the distribution, for fields of histograms
+  pub histogram: Option<Vec<f64>>,
+}
+
+/// The one value that is set, if exactly one is
+fn single_value(values: Vec<Option<Value>>) -> Option<Value> {
+  let mut values = values.into_iter().flatten();
+  match (values.next(), values.next()) {
+    (Some(value), None) => Some(value),
+    _ => None,
+  }
+}
+
+impl NewPointInput {
+  pub fn into_new_point(self) -> Result<NewPoint, &'static str> {
+    let fields = match self.fields {
+      Some(fields) => Some(Value::Fields(
+        fields
+          .into_iter()
+          .map(NewFieldValue::into_field_value)
+          .collect::<Result<_, _>>()?,
+      )),
+      None => None,
+    };
+    let value = single_value(vec![
+      self.value.map(Value::Float),
+      self.int_value.map(|value| Value::Int(value.0)),
+      self.bool_value.map(Value::Bool),
+      self.string_value.map(Value::String),
+      self
+        .histogram
+        .map(|samples| Value::Histogram(Histogram::from_samples(&samples))),
+      fields,
+    ])
+    .ok_or(
+      "Exactly one of value, intValue, boolValue, stringValue, histogram and fields must be set",
+    )?;
+
+    Ok(NewPoint {
+      time: self.time,
+      value,
+    })
+  }
+}
+
+impl NewFieldValue {
+  fn into_field_value(self) -> Result<FieldValue, &'static str> {
+    let value = single_value(vec![
+      self.value.map(Value::Float),
+      self.int_value.map(|value| Value::Int(value.0)),
+      self.bool_value.map(Value::Bool),
+      self.string_value.map(Value::String),
+      self
+        .histogram
+        .map(|samples| Value::Histogram(Histogram::from_samples(&samples))),
+    ])
+    .ok_or(
+      "Exactly one of value, intValue, boolValue, stringValue and histogram of a field must be set",
+    )?;
+
+    Ok(FieldValue {
+      name: self.name,
+      value,
+    })
+  }
+}
+
+#[derive(PartialEq, Debug, GraphQLObject)]
+#[graphql(description = "The outcome of deleting points")]
+pub struct Deletion {
+  pub deleted_points: Int64,
+}
+
+#[derive(PartialEq, Debug, GraphQLObject)]
+#[graphql(
+  description = "The points of one of the series selected by a query",
+  scalar = juniper::DefaultScalarValue
+)]
+pub struct SeriesPoints {
+  pub series: Series,
+  pub points: Vec<Point>,
+}
+
+impl From<usize> for Deletion {
+  fn from(deleted_points: usize) -> Self {
+    Deletion {
+      deleted_points: Int64(deleted_points as i64),
+    }
+  }
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone, GraphQLInputObject, Default)]
@@ -24,15 +239,22 @@ pub struct QueryOptions {
   pub since: Option<DateTime<Utc>>,
   pub until: Option<DateTime<Utc>>,
   pub aggregate: Option<NewAggregationStrategy>,
+  /// The fields to return, all of them if left out
+  pub fields: Option<Vec<String>>,
 }
 
 impl QueryOptions {
   pub fn with<F>(setter: F) -> QueryOptions
   where
-    F: FnOnce(&mut QueryOptions) -> (),
+    F: FnOnce(&mut QueryOptions),
   {
     let mut options: QueryOptions = Default::default();
     setter(&mut options);
     options
   }
+
+  /// If `time` is within the since and until range
+  pub fn contains(&self, time: &DateTime<Utc>) -> bool {
+    self.since.iter().all(|since| time >= since) && self.until.iter().all(|until| time <= until)
+  }
 }
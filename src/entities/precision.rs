@@ -0,0 +1,59 @@
+use crate::codec;
+use chrono::{DateTime, Utc};
+
+/// The resolution the times of a series are stored with
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy, Default, GraphQLEnum)]
+pub enum Precision {
+  Seconds,
+  Milliseconds,
+  Microseconds,
+  #[default]
+  Nanoseconds,
+}
+
+impl Precision {
+  /// The length of one tick in nanoseconds
+  pub fn span(self) -> i64 {
+    match self {
+      Precision::Seconds => 1_000_000_000,
+      Precision::Milliseconds => 1_000_000,
+      Precision::Microseconds => 1_000,
+      Precision::Nanoseconds => 1,
+    }
+  }
+
+  /// Drops the part of `time` finer than the precision, rounding towards the
+  /// past
+  pub fn truncate(self, time: &DateTime<Utc>) -> DateTime<Utc> {
+    codec::truncate_time(time, self.span())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use chrono::TimeZone;
+
+  #[test]
+  fn test_truncate() {
+    let time = Utc.timestamp(1_560_000_000, 123_456_789);
+
+    assert_eq!(
+      Precision::Seconds.truncate(&time),
+      Utc.timestamp(1_560_000_000, 0)
+    );
+    assert_eq!(
+      Precision::Milliseconds.truncate(&time),
+      Utc.timestamp(1_560_000_000, 123_000_000)
+    );
+    assert_eq!(
+      Precision::Microseconds.truncate(&time),
+      Utc.timestamp(1_560_000_000, 123_456_000)
+    );
+    assert_eq!(Precision::Nanoseconds.truncate(&time), time);
+    assert_eq!(
+      Precision::Seconds.truncate(&Utc.timestamp(-1, 500_000_000)),
+      Utc.timestamp(-1, 0)
+    );
+  }
+}
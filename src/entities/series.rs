@@ -1,7 +1,14 @@
+use crate::catalog::SeriesId;
 use crate::entities::aggregation::{AggregationStrategy, NewAggregationStrategy};
 use crate::entities::duration::Duration;
+use crate::entities::point::Point;
+use crate::entities::precision::Precision;
+use crate::entities::tag::{NewTag, Tag};
+use crate::entities::value::{Int64, ValueType};
+use crate::shard;
+use chrono::{DateTime, Utc};
 
-pub const CURRENT_STORAGE_VERSION: i32 = 0;
+pub const CURRENT_STORAGE_VERSION: i32 = 2;
 
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone, GraphQLObject)]
 pub struct CompactionStrategy {
@@ -39,37 +46,194 @@ pub struct NewRetentionPolicy {
 impl From<NewRetentionPolicy> for RetentionPolicy {
   fn from(policy: NewRetentionPolicy) -> Self {
     RetentionPolicy {
-      compact: policy.compact.map_or_else(
-        || vec![],
-        |compact| compact.into_iter().map(CompactionStrategy::from).collect(),
-      ),
+      compact: policy.compact.map_or_else(Vec::new, |compact| {
+        compact.into_iter().map(CompactionStrategy::from).collect()
+      }),
       drop_after: policy.drop_after,
     }
   }
 }
 
+/// What happens when a point is written at a time the series already has a
+/// point at
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy, Default, GraphQLEnum)]
+pub enum DuplicatePolicy {
+  /// The new point replaces the old one
+  #[default]
+  Overwrite,
+  /// The write fails
+  Reject,
+  /// The old point is kept and the new one dropped
+  KeepFirst,
+  /// The values of the points are summed
+  Sum,
+}
+
+/// Statistics of the points of a series, kept up to date by the database
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Default)]
+pub struct SeriesStats {
+  pub count: u64,
+  pub first: Option<DateTime<Utc>>,
+  pub last: Option<DateTime<Utc>>,
+  /// The smallest value as a number. Overwritten points, and points
+  /// dropped by the compaction filter, only ever widen the range.
+  pub min: Option<f64>,
+  pub max: Option<f64>,
+  /// The size of the keys and values stored for the points
+  pub bytes: u64,
+}
+
+graphql_object!(SeriesStats: () as "SeriesStats" |&self| {
+    description: "Statistics of the points of a series"
+
+    field count() -> Int64 {
+        Int64(self.count as i64)
+    }
+
+    field first() -> Option<&DateTime<Utc>> {
+        self.first.as_ref()
+    }
+
+    field last() -> Option<&DateTime<Utc>> {
+        self.last.as_ref()
+    }
+
+    field min() -> Option<f64> as "The smallest value as a number" {
+        self.min
+    }
+
+    field max() -> Option<f64> as "The largest value as a number" {
+        self.max
+    }
+
+    field bytes() -> Int64 as "The approximate size of the stored points" {
+        Int64(self.bytes as i64)
+    }
+});
+
+impl SeriesStats {
+  /// Widens the statistics to a point, which is counted if it is `new`
+  pub fn add(&mut self, point: &Point, new: bool) {
+    if new {
+      self.count += 1;
+    }
+    self.first = Some(self.first.map_or(point.time, |first| first.min(point.time)));
+    self.last = Some(self.last.map_or(point.time, |last| last.max(point.time)));
+    if let Some(value) = point.value.as_f64() {
+      self.min = Some(self.min.map_or(value, |min| min.min(value)));
+      self.max = Some(self.max.map_or(value, |max| max.max(value)));
+    }
+  }
+}
+
 #[derive(Serialize, Deserialize, PartialEq, Debug, GraphQLObject)]
-#[graphql(description = "A collection of data over time")]
+#[graphql(
+  description = "A collection of data over time",
+  scalar = juniper::DefaultScalarValue
+)]
 pub struct Series {
+  #[graphql(skip)]
+  pub id: SeriesId,
   pub name: String,
   pub retention_policy: Option<RetentionPolicy>,
+  /// The span of time covered by each shard of the series
+  pub shard_duration: Duration,
+  /// Times of points are truncated to this precision when they are written
+  pub precision: Precision,
+  /// The type of the values of every point of the series
+  pub value_type: ValueType,
+  /// The fields every point has a value for, if the value type is fields
+  pub fields: Vec<Field>,
+  /// Describes the series, sorted by key
+  pub tags: Vec<Tag>,
+  pub duplicate_policy: DuplicatePolicy,
   #[graphql(skip)]
   pub storage_version: i32,
+  /// Not stored with the series, but filled in when it is read
+  #[serde(skip)]
+  pub stats: SeriesStats,
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug, GraphQLInputObject)]
+#[derive(Serialize, Deserialize, PartialEq, Debug, Default, GraphQLInputObject)]
 #[graphql(description = "A collection of data over time")]
 pub struct NewSeries {
   pub name: String,
   pub retention_policy: Option<NewRetentionPolicy>,
+  pub shard_duration: Option<Duration>,
+  pub precision: Option<Precision>,
+  pub value_type: Option<ValueType>,
+  /// Points of a series with fields have a value for each field instead of
+  /// a single value
+  pub fields: Option<Vec<NewField>>,
+  /// Every key can only be given once
+  pub tags: Option<Vec<NewTag>>,
+  pub duplicate_policy: Option<DuplicatePolicy>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, GraphQLObject)]
+#[graphql(description = "A named value of the points of a series")]
+pub struct Field {
+  pub name: String,
+  pub value_type: ValueType,
 }
 
-impl From<NewSeries> for Series {
-  fn from(series: NewSeries) -> Self {
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, GraphQLInputObject)]
+#[graphql(description = "A named value of the points of a series")]
+pub struct NewField {
+  pub name: String,
+  pub value_type: ValueType,
+}
+
+impl From<NewField> for Field {
+  fn from(field: NewField) -> Self {
+    Field {
+      name: field.name,
+      value_type: field.value_type,
+    }
+  }
+}
+
+impl Series {
+  pub fn new(id: SeriesId, series: NewSeries) -> Self {
     Series {
+      id,
       name: series.name,
       retention_policy: series.retention_policy.map(RetentionPolicy::from),
+      shard_duration: series
+        .shard_duration
+        .unwrap_or_else(shard::default_shard_duration),
+      precision: series.precision.unwrap_or_default(),
+      value_type: match series.fields {
+        Some(_) => ValueType::Fields,
+        None => series.value_type.unwrap_or_default(),
+      },
+      fields: series
+        .fields
+        .unwrap_or_default()
+        .into_iter()
+        .map(Field::from)
+        .collect(),
+      tags: {
+        let mut tags = series
+          .tags
+          .unwrap_or_default()
+          .into_iter()
+          .map(Tag::from)
+          .collect::<Vec<_>>();
+        tags.sort_by(|a, b| a.key.cmp(&b.key));
+        tags
+      },
+      duplicate_policy: series.duplicate_policy.unwrap_or_default(),
       storage_version: CURRENT_STORAGE_VERSION,
+      stats: SeriesStats::default(),
+    }
+  }
+
+  /// The types of the values aggregations and writes have to handle
+  pub fn value_types(&self) -> Vec<ValueType> {
+    match self.value_type {
+      ValueType::Fields => self.fields.iter().map(|field| field.value_type).collect(),
+      value_type => vec![value_type],
     }
   }
 }
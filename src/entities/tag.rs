@@ -0,0 +1,146 @@
+use regex::Regex;
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, GraphQLObject)]
+#[graphql(description = "A key and value describing a series, like the host it is measured on")]
+pub struct Tag {
+  pub key: String,
+  pub value: String,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, GraphQLInputObject)]
+#[graphql(description = "A key and value describing a series, like the host it is measured on")]
+pub struct NewTag {
+  pub key: String,
+  pub value: String,
+}
+
+impl From<NewTag> for Tag {
+  fn from(tag: NewTag) -> Self {
+    Tag {
+      key: tag.key,
+      value: tag.value,
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy, GraphQLEnum)]
+pub enum MatchOperator {
+  /// The series has the tag with exactly the value
+  Equal,
+  /// The series lacks the tag or has it with another value
+  NotEqual,
+  /// The series has the tag with a value fully matching the regex
+  Regex,
+  /// The series lacks the tag or has it with a value not fully matching the
+  /// regex
+  NotRegex,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, GraphQLInputObject)]
+#[graphql(description = "Selects series by one of their tags")]
+pub struct TagMatcher {
+  pub key: String,
+  pub operator: MatchOperator,
+  pub value: String,
+}
+
+/// A tag matcher with its regex compiled
+pub enum Matcher {
+  Equal(String, String),
+  NotEqual(String, String),
+  Regex(String, Regex),
+  NotRegex(String, Regex),
+}
+
+impl Matcher {
+  /// Regexes are anchored, so they have to match the whole value
+  pub fn compile(matcher: &TagMatcher) -> Result<Matcher, regex::Error> {
+    let key = matcher.key.clone();
+    let value = matcher.value.clone();
+    Ok(match matcher.operator {
+      MatchOperator::Equal => Matcher::Equal(key, value),
+      MatchOperator::NotEqual => Matcher::NotEqual(key, value),
+      MatchOperator::Regex => Matcher::Regex(key, anchored(&value)?),
+      MatchOperator::NotRegex => Matcher::NotRegex(key, anchored(&value)?),
+    })
+  }
+
+  /// Negated matchers also select series without the tag, so they can not be
+  /// looked up in the index
+  pub fn is_negated(&self) -> bool {
+    match self {
+      Matcher::Equal(..) | Matcher::Regex(..) => false,
+      Matcher::NotEqual(..) | Matcher::NotRegex(..) => true,
+    }
+  }
+
+  pub fn key(&self) -> &str {
+    match self {
+      Matcher::Equal(key, _)
+      | Matcher::NotEqual(key, _)
+      | Matcher::Regex(key, _)
+      | Matcher::NotRegex(key, _) => key,
+    }
+  }
+
+  /// If a tag with the key of the matcher and `value` matches, ignoring
+  /// negation
+  pub fn matches_value(&self, value: &str) -> bool {
+    match self {
+      Matcher::Equal(_, expected) | Matcher::NotEqual(_, expected) => value == expected,
+      Matcher::Regex(_, regex) | Matcher::NotRegex(_, regex) => regex.is_match(value),
+    }
+  }
+
+  pub fn matches(&self, tags: &[Tag]) -> bool {
+    let matched = tags
+      .iter()
+      .any(|tag| tag.key == self.key() && self.matches_value(&tag.value));
+    matched != self.is_negated()
+  }
+}
+
+fn anchored(regex: &str) -> Result<Regex, regex::Error> {
+  Regex::new(&format!("^(?:{})$", regex))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn tags() -> Vec<Tag> {
+    vec![
+      Tag {
+        key: "host".to_string(),
+        value: "web-1".to_string(),
+      },
+      Tag {
+        key: "unit".to_string(),
+        value: "celsius".to_string(),
+      },
+    ]
+  }
+
+  fn matches(key: &str, operator: MatchOperator, value: &str) -> bool {
+    Matcher::compile(&TagMatcher {
+      key: key.to_string(),
+      operator,
+      value: value.to_string(),
+    })
+    .unwrap()
+    .matches(&tags())
+  }
+
+  #[test]
+  fn test_matches() {
+    assert!(matches("host", MatchOperator::Equal, "web-1"));
+    assert!(!matches("host", MatchOperator::Equal, "web"));
+    assert!(matches("host", MatchOperator::NotEqual, "web-2"));
+    assert!(matches("plant", MatchOperator::NotEqual, "north"));
+    assert!(matches("host", MatchOperator::Regex, "web-\\d+"));
+    assert!(!matches("host", MatchOperator::Regex, "web"));
+    assert!(!matches("plant", MatchOperator::Regex, ".*"));
+    assert!(matches("host", MatchOperator::NotRegex, "db-.*"));
+    assert!(!matches("unit", MatchOperator::NotRegex, "c.*|f.*"));
+  }
+}
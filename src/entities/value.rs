@@ -0,0 +1,211 @@
+use crate::entities::histogram::Histogram;
+use juniper::{
+  parser::{ParseError, ScalarToken, Token},
+  ParseScalarResult,
+};
+use std::fmt;
+
+/// The type of the values of a series, every point of a series has it
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy, Default, GraphQLEnum)]
+pub enum ValueType {
+  #[default]
+  Float,
+  Int,
+  Bool,
+  String,
+  /// A value for each field of the series
+  Fields,
+  /// A distribution of numbers
+  Histogram,
+}
+
+impl fmt::Display for ValueType {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      ValueType::Float => write!(f, "float"),
+      ValueType::Int => write!(f, "int"),
+      ValueType::Bool => write!(f, "bool"),
+      ValueType::String => write!(f, "string"),
+      ValueType::Fields => write!(f, "fields"),
+      ValueType::Histogram => write!(f, "histogram"),
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub enum Value {
+  Float(f64),
+  Int(i64),
+  Bool(bool),
+  String(String),
+  /// The values of the fields of a series, in the order of its fields
+  Fields(Vec<FieldValue>),
+  Histogram(Histogram),
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct FieldValue {
+  pub name: String,
+  pub value: Value,
+}
+
+graphql_object!(FieldValue: () as "FieldValue" |&self| {
+    description: "The value of one field of a point"
+
+    field name() -> &str {
+        &self.name
+    }
+
+    field value() -> Option<f64> as "The value as a number, booleans are 0 or 1" {
+        self.value.as_f64()
+    }
+
+    field int_value() -> Option<Int64> {
+        self.value.as_int64()
+    }
+
+    field bool_value() -> Option<bool> {
+        self.value.as_bool()
+    }
+
+    field string_value() -> Option<&str> {
+        self.value.as_str()
+    }
+
+    field histogram() -> Option<&Histogram> {
+        self.value.as_histogram()
+    }
+});
+
+impl Value {
+  pub fn value_type(&self) -> ValueType {
+    match self {
+      Value::Float(_) => ValueType::Float,
+      Value::Int(_) => ValueType::Int,
+      Value::Bool(_) => ValueType::Bool,
+      Value::String(_) => ValueType::String,
+      Value::Fields(_) => ValueType::Fields,
+      Value::Histogram(_) => ValueType::Histogram,
+    }
+  }
+
+  /// The value as a number, booleans are 0 or 1 and strings have none.
+  /// Histograms are their mean.
+  pub fn as_f64(&self) -> Option<f64> {
+    match self {
+      Value::Float(value) => Some(*value),
+      Value::Int(value) => Some(*value as f64),
+      Value::Bool(value) => Some(if *value { 1.0 } else { 0.0 }),
+      Value::Histogram(histogram) => histogram.mean(),
+      Value::String(_) | Value::Fields(_) => None,
+    }
+  }
+
+  pub fn as_int64(&self) -> Option<Int64> {
+    match self {
+      Value::Int(value) => Some(Int64(*value)),
+      _ => None,
+    }
+  }
+
+  pub fn as_bool(&self) -> Option<bool> {
+    match self {
+      Value::Bool(value) => Some(*value),
+      _ => None,
+    }
+  }
+
+  pub fn as_str(&self) -> Option<&str> {
+    match self {
+      Value::String(value) => Some(value),
+      _ => None,
+    }
+  }
+
+  pub fn as_fields(&self) -> Option<&[FieldValue]> {
+    match self {
+      Value::Fields(fields) => Some(fields),
+      _ => None,
+    }
+  }
+
+  pub fn as_histogram(&self) -> Option<&Histogram> {
+    match self {
+      Value::Histogram(histogram) => Some(histogram),
+      _ => None,
+    }
+  }
+
+  /// The 64 bits the value is sealed into blocks with. Strings, fields and
+  /// histograms do not fit and are never sealed.
+  pub fn to_bits(&self) -> Option<u64> {
+    match self {
+      Value::Float(value) => Some(value.to_bits()),
+      Value::Int(value) => Some(*value as u64),
+      Value::Bool(value) => Some(u64::from(*value)),
+      Value::String(_) | Value::Fields(_) | Value::Histogram(_) => None,
+    }
+  }
+
+  pub fn from_bits(value_type: ValueType, bits: u64) -> Option<Value> {
+    match value_type {
+      ValueType::Float => Some(Value::Float(f64::from_bits(bits))),
+      ValueType::Int => Some(Value::Int(bits as i64)),
+      ValueType::Bool => Some(Value::Bool(bits != 0)),
+      ValueType::String | ValueType::Fields | ValueType::Histogram => None,
+    }
+  }
+}
+
+/// A 64-bit integer. GraphQL integers only have 32 bits, so it is written as
+/// a string, and read from either a string or an integer.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct Int64(pub i64);
+
+graphql_scalar!(Int64 as "Int64" where Scalar = <S> {
+    description: "64-bit integer, as a string"
+
+    resolve(&self) -> Value {
+      juniper::Value::scalar(self.0.to_string())
+    }
+
+    from_input_value(v: &InputValue) -> Option<Int64> {
+      v.as_scalar_value::<String>()
+        .and_then(|s| s.parse::<i64>().ok())
+        .or_else(|| v.as_scalar_value::<i32>().map(|&i| i64::from(i)))
+        .map(Int64)
+    }
+
+    from_str<'a>(value: ScalarToken<'a>) -> ParseScalarResult<'a, S> {
+      match value {
+        ScalarToken::String(value) => Ok(S::from(value.to_owned())),
+        ScalarToken::Int(int) => int
+          .parse::<i32>()
+          .map(S::from)
+          .map_err(|_| ParseError::UnexpectedToken(Token::Scalar(value))),
+        _ => Err(ParseError::UnexpectedToken(Token::Scalar(value))),
+      }
+    }
+});
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_bits_roundtrip() {
+    let values = vec![
+      Value::Float(-1.5),
+      Value::Int(i64::MIN),
+      Value::Int(9_007_199_254_740_993),
+      Value::Bool(true),
+      Value::Bool(false),
+    ];
+
+    for value in values {
+      let bits = value.to_bits().unwrap();
+      assert_eq!(Value::from_bits(value.value_type(), bits), Some(value));
+    }
+    assert_eq!(Value::String("open".to_string()).to_bits(), None);
+  }
+}
@@ -0,0 +1,546 @@
+//! Checks that everything stored can be read.
+//!
+//! Reads skip keys and values they can not parse with no more than a warning,
+//! so damaged data goes unnoticed until it is missed. The check scans every
+//! key of the series, points, rollups and blocks column families and reports
+//! what can not be read, what belongs to no series and what does not match
+//! the series it belongs to.
+//!
+//! Repairing deletes the problematic keys, quarantining moves them to the
+//! quarantine column family to be looked at later. Mismatched series are
+//! never changed, they need a human. A series that can not be read is only
+//! quarantined along with its data, which is intact as far as we know, and
+//! repairing leaves both alone.
+
+use crate::block;
+use crate::catalog::SeriesId;
+use crate::codec::{self, KeyKind};
+use crate::database::{
+  Database, Error, BLOCKS_CF, POINTS_CF, QUARANTINE_CF, ROLLUPS_CF, SERIES_CF,
+};
+use crate::entities::annotation::Annotation;
+use crate::entities::point::StoragePoint;
+use crate::entities::series::{Series, SeriesStats, CURRENT_STORAGE_VERSION};
+use crate::entities::value::ValueType;
+use crate::storage::{iter_prefix, Batch};
+use bincode::deserialize;
+use std::collections::HashMap;
+use std::fmt;
+
+/// What to do with the problems that are found
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Fix {
+  Nothing,
+  Repair,
+  Quarantine,
+}
+
+#[derive(PartialEq, Debug)]
+pub enum Problem {
+  UnparsableKey {
+    cf: &'static str,
+    key: Vec<u8>,
+  },
+  UnparsableValue {
+    cf: &'static str,
+    key: Vec<u8>,
+    reason: String,
+  },
+  /// Data of a series that does not exist
+  Orphaned {
+    cf: &'static str,
+    key: Vec<u8>,
+    series_id: SeriesId,
+  },
+  /// Data of a series that exists, but can not be read
+  UnreadableSeries {
+    cf: &'static str,
+    key: Vec<u8>,
+    series_id: SeriesId,
+  },
+  /// A series or block that can be read, but not used as it is
+  FormatMismatch {
+    cf: &'static str,
+    key: Vec<u8>,
+    reason: String,
+  },
+}
+
+impl Problem {
+  fn location(&self) -> (&'static str, &[u8]) {
+    match self {
+      Problem::UnparsableKey { cf, key }
+      | Problem::UnparsableValue { cf, key, .. }
+      | Problem::Orphaned { cf, key, .. }
+      | Problem::UnreadableSeries { cf, key, .. }
+      | Problem::FormatMismatch { cf, key, .. } => (cf, key),
+    }
+  }
+
+  fn is_fixed_by(&self, fix: Fix) -> bool {
+    match self {
+      Problem::FormatMismatch { .. } => false,
+      Problem::UnreadableSeries { .. } => fix == Fix::Quarantine,
+      Problem::UnparsableValue { cf, key, .. }
+        if *cf == SERIES_CF && codec::key_kind(key) == Some(KeyKind::Series) =>
+      {
+        fix == Fix::Quarantine
+      }
+      _ => fix != Fix::Nothing,
+    }
+  }
+}
+
+impl fmt::Display for Problem {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let (cf, key) = self.location();
+    write!(f, "{} {}: ", cf, hex(key))?;
+    match self {
+      Problem::UnparsableKey { .. } => write!(f, "unparsable key"),
+      Problem::UnparsableValue { reason, .. } => write!(f, "unparsable value, {}", reason),
+      Problem::Orphaned { series_id, .. } => write!(f, "no series with id {}", series_id),
+      Problem::UnreadableSeries { series_id, .. } => {
+        write!(f, "series with id {} can not be read", series_id)
+      }
+      Problem::FormatMismatch { reason, .. } => write!(f, "{}", reason),
+    }
+  }
+}
+
+fn hex(bytes: &[u8]) -> String {
+  bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[derive(PartialEq, Debug, Default)]
+pub struct Report {
+  /// The number of keys that were checked
+  pub keys: usize,
+  pub problems: Vec<Problem>,
+  /// The number of problems that were repaired or quarantined
+  pub fixed: usize,
+}
+
+pub fn check(db: &Database, fix: Fix) -> Result<Report, Error> {
+  if fix != Fix::Nothing && db.is_read_only() {
+    return Err(Error::ReadOnly("be repaired"));
+  }
+  let backend = db.backend();
+  let mut report = Report::default();
+
+  // The stored series by id, none for those that can not be read
+  let mut series = HashMap::new();
+  for item in iter_prefix(backend, SERIES_CF, codec::series_prefix()) {
+    let (key, value) = item?;
+    match deserialize::<Series>(&value) {
+      Ok(stored) => {
+        series.insert(stored.id, Some(stored));
+      }
+      Err(_) => {
+        if let Some(series_id) = codec::decode_series_key(&key) {
+          series.entry(series_id).or_insert(None);
+        }
+      }
+    }
+  }
+
+  for &cf in &[SERIES_CF, POINTS_CF, ROLLUPS_CF, BLOCKS_CF] {
+    for item in backend.iter_from(cf, &[]) {
+      let (key, value) = item?;
+      report.keys += 1;
+      let problem = match cf {
+        SERIES_CF => check_series_cf(&series, &key, &value),
+        BLOCKS_CF => check_block(&series, &key, &value),
+        cf => check_point(&series, cf, &key, &value),
+      };
+      if let Some(problem) = problem {
+        report.problems.push(problem);
+      }
+    }
+  }
+
+  if fix == Fix::Nothing {
+    return Ok(report);
+  }
+
+  let mut batch = Batch::default();
+  for problem in report
+    .problems
+    .iter()
+    .filter(|problem| problem.is_fixed_by(fix))
+  {
+    let (cf, key) = problem.location();
+    if fix == Fix::Quarantine {
+      // What can not be decrypted is kept as it is stored
+      let value = backend.get_raw(cf, key)?;
+      let mut quarantine_key = cf.as_bytes().to_vec();
+      quarantine_key.push(0);
+      quarantine_key.extend_from_slice(key);
+      batch.put(QUARANTINE_CF, quarantine_key, value.unwrap_or_default());
+    }
+    batch.delete(cf, key);
+    report.fixed += 1;
+  }
+  backend.write(batch)?;
+
+  if report.fixed > 0 {
+    for series in db.list_series()? {
+      db.refresh_stats(&series)?;
+    }
+  }
+  Ok(report)
+}
+
+fn orphaned(
+  series: &HashMap<SeriesId, Option<Series>>,
+  cf: &'static str,
+  key: &[u8],
+  series_id: SeriesId,
+) -> Option<Problem> {
+  match series.get(&series_id) {
+    Some(Some(_)) => None,
+    Some(None) => Some(Problem::UnreadableSeries {
+      cf,
+      key: key.to_vec(),
+      series_id,
+    }),
+    None => Some(Problem::Orphaned {
+      cf,
+      key: key.to_vec(),
+      series_id,
+    }),
+  }
+}
+
+fn unparsable_key(cf: &'static str, key: &[u8]) -> Option<Problem> {
+  Some(Problem::UnparsableKey {
+    cf,
+    key: key.to_vec(),
+  })
+}
+
+fn unparsable_value(cf: &'static str, key: &[u8], reason: String) -> Option<Problem> {
+  Some(Problem::UnparsableValue {
+    cf,
+    key: key.to_vec(),
+    reason,
+  })
+}
+
+fn check_series_cf(
+  series: &HashMap<SeriesId, Option<Series>>,
+  key: &[u8],
+  value: &[u8],
+) -> Option<Problem> {
+  let cf = SERIES_CF;
+  match codec::key_kind(key) {
+    Some(KeyKind::Catalog) => None,
+    Some(KeyKind::Series) => {
+      let series_id = match codec::decode_series_key(key) {
+        Some(series_id) => series_id,
+        None => return unparsable_key(cf, key),
+      };
+      let stored = match series.get(&series_id) {
+        Some(Some(stored)) => stored,
+        _ => return unparsable_value(cf, key, "not a series".to_string()),
+      };
+      let reason = if stored.id != series_id {
+        format!("series stored under id {} has id {}", series_id, stored.id)
+      } else if stored.storage_version > CURRENT_STORAGE_VERSION {
+        format!(
+          "series \"{}\" has storage version {}",
+          stored.name, stored.storage_version
+        )
+      } else {
+        return None;
+      };
+      Some(Problem::FormatMismatch {
+        cf,
+        key: key.to_vec(),
+        reason,
+      })
+    }
+    Some(KeyKind::SeriesName) => match codec::decode_series_id(value) {
+      Some(series_id) => orphaned(series, cf, key, series_id),
+      None => unparsable_value(cf, key, "not a series id".to_string()),
+    },
+    Some(KeyKind::Shard) => {
+      match (
+        codec::decode_key_series_id(key),
+        codec::decode_shard_start(key),
+      ) {
+        (Some(series_id), Some(_)) => orphaned(series, cf, key, series_id),
+        _ => unparsable_key(cf, key),
+      }
+    }
+    Some(KeyKind::Tag) => match codec::decode_tag_index_key(key) {
+      Some((_, series_id)) => orphaned(series, cf, key, series_id),
+      None => unparsable_key(cf, key),
+    },
+    Some(KeyKind::Annotation) => match codec::decode_annotation_series_id(key) {
+      Some(series_id) => match deserialize::<Annotation>(value) {
+        Ok(_) if series_id == 0 => None,
+        Ok(_) => orphaned(series, cf, key, series_id),
+        Err(_) => unparsable_value(cf, key, "not an annotation".to_string()),
+      },
+      None => unparsable_key(cf, key),
+    },
+    Some(KeyKind::AnnotationId) => None,
+    Some(KeyKind::Stats) => match codec::decode_stats_series_id(key) {
+      Some(series_id) => match deserialize::<SeriesStats>(value) {
+        Ok(_) => orphaned(series, cf, key, series_id),
+        Err(_) => unparsable_value(cf, key, "not series stats".to_string()),
+      },
+      None => unparsable_key(cf, key),
+    },
+    Some(KeyKind::Point) | Some(KeyKind::Block) | None => unparsable_key(cf, key),
+  }
+}
+
+fn check_point(
+  series: &HashMap<SeriesId, Option<Series>>,
+  cf: &'static str,
+  key: &[u8],
+  value: &[u8],
+) -> Option<Problem> {
+  let series_id = match (
+    codec::decode_key_series_id(key),
+    codec::decode_point_time(key),
+  ) {
+    (Some(series_id), Some(_)) => series_id,
+    _ => return unparsable_key(cf, key),
+  };
+  let series = match series.get(&series_id) {
+    Some(Some(series)) => series,
+    _ => return orphaned(series, cf, key, series_id),
+  };
+
+  // Values are stored without their type, so one of another type may decode
+  // without using all of its bytes
+  match StoragePoint::decode(series.value_type, &series.fields, value) {
+    Ok(point) if point.encode().len() == value.len() => None,
+    _ => unparsable_value(cf, key, format!("not a {} value", series.value_type)),
+  }
+}
+
+fn check_block(
+  series: &HashMap<SeriesId, Option<Series>>,
+  key: &[u8],
+  value: &[u8],
+) -> Option<Problem> {
+  let cf = BLOCKS_CF;
+  let series_id = match (
+    codec::decode_key_series_id(key),
+    codec::decode_block_start(key),
+  ) {
+    (Some(series_id), Some(_)) => series_id,
+    _ => return unparsable_key(cf, key),
+  };
+  let series = match series.get(&series_id) {
+    Some(Some(series)) => series,
+    _ => return orphaned(series, cf, key, series_id),
+  };
+
+  if let Err(err) = block::decode(value) {
+    return unparsable_value(cf, key, format!("{:?}", err));
+  }
+  match series.value_type {
+    ValueType::String | ValueType::Fields | ValueType::Histogram => Some(Problem::FormatMismatch {
+      cf,
+      key: key.to_vec(),
+      reason: format!("block of a series of {} values", series.value_type),
+    }),
+    _ => None,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::entities::point::NewPoint;
+  use crate::entities::series::NewSeries;
+  use crate::entities::value::Value;
+  use crate::storage::encrypted::Keyring;
+  use crate::storage::rocks::RocksDbBackend;
+  use crate::storage::StorageBackend;
+  use crate::storage_options::RocksDbConfig;
+  use chrono::{TimeZone, Utc};
+  use tempdir::TempDir;
+
+  #[test]
+  fn test_check() {
+    let db = Database::in_memory();
+    let series = db
+      .create_series(NewSeries {
+        name: "test-series".to_string(),
+        ..Default::default()
+      })
+      .unwrap();
+    let time = Utc.ymd(2019, 6, 10).and_hms(8, 0, 0);
+    db.create_point(
+      "test-series",
+      NewPoint {
+        time,
+        value: Value::Float(1.0),
+      },
+      None,
+    )
+    .unwrap();
+    assert_eq!(check(&db, Fix::Nothing).unwrap().problems, vec![]);
+
+    let backend = db.backend();
+    let orphan = codec::point_key(42, &time);
+    backend
+      .put(
+        POINTS_CF,
+        &orphan,
+        &StoragePoint {
+          value: Value::Float(2.0),
+        }
+        .encode(),
+      )
+      .unwrap();
+    let truncated = codec::point_key(series.id, &(time + chrono::Duration::hours(1)));
+    backend.put(POINTS_CF, &truncated, &[1, 2]).unwrap();
+    backend.put(ROLLUPS_CF, b"garbage", &[]).unwrap();
+
+    let report = check(&db, Fix::Quarantine).unwrap();
+    assert_eq!(
+      report.problems,
+      vec![
+        Problem::UnparsableValue {
+          cf: POINTS_CF,
+          key: truncated.clone(),
+          reason: "not a float value".to_string(),
+        },
+        Problem::Orphaned {
+          cf: POINTS_CF,
+          key: orphan,
+          series_id: 42,
+        },
+        Problem::UnparsableKey {
+          cf: ROLLUPS_CF,
+          key: b"garbage".to_vec(),
+        },
+      ]
+    );
+    assert_eq!(report.fixed, 3);
+
+    let mut quarantined = POINTS_CF.as_bytes().to_vec();
+    quarantined.push(0);
+    quarantined.extend_from_slice(&truncated);
+    assert_eq!(
+      backend.get(QUARANTINE_CF, &quarantined),
+      Ok(Some(vec![1, 2]))
+    );
+    assert_eq!(check(&db, Fix::Nothing).unwrap().problems, vec![]);
+    assert_eq!(db.query("test-series", None).unwrap().len(), 1);
+  }
+
+  #[test]
+  fn test_unparsable_series() {
+    let db = Database::in_memory();
+    db.backend()
+      .put(SERIES_CF, &codec::series_key(7), &[0xff])
+      .unwrap();
+
+    assert_eq!(db.list_series(), Ok(vec![]));
+    assert_eq!(
+      check(&db, Fix::Nothing).unwrap().problems,
+      vec![Problem::UnparsableValue {
+        cf: SERIES_CF,
+        key: codec::series_key(7),
+        reason: "not a series".to_string(),
+      }]
+    );
+  }
+
+  #[test]
+  fn test_quarantine_keeps_ciphertext() {
+    let dir = TempDir::new("kakoidb-quarantine-encrypted").unwrap();
+    let keys = || Keyring::parse(&"01".repeat(32)).unwrap();
+    let time = Utc.timestamp(1_560_000_000, 0);
+    let key = {
+      let db = Database::open_encrypted(dir.path(), &RocksDbConfig::default(), keys()).unwrap();
+      let series = db
+        .create_series(NewSeries {
+          name: "test-series".to_string(),
+          ..Default::default()
+        })
+        .unwrap();
+      db.create_point(
+        "test-series",
+        NewPoint {
+          time,
+          value: Value::Float(1.0),
+        },
+        None,
+      )
+      .unwrap();
+      codec::point_key(series.id, &time)
+    };
+    {
+      let backend =
+        RocksDbBackend::open(dir.path(), &RocksDbConfig::default(), &Default::default()).unwrap();
+      backend.put(POINTS_CF, &key, b"not sealed").unwrap();
+    }
+
+    let db = Database::open_encrypted(dir.path(), &RocksDbConfig::default(), keys()).unwrap();
+    assert_eq!(check(&db, Fix::Quarantine).unwrap().fixed, 1);
+
+    let mut quarantined = POINTS_CF.as_bytes().to_vec();
+    quarantined.push(0);
+    quarantined.extend_from_slice(&key);
+    assert_eq!(
+      db.backend().get(QUARANTINE_CF, &quarantined),
+      Ok(Some(b"not sealed".to_vec()))
+    );
+  }
+
+  #[test]
+  fn test_unreadable_series_data() {
+    let dir = TempDir::new("kakoidb-unreadable-series").unwrap();
+    let series_id = {
+      let db = Database::open(dir.path(), &RocksDbConfig::default()).unwrap();
+      let series = db
+        .create_series(NewSeries {
+          name: "test-series".to_string(),
+          ..Default::default()
+        })
+        .unwrap();
+      db.create_point(
+        "test-series",
+        NewPoint {
+          time: Utc.timestamp(1_560_000_000, 0),
+          value: Value::Float(1.0),
+        },
+        None,
+      )
+      .unwrap();
+      db.backend()
+        .put(SERIES_CF, &codec::series_key(series.id), &[0xff])
+        .unwrap();
+      series.id
+    };
+
+    // The damaged series is left to the check
+    let db = Database::open(dir.path(), &RocksDbConfig::default()).unwrap();
+    assert_eq!(db.list_series(), Ok(vec![]));
+
+    let report = check(&db, Fix::Repair).unwrap();
+    assert_eq!(report.fixed, 0);
+    assert!(report.problems.contains(&Problem::UnreadableSeries {
+      cf: POINTS_CF,
+      key: codec::point_key(series_id, &Utc.timestamp(1_560_000_000, 0)),
+      series_id,
+    }));
+    assert!(report.problems.iter().all(|problem| match problem {
+      Problem::UnparsableValue { cf, .. } => *cf == SERIES_CF,
+      Problem::UnreadableSeries { .. } => true,
+      _ => false,
+    }));
+
+    let report = check(&db, Fix::Quarantine).unwrap();
+    assert_eq!(report.fixed, report.problems.len());
+    assert_eq!(check(&db, Fix::Nothing).unwrap().problems, vec![]);
+  }
+}
@@ -1,12 +1,9 @@
-use crate::database::Database;
+use crate::database::{Database, Error};
 use crate::entities::aggregation::NewAggregationStrategy;
 use crate::entities::point::{Point, QueryOptions};
-use crate::entities::series::RetentionPolicy;
-use bincode::serialize;
+use crate::entities::series::{RetentionPolicy, Series};
 use chrono::prelude::*;
 use chrono::Duration;
-use rocksdb::WriteBatch;
-use std::str;
 use std::sync::{Arc, RwLock, RwLockWriteGuard};
 use std::thread;
 use std::time::Instant;
@@ -40,21 +37,45 @@ pub fn start_janitor(
 
     let task = interval
       .for_each(move |_| {
+        // Followers get what the janitor of their primary writes
+        if db.read().unwrap().is_following() {
+          return future::done(Ok(()));
+        }
         info!("Running janitor");
 
-        let mut db_mut = db.write().unwrap();
-        let series = db_mut.list_series().unwrap();
-
-        let series = series.into_iter().filter_map(|series| {
-          let name = series.name;
-          series.retention_policy.map(|policy| (name, policy))
-        });
-
-        series.for_each(|(series_name, policy)| {
-          debug!("Running janitor on series {}", series_name);
-          garbage_collect_series(&mut db_mut, &series_name, &policy).unwrap();
-          compact_series(&mut db_mut, &series_name, policy).unwrap();
-        });
+        let series = match db.read().unwrap().list_series() {
+          Ok(series) => series,
+          Err(err) => {
+            error!("Janitor could not list the series: {}", err);
+            return future::done(Ok(()));
+          }
+        };
+
+        // An error with one series does not keep the others from being cleaned
+        for series in series {
+          debug!("Running janitor on series {}", series.name);
+          if let Some(policy) = &series.retention_policy {
+            if let Err(err) = garbage_collect_series(&db.read().unwrap(), &series, policy) {
+              error!(
+                "Could not drop the shards of series {}: {}",
+                series.name, err
+              );
+              continue;
+            }
+            if let Err(err) = compact_series(&mut db.write().unwrap(), &series, policy.clone()) {
+              error!("Could not compact series {}: {}", series.name, err);
+              continue;
+            }
+          }
+          let sealed = db.write().unwrap().seal_blocks(&series, Utc::now());
+          match sealed {
+            Ok(sealed) => debug!("Sealed {} points of series {}", sealed, series.name),
+            Err(err) => error!(
+              "Could not seal the blocks of series {}: {}",
+              series.name, err
+            ),
+          }
+        }
 
         future::done(Ok(()))
       })
@@ -66,31 +87,28 @@ pub fn start_janitor(
   Ok(())
 }
 
+/// Drops the shards that ended more than `drop_after` ago. Expired points in
+/// the shard that is still partially retained are left to the compaction
+/// filter.
 fn garbage_collect_series(
-  db: &mut RwLockWriteGuard<Database>,
-  series_name: &str,
+  db: &Database,
+  series: &Series,
   policy: &RetentionPolicy,
-) -> Result<(), rocksdb::Error> {
-  match policy.drop_after.as_ref() {
-    Some(drop_after) => {
-      let drop_until = Utc::now() - drop_after;
-      trace!("Drop until {}", drop_until);
-      db.delete_by_query(
-        &series_name,
-        Some(QueryOptions::with(|options| {
-          options.until = Some(drop_until);
-        })),
-      )
-    }
-    None => Ok(()),
+) -> Result<(), Error> {
+  if let Some(drop_after) = policy.drop_after.as_ref() {
+    let drop_until = Utc::now() - drop_after;
+    trace!("Drop until {}", drop_until);
+    let dropped = db.drop_shards(series, drop_until)?;
+    debug!("Dropped {} shards of series {}", dropped, series.name);
   }
+  Ok(())
 }
 
 fn compact_series(
   db: &mut RwLockWriteGuard<Database>,
-  series_name: &str,
+  series: &Series,
   policy: RetentionPolicy,
-) -> Result<(), rocksdb::Error> {
+) -> Result<(), Error> {
   policy
     .compact
     .into_iter()
@@ -105,58 +123,64 @@ fn compact_series(
         since,
         until,
         aggregate: Some(aggregation_strategy.clone()),
+        fields: None,
       };
 
-      let mut batch = WriteBatch::default();
-      {
-        let mut points = db.iter_points(&series_name, Some(query_options));
+      let rollups = {
+        let mut points = db.iter_points(series, Some(query_options.clone()));
 
         if let Some(first) = points.next() {
+          let first = first?;
           let duration = (&aggregation_strategy.over).into();
+          let function = aggregation_strategy.function;
           let mut count = 1;
           let mut start_time = first.time;
-          let mut value = first.value;
+          let mut partial = function.start(first.value);
+          let mut rollups = vec![];
 
           for point in points {
+            let point = point?;
             if point.time - start_time >= duration {
-              let aggregated_point = Point {
-                time: start_time,
-                value: aggregation_strategy.function.finish(value, count),
-              };
+              rollups.push((start_time, function.finish(partial, count)));
 
               count = 1;
               start_time = point.time;
-              value = point.value;
-
-              debug!("creating aggregation {}", &start_time);
-              batch.put(
-                &format!("points::{}::{}", &series_name, &start_time).into_bytes(),
-                &serialize(&aggregated_point).unwrap(),
-              )?;
+              partial = function.start(point.value);
             } else {
-              count += 1;
-              value = aggregation_strategy.function.reduce(value, point.value);
               debug!("compacting {}", &point.time);
-              batch.delete(&format!("points::{}::{}", &series_name, &point.time).into_bytes())?;
+              count += 1;
+              partial = function.reduce(partial, point.value);
             }
           }
 
-          debug!("creating aggregation2 {}", &start_time);
-          batch.put(
-            &format!("points::{}::{}", &series_name, &start_time).into_bytes(),
-            &serialize(&Point {
-              time: start_time,
-              value: aggregation_strategy.function.finish(value, count),
+          rollups.push((start_time, function.finish(partial, count)));
+          rollups
+            .into_iter()
+            .map(|(time, value)| {
+              Some(Point {
+                time,
+                value: value?,
+              })
             })
-            .unwrap(),
-          )?;
+            .collect::<Option<Vec<_>>>()
+        } else {
+          return Ok(until);
         }
-      }
+      };
 
-      db.write(batch)?;
+      match rollups {
+        // Every compacted point is removed, wherever it is stored, and each
+        // aggregation is written as a rollup at the start of its range
+        Some(rollups) => {
+          db.replace_points(series, &query_options, rollups)?;
+        }
+        None => warn!(
+          "Left points of series {} uncompacted, a sum of integers does not fit a 64-bit integer",
+          series.name
+        ),
+      }
 
       Ok(until)
-    })?;
-
-  Ok(())
+    })
+    .map(|_| ())
 }
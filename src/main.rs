@@ -4,35 +4,72 @@ extern crate simplelog;
 extern crate log;
 #[macro_use]
 extern crate juniper;
+extern crate hyper;
 extern crate juniper_warp;
+extern crate librocksdb_sys;
 extern crate rocksdb;
 extern crate warp;
 #[macro_use]
 extern crate serde_derive;
 extern crate atty;
 extern crate bincode;
+extern crate chacha20poly1305;
 extern crate chrono;
+extern crate regex;
+extern crate serde_json;
 extern crate tokio;
 extern crate tokio_timer;
 
+mod annotations;
 mod api;
+mod backup;
+mod block;
+mod catalog;
+mod codec;
 mod database;
 mod entities;
+mod fsck;
 mod janitor;
+mod migration;
+mod replication;
+mod retention;
+mod shard;
+mod stats;
+mod storage;
+mod storage_options;
 
 use api::{start_api, ApiConfig};
 use atty::Stream;
+use backup::{create_backup, request_backup, restore_backup, BackupConfig};
 use database::Database;
+use entities::duration::Duration;
+use fsck::{check, Fix};
 use janitor::start_janitor;
 use janitor::JanitorConfig;
+use replication::{start_following, ReplicationConfig};
 use simplelog::{SimpleLogger, TermLogger};
+use std::mem;
 use std::path::Path;
 use std::str::FromStr;
 use std::sync::{Arc, RwLock};
+use std::thread;
+use storage::encrypted::Keyring;
+use storage::{BackendKind, Durability};
+use storage_options::RocksDbConfig;
 
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 struct StorageConfig {
   path: String,
+  backend: Option<BackendKind>,
+  durability: Option<Durability>,
+  /// A file with the keys to encrypt values with. Keys are never read from
+  /// the config itself. Series names and tags are stored in plain text.
+  key_file: Option<String>,
+  /// Opens the database for reading only, next to the process writing it
+  read_only: Option<bool>,
+  /// How often a read-only database catches up with the writer
+  catch_up_interval: Option<String>,
+  rocksdb: Option<RocksDbConfig>,
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
@@ -40,6 +77,8 @@ pub struct Config {
   server: Option<ApiConfig>,
   storage: StorageConfig,
   janitor: Option<JanitorConfig>,
+  backup: Option<BackupConfig>,
+  replication: Option<ReplicationConfig>,
   log_level: Option<String>,
 }
 
@@ -56,6 +95,14 @@ fn main() {
         .separator("_")
         .ignore_empty(true),
     )
+    .unwrap()
+    // Keys containing an underscore are nested with a double underscore
+    // Eg.. `KAKOI_STORAGE__ROCKSDB__BLOCK_CACHE_SIZE=1073741824`
+    .merge(
+      config::Environment::with_prefix("KAKOI")
+        .separator("__")
+        .ignore_empty(true),
+    )
     .unwrap();
 
   let config = settings.try_into::<Config>().unwrap_or_else(|err| {
@@ -63,11 +110,13 @@ fn main() {
     ::std::process::exit(1);
   });
 
-  let log_level = log::Level::from_str(&config.log_level.as_ref().unwrap_or(&"info".to_string()))
+  let log_level = log::Level::from_str(config.log_level.as_ref().unwrap_or(&"info".to_string()))
     .unwrap()
     .to_level_filter();
-  let mut log_config = simplelog::Config::default();
-  log_config.time_format = Some("%+");
+  let log_config = simplelog::Config {
+    time_format: Some("%+"),
+    ..Default::default()
+  };
   if atty::is(Stream::Stdout) {
     TermLogger::init(log_level, log_config).unwrap();
   } else {
@@ -78,11 +127,242 @@ fn main() {
   debug!("Config: {:?}", &config);
 
   let db_dir = Path::new(&config.storage.path).join("test.db");
-  let db = Arc::new(RwLock::new(Database::open(db_dir)));
 
-  start_janitor(&config.janitor, db.clone()).unwrap_or_else(|err| {
-    eprintln!("Invalid config [janitor]: {}", err);
+  match std::env::args().nth(1).as_deref() {
+    None | Some("serve") => serve(&config, &db_dir),
+    Some("backup") => {
+      let result = if std::env::args().nth(2).as_deref() == Some("--offline") {
+        let db = open_database(&config, &db_dir);
+        create_backup(backup_config(&config), &db).map_err(|err| err.to_string())
+      } else {
+        let server = config.server.clone().unwrap_or_default();
+        request_backup(server.address())
+      };
+      result.unwrap_or_else(|err| {
+        eprintln!("Could not create backup: {}", err);
+        ::std::process::exit(1);
+      });
+    }
+    Some("fsck") => {
+      let mut fix = Fix::Nothing;
+      let mut repair_rocksdb = false;
+      for flag in std::env::args().skip(2) {
+        match flag.as_str() {
+          "--repair" => fix = Fix::Repair,
+          "--quarantine" => fix = Fix::Quarantine,
+          "--repair-rocksdb" => repair_rocksdb = true,
+          flag => {
+            eprintln!(
+              "Unknown flag \"{}\", expected --repair, --quarantine or --repair-rocksdb",
+              flag
+            );
+            ::std::process::exit(1);
+          }
+        }
+      }
+      if repair_rocksdb {
+        let rocksdb_config = config.storage.rocksdb.clone().unwrap_or_default();
+        Database::repair_rocksdb(&db_dir, &rocksdb_config).unwrap_or_else(|err| {
+          eprintln!("Could not repair database: {}", err);
+          ::std::process::exit(1);
+        });
+      }
+      let db = open_database(&config, &db_dir);
+      let report = check(&db, fix).unwrap_or_else(|err| {
+        eprintln!("Could not check database: {}", err);
+        ::std::process::exit(1);
+      });
+      for problem in &report.problems {
+        println!("{}", problem);
+      }
+      println!(
+        "Checked {} keys, found {} problems and fixed {}",
+        report.keys,
+        report.problems.len(),
+        report.fixed
+      );
+      if report.fixed < report.problems.len() {
+        ::std::process::exit(1);
+      }
+    }
+    Some("rotate-key") => {
+      let keys = encryption_keys(&config).unwrap_or_else(|| {
+        eprintln!("No encryption key given");
+        ::std::process::exit(1);
+      });
+      let rocksdb_config = config.storage.rocksdb.clone().unwrap_or_default();
+      let encrypted = Database::rotate_key(&db_dir, &rocksdb_config, keys).unwrap_or_else(|err| {
+        eprintln!("Could not rotate key: {}", err);
+        ::std::process::exit(1);
+      });
+      println!("Encrypted {} values with the current key", encrypted);
+    }
+    Some("restore") => {
+      restore_backup(backup_config(&config), &db_dir).unwrap_or_else(|err| {
+        eprintln!("Could not restore backup: {}", err);
+        ::std::process::exit(1);
+      });
+    }
+    Some(command) => {
+      eprintln!(
+        "Unknown command \"{}\", expected serve, backup, restore, fsck or rotate-key",
+        command
+      );
+      ::std::process::exit(1);
+    }
+  }
+}
+
+fn open_database(config: &Config, db_dir: &Path) -> Database {
+  if config.storage.backend == Some(BackendKind::Memory) {
+    if is_read_only(config) {
+      eprintln!("The in-memory backend can not be opened read-only");
+      ::std::process::exit(1);
+    }
+    warn!("Using the in-memory backend, nothing will be persisted");
+    return replicate(config, Database::in_memory());
+  }
+  let rocksdb_config = config.storage.rocksdb.clone().unwrap_or_default();
+  if is_read_only(config) {
+    return Database::open_read_only(db_dir, &rocksdb_config, encryption_keys(config))
+      .unwrap_or_else(|err| {
+        eprintln!("Could not open database: {}", err);
+        ::std::process::exit(1);
+      });
+  }
+
+  let db = match encryption_keys(config) {
+    Some(keys) => Database::open_encrypted(db_dir, &rocksdb_config, keys),
+    None => Database::open(db_dir, &rocksdb_config),
+  };
+  let mut db = db.unwrap_or_else(|err| {
+    eprintln!("Could not open database: {}", err);
+    ::std::process::exit(1);
+  });
+  db.set_durability(config.storage.durability.unwrap_or_default());
+  replicate(config, db)
+}
+
+/// Logs the writes to `db` for followers, or follows the primary
+fn replicate(config: &Config, db: Database) -> Database {
+  match &config.replication {
+    Some(replication) => db
+      .with_replication(replication.log_size(), replication.is_following())
+      .unwrap_or_else(|err| {
+        eprintln!("Could not replicate database: {}", err);
+        ::std::process::exit(1);
+      }),
+    None => db,
+  }
+}
+
+fn is_read_only(config: &Config) -> bool {
+  config.storage.read_only.unwrap_or(false)
+}
+
+fn encryption_keys(config: &Config) -> Option<Keyring> {
+  Keyring::load(config.storage.key_file.as_deref()).unwrap_or_else(|err| {
+    eprintln!("Invalid encryption keys: {}", err);
     ::std::process::exit(1);
+  })
+}
+
+fn backup_config(config: &Config) -> &BackupConfig {
+  config.backup.as_ref().unwrap_or_else(|| {
+    eprintln!("Missing config [backup]");
+    ::std::process::exit(1);
+  })
+}
+
+fn serve(config: &Config, db_dir: &Path) {
+  let db = Arc::new(RwLock::new(open_database(config, db_dir)));
+
+  if is_read_only(config) {
+    start_catching_up(config, db_dir, db.clone());
+  } else {
+    if let Some(replication) = config.replication.as_ref().filter(|r| r.is_following()) {
+      start_following(replication, db.clone()).unwrap_or_else(|err| {
+        eprintln!("Invalid config [replication]: {}", err);
+        ::std::process::exit(1);
+      });
+    }
+    start_janitor(&config.janitor, db.clone()).unwrap_or_else(|err| {
+      eprintln!("Invalid config [janitor]: {}", err);
+      ::std::process::exit(1);
+    });
+  }
+  start_api(&config.server, &config.backup, db);
+}
+
+/// Opens the read-only database again every `catch_up_interval`, to read
+/// what the writer wrote since. RocksDB 5.18 has no secondary instances that
+/// could catch up in place, so the database is opened in full. It is opened
+/// before the lock is taken, so requests are only held up by the swap.
+fn start_catching_up(config: &Config, db_dir: &Path, db: Arc<RwLock<Database>>) {
+  let interval = config
+    .storage
+    .catch_up_interval
+    .as_deref()
+    .unwrap_or("1 minute");
+  let interval = Duration::from_string(interval).unwrap_or_else(|| {
+    eprintln!("Invalid config [storage]: Invalid duration for catch_up_interval");
+    ::std::process::exit(1);
+  });
+  let interval = chrono::Duration::from(&interval).to_std().unwrap();
+  let config = config.clone();
+  let rocksdb_config = config.storage.rocksdb.clone().unwrap_or_default();
+  let db_dir = db_dir.to_path_buf();
+
+  thread::spawn(move || loop {
+    thread::sleep(interval);
+    match Database::open_read_only(&db_dir, &rocksdb_config, encryption_keys(&config)) {
+      Ok(caught_up) => {
+        // The replaced database is closed after the lock is released
+        let replaced = mem::replace(&mut *db.write().unwrap(), caught_up);
+        drop(replaced);
+      }
+      Err(err) => error!("Could not catch up with the writer: {}", err),
+    }
   });
-  start_api(&config.server, db.clone());
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use storage_options::Compression;
+
+  const SETTINGS: &str = r#"
+    [storage]
+    path = '/storage'
+    backend = 'memory'
+    durability = 'disabled'
+
+    [storage.rocksdb]
+    compression_per_level = ['none', 'snappy', 'zlib', 'bz2', 'lz4', 'lz4hc', 'zstd']
+  "#;
+
+  #[test]
+  fn test_settings() {
+    let mut settings = config::Config::default();
+    settings
+      .merge(config::File::from_str(SETTINGS, config::FileFormat::Toml))
+      .unwrap();
+    let config = settings.try_into::<Config>().unwrap();
+
+    assert_eq!(config.storage.backend, Some(BackendKind::Memory));
+    assert_eq!(config.storage.durability, Some(Durability::Disabled));
+    let rocksdb = config.storage.rocksdb.unwrap();
+    assert_eq!(
+      rocksdb.compression_per_level,
+      Some(vec![
+        Compression::None,
+        Compression::Snappy,
+        Compression::Zlib,
+        Compression::Bz2,
+        Compression::Lz4,
+        Compression::Lz4hc,
+        Compression::Zstd,
+      ])
+    );
+  }
 }
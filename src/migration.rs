@@ -0,0 +1,642 @@
+//! Upgrades data written by older versions of kakoidb.
+//!
+//! The database stores the version of its layout under a catalog key and
+//! every series stores the version of its own data in `storage_version`.
+//! When the database is opened, the database migrations newer than the
+//! stored version run first, then the series migrations of every series that
+//! is behind. Each migration bumps the stored version once it is done, so an
+//! interrupted upgrade resumes where it stopped.
+//!
+//! Data written by a newer version is refused rather than misread.
+
+use crate::catalog::{Catalog, SeriesId};
+use crate::codec;
+use crate::database::{Error, BLOCKS_CF, DEFAULT_CF, POINTS_CF, ROLLUPS_CF, SERIES_CF};
+use crate::entities::duration::Duration;
+use crate::entities::precision::Precision;
+use crate::entities::series::{
+  DuplicatePolicy, Field, RetentionPolicy, Series, CURRENT_STORAGE_VERSION,
+};
+use crate::entities::tag::Tag;
+use crate::entities::value::ValueType;
+use crate::shard;
+use crate::storage::{iter_prefix, Batch, StorageBackend};
+use bincode::{deserialize, serialize};
+use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
+
+/// The version of the database wide layout written by this build
+pub const CURRENT_FORMAT_VERSION: i32 = 7;
+
+const FORMAT_VERSION: &str = "format_version";
+
+/// Points moved per write batch, and between progress logs
+const BATCH_SIZE: usize = 10_000;
+
+struct Migration {
+  /// The version the migration upgrades to
+  version: i32,
+  description: &'static str,
+  run: fn(&dyn StorageBackend, &Catalog) -> Result<(), Error>,
+}
+
+struct SeriesMigration {
+  /// The version the migration upgrades to
+  version: i32,
+  description: &'static str,
+  run: fn(&dyn StorageBackend, &Series) -> Result<(), Error>,
+}
+
+const MIGRATIONS: &[Migration] = &[
+  Migration {
+    version: 1,
+    description: "move series from string keys into the catalog",
+    run: migrate_legacy_catalog,
+  },
+  Migration {
+    version: 2,
+    description: "add shard durations to series",
+    run: add_shard_durations,
+  },
+  Migration {
+    version: 3,
+    description: "add time precisions to series",
+    run: add_precisions,
+  },
+  Migration {
+    version: 4,
+    description: "add value types to series",
+    run: add_value_types,
+  },
+  Migration {
+    version: 5,
+    description: "add fields to series",
+    run: add_fields,
+  },
+  Migration {
+    version: 6,
+    description: "add tags to series",
+    run: add_tags,
+  },
+  Migration {
+    version: 7,
+    description: "add duplicate policies to series",
+    run: add_duplicate_policies,
+  },
+];
+
+const SERIES_MIGRATIONS: &[SeriesMigration] = &[
+  SeriesMigration {
+    version: 1,
+    description: "move points from string keys into the points column family",
+    run: migrate_legacy_points,
+  },
+  SeriesMigration {
+    version: 2,
+    description: "record the shards points are stored in",
+    run: index_shards,
+  },
+];
+
+pub fn migrate(db: &dyn StorageBackend, catalog: &Catalog) -> Result<(), Error> {
+  let format_version = read_format_version(db)?;
+  if format_version > CURRENT_FORMAT_VERSION {
+    return Err(Error::UnsupportedFormatVersion(format_version));
+  }
+
+  for migration in MIGRATIONS
+    .iter()
+    .filter(|migration| migration.version > format_version)
+  {
+    info!(
+      "Migrating database to format version {}: {}",
+      migration.version, migration.description
+    );
+    (migration.run)(db, catalog)?;
+    write_format_version(db, migration.version)?;
+  }
+
+  for mut series in list_series(db)? {
+    if series.storage_version > CURRENT_STORAGE_VERSION {
+      return Err(Error::UnsupportedStorageVersion(
+        series.name,
+        series.storage_version,
+      ));
+    }
+
+    let storage_version = series.storage_version;
+    for migration in SERIES_MIGRATIONS
+      .iter()
+      .filter(|migration| migration.version > storage_version)
+    {
+      info!(
+        "Migrating series {} to storage version {}: {}",
+        series.name, migration.version, migration.description
+      );
+      (migration.run)(db, &series)?;
+      series.storage_version = migration.version;
+      catalog.update(db, &series)?;
+    }
+  }
+
+  Ok(())
+}
+
+/// Fails unless the database and every series are at the current versions,
+/// for databases that are opened read-only and can not be migrated
+pub fn check(db: &dyn StorageBackend) -> Result<(), Error> {
+  let format_version = match db.get(SERIES_CF, &codec::catalog_key(FORMAT_VERSION))? {
+    Some(bytes) => decode_format_version(&bytes)?,
+    None if is_legacy(db)? => 0,
+    None => CURRENT_FORMAT_VERSION,
+  };
+  if format_version > CURRENT_FORMAT_VERSION {
+    return Err(Error::UnsupportedFormatVersion(format_version));
+  }
+  if format_version < CURRENT_FORMAT_VERSION {
+    return Err(Error::ReadOnly("be migrated"));
+  }
+
+  for series in list_series(db)? {
+    match series.storage_version.cmp(&CURRENT_STORAGE_VERSION) {
+      Ordering::Greater => {
+        return Err(Error::UnsupportedStorageVersion(
+          series.name,
+          series.storage_version,
+        ))
+      }
+      Ordering::Less => return Err(Error::ReadOnly("be migrated")),
+      Ordering::Equal => (),
+    }
+  }
+  Ok(())
+}
+
+fn decode_format_version(bytes: &[u8]) -> Result<i32, Error> {
+  let mut buffer = [0; 4];
+  if bytes.len() != buffer.len() {
+    return Err(Error::Storage(format!(
+      "Stored format version is {} bytes long instead of {}",
+      bytes.len(),
+      buffer.len()
+    )));
+  }
+  buffer.copy_from_slice(bytes);
+  Ok(i32::from_be_bytes(buffer))
+}
+
+/// The stored format version. A database without one is either new or was
+/// written before versions were recorded, which is told apart by the string
+/// keys that layout left in the default column family.
+fn read_format_version(db: &dyn StorageBackend) -> Result<i32, Error> {
+  let stored = db.get(SERIES_CF, &codec::catalog_key(FORMAT_VERSION))?;
+
+  match stored {
+    Some(bytes) => decode_format_version(&bytes),
+    None => {
+      if is_legacy(db)? {
+        Ok(0)
+      } else {
+        write_format_version(db, CURRENT_FORMAT_VERSION)?;
+        Ok(CURRENT_FORMAT_VERSION)
+      }
+    }
+  }
+}
+
+/// If the layout from before column families were used left keys in the
+/// default column family
+fn is_legacy(db: &dyn StorageBackend) -> Result<bool, Error> {
+  Ok(db.iter_from(DEFAULT_CF, &[]).next().transpose()?.is_some())
+}
+
+fn write_format_version(db: &dyn StorageBackend, version: i32) -> Result<(), Error> {
+  db.put(
+    SERIES_CF,
+    &codec::catalog_key(FORMAT_VERSION),
+    &version.to_be_bytes(),
+  )
+}
+
+fn list_series(db: &dyn StorageBackend) -> Result<Vec<Series>, Error> {
+  Ok(
+    decode_all::<Series>(db, SERIES_CF, codec::series_prefix())?
+      .into_iter()
+      .map(|(_, series)| series)
+      .collect(),
+  )
+}
+
+/// Decodes every value under `prefix`. Values that can not be decoded are
+/// left as they are, for the integrity check to report.
+fn decode_all<T: DeserializeOwned>(
+  db: &dyn StorageBackend,
+  cf: &str,
+  prefix: Vec<u8>,
+) -> Result<Vec<(Vec<u8>, T)>, Error> {
+  let mut decoded = vec![];
+  for item in iter_prefix(db, cf, prefix) {
+    let (key, value) = item?;
+    match deserialize(&value) {
+      Ok(value) => decoded.push((key.to_vec(), value)),
+      Err(err) => {
+        warn!("Could not parse {} {:?}, it is not migrated", cf, key);
+        debug!("Parse error: {:?}", err);
+      }
+    }
+  }
+  Ok(decoded)
+}
+
+/// A series as it was stored under `series::<name>`
+#[derive(Deserialize)]
+struct LegacySeries {
+  name: String,
+  retention_policy: Option<RetentionPolicy>,
+}
+
+fn migrate_legacy_catalog(db: &dyn StorageBackend, catalog: &Catalog) -> Result<(), Error> {
+  for (key, legacy) in decode_all::<LegacySeries>(db, DEFAULT_CF, b"series::".to_vec())? {
+    debug!("Adding series {} to the catalog", legacy.name);
+
+    // Series left behind by an interrupted migration are already there
+    match catalog.insert(db, &legacy.name, &[], |series_id| SeriesV1 {
+      id: series_id,
+      name: legacy.name.clone(),
+      retention_policy: legacy.retention_policy.clone(),
+      storage_version: 0,
+    }) {
+      Ok(_) | Err(Error::SeriesExists(_)) => (),
+      Err(error) => return Err(error),
+    }
+    db.delete(DEFAULT_CF, &key)?;
+  }
+
+  Ok(())
+}
+
+/// A series as it was stored before it had a shard duration
+#[derive(Serialize, Deserialize)]
+struct SeriesV1 {
+  id: SeriesId,
+  name: String,
+  retention_policy: Option<RetentionPolicy>,
+  storage_version: i32,
+}
+
+fn add_shard_durations(db: &dyn StorageBackend, _catalog: &Catalog) -> Result<(), Error> {
+  for (_, series) in decode_all::<SeriesV1>(db, SERIES_CF, codec::series_prefix())? {
+    let series = SeriesV2 {
+      id: series.id,
+      name: series.name,
+      retention_policy: series.retention_policy,
+      shard_duration: shard::default_shard_duration(),
+      storage_version: series.storage_version,
+    };
+    put_series(db, series.id, &series)?;
+  }
+
+  Ok(())
+}
+
+/// A series as it was stored before it had a precision
+#[derive(Serialize, Deserialize)]
+struct SeriesV2 {
+  id: SeriesId,
+  name: String,
+  retention_policy: Option<RetentionPolicy>,
+  shard_duration: Duration,
+  storage_version: i32,
+}
+
+/// Series written so far kept the full nanosecond precision
+fn add_precisions(db: &dyn StorageBackend, _catalog: &Catalog) -> Result<(), Error> {
+  for (_, series) in decode_all::<SeriesV2>(db, SERIES_CF, codec::series_prefix())? {
+    let series = SeriesV3 {
+      id: series.id,
+      name: series.name,
+      retention_policy: series.retention_policy,
+      shard_duration: series.shard_duration,
+      precision: Precision::Nanoseconds,
+      storage_version: series.storage_version,
+    };
+    put_series(db, series.id, &series)?;
+  }
+
+  Ok(())
+}
+
+/// A series as it was stored before it had a value type
+#[derive(Serialize, Deserialize)]
+struct SeriesV3 {
+  id: SeriesId,
+  name: String,
+  retention_policy: Option<RetentionPolicy>,
+  shard_duration: Duration,
+  precision: Precision,
+  storage_version: i32,
+}
+
+/// Series written so far only held floats
+fn add_value_types(db: &dyn StorageBackend, _catalog: &Catalog) -> Result<(), Error> {
+  for (_, series) in decode_all::<SeriesV3>(db, SERIES_CF, codec::series_prefix())? {
+    let series = SeriesV4 {
+      id: series.id,
+      name: series.name,
+      retention_policy: series.retention_policy,
+      shard_duration: series.shard_duration,
+      precision: series.precision,
+      value_type: ValueType::Float,
+      storage_version: series.storage_version,
+    };
+    put_series(db, series.id, &series)?;
+  }
+
+  Ok(())
+}
+
+/// A series as it was stored before it could have fields
+#[derive(Serialize, Deserialize)]
+struct SeriesV4 {
+  id: SeriesId,
+  name: String,
+  retention_policy: Option<RetentionPolicy>,
+  shard_duration: Duration,
+  precision: Precision,
+  value_type: ValueType,
+  storage_version: i32,
+}
+
+fn add_fields(db: &dyn StorageBackend, _catalog: &Catalog) -> Result<(), Error> {
+  for (_, series) in decode_all::<SeriesV4>(db, SERIES_CF, codec::series_prefix())? {
+    let series = SeriesV5 {
+      id: series.id,
+      name: series.name,
+      retention_policy: series.retention_policy,
+      shard_duration: series.shard_duration,
+      precision: series.precision,
+      value_type: series.value_type,
+      fields: vec![],
+      storage_version: series.storage_version,
+    };
+    put_series(db, series.id, &series)?;
+  }
+
+  Ok(())
+}
+
+/// A series as it was stored before it could have tags
+#[derive(Serialize, Deserialize)]
+struct SeriesV5 {
+  id: SeriesId,
+  name: String,
+  retention_policy: Option<RetentionPolicy>,
+  shard_duration: Duration,
+  precision: Precision,
+  value_type: ValueType,
+  fields: Vec<Field>,
+  storage_version: i32,
+}
+
+/// Series without tags have nothing to index
+fn add_tags(db: &dyn StorageBackend, _catalog: &Catalog) -> Result<(), Error> {
+  for (_, series) in decode_all::<SeriesV5>(db, SERIES_CF, codec::series_prefix())? {
+    let series = SeriesV6 {
+      id: series.id,
+      name: series.name,
+      retention_policy: series.retention_policy,
+      shard_duration: series.shard_duration,
+      precision: series.precision,
+      value_type: series.value_type,
+      fields: series.fields,
+      tags: vec![],
+      storage_version: series.storage_version,
+    };
+    put_series(db, series.id, &series)?;
+  }
+
+  Ok(())
+}
+
+/// A series as it was stored before it had a duplicate policy
+#[derive(Serialize, Deserialize)]
+struct SeriesV6 {
+  id: SeriesId,
+  name: String,
+  retention_policy: Option<RetentionPolicy>,
+  shard_duration: Duration,
+  precision: Precision,
+  value_type: ValueType,
+  fields: Vec<Field>,
+  tags: Vec<Tag>,
+  storage_version: i32,
+}
+
+/// Points written so far overwrote each other
+fn add_duplicate_policies(db: &dyn StorageBackend, catalog: &Catalog) -> Result<(), Error> {
+  for (_, series) in decode_all::<SeriesV6>(db, SERIES_CF, codec::series_prefix())? {
+    catalog.update(
+      db,
+      &Series {
+        id: series.id,
+        name: series.name,
+        retention_policy: series.retention_policy,
+        shard_duration: series.shard_duration,
+        precision: series.precision,
+        value_type: series.value_type,
+        fields: series.fields,
+        tags: series.tags,
+        duplicate_policy: DuplicatePolicy::Overwrite,
+        storage_version: series.storage_version,
+        stats: Default::default(),
+      },
+    )?;
+  }
+
+  Ok(())
+}
+
+/// Overwrites a series with an older layout than `Series`
+fn put_series<T: Serialize>(
+  db: &dyn StorageBackend,
+  series_id: SeriesId,
+  series: &T,
+) -> Result<(), Error> {
+  db.put(
+    SERIES_CF,
+    &codec::series_key(series_id),
+    &serialize(series).unwrap(),
+  )
+}
+
+fn index_shards(db: &dyn StorageBackend, series: &Series) -> Result<(), Error> {
+  let mut shards = BTreeSet::new();
+  for (cf, prefix) in &[
+    (POINTS_CF, codec::point_prefix(series.id)),
+    (ROLLUPS_CF, codec::point_prefix(series.id)),
+    (BLOCKS_CF, codec::block_prefix(series.id)),
+  ] {
+    for item in iter_prefix(db, cf, prefix.clone()) {
+      let (key, _) = item?;
+      let time = match *cf {
+        BLOCKS_CF => codec::decode_block_start(&key),
+        _ => codec::decode_point_time(&key),
+      };
+      if let Some(time) = time {
+        shards.insert(shard::shard_start(&time, &series.shard_duration));
+      }
+    }
+  }
+
+  let mut batch = Batch::default();
+  for start in &shards {
+    batch.put(SERIES_CF, codec::shard_key(series.id, start), []);
+  }
+  db.write(batch)?;
+  info!("Found {} shards of series {}", shards.len(), series.name);
+
+  Ok(())
+}
+
+fn migrate_legacy_points(db: &dyn StorageBackend, series: &Series) -> Result<(), Error> {
+  let prefix = format!("points::{}::", series.name).into_bytes();
+
+  let mut batch = Batch::default();
+  let mut moved = 0;
+  for item in iter_prefix(db, DEFAULT_CF, prefix.clone()) {
+    let (key, value) = item?;
+    let time = String::from_utf8_lossy(&key[prefix.len()..]);
+    match time.parse::<DateTime<Utc>>() {
+      Ok(time) => {
+        batch.put(POINTS_CF, codec::point_key(series.id, &time), &value);
+        moved += 1;
+      }
+      // Keys of another series whose name starts with this one
+      Err(_) if time.contains("::") => continue,
+      Err(_) => {
+        warn!(
+          "Left point key {} of series {} in place, its time can not be parsed",
+          String::from_utf8_lossy(&key),
+          series.name
+        );
+        continue;
+      }
+    }
+    batch.delete(DEFAULT_CF, &key);
+
+    if moved % BATCH_SIZE == 0 {
+      db.write(batch)?;
+      batch = Batch::default();
+      info!("Moved {} points of series {}", moved, series.name);
+    }
+  }
+  db.write(batch)?;
+  info!("Moved {} points of series {}", moved, series.name);
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::database::Database;
+  use crate::entities::point::StoragePoint;
+  use crate::entities::value::Value;
+  use crate::storage::rocks::RocksDbBackend;
+  use chrono::TimeZone;
+  use rocksdb::DB;
+  use tempdir::TempDir;
+
+  #[derive(Serialize)]
+  struct LegacySeriesValue {
+    name: String,
+    retention_policy: Option<RetentionPolicy>,
+    storage_version: i32,
+  }
+
+  #[test]
+  fn test_migrate_legacy_database() {
+    let tmp_dir = TempDir::new("test_migrate_legacy_database").unwrap();
+    let db_path = tmp_dir.path().join("db");
+    let time = Utc.timestamp(1_560_000_000, 0);
+
+    {
+      let legacy_db = DB::open_default(&db_path).unwrap();
+      legacy_db
+        .put(
+          b"series::cpu",
+          serialize(&LegacySeriesValue {
+            name: "cpu".to_string(),
+            retention_policy: None,
+            storage_version: 0,
+          })
+          .unwrap(),
+        )
+        .unwrap();
+      legacy_db
+        .put(
+          format!("points::cpu::{}", time.to_rfc3339()),
+          StoragePoint {
+            value: Value::Float(42.0),
+          }
+          .encode(),
+        )
+        .unwrap();
+    }
+
+    let db = Database::open(&db_path, &Default::default()).unwrap();
+    let series = db.get_series("cpu").unwrap().unwrap();
+    let points = db.query("cpu", None).unwrap();
+
+    assert_eq!(series.storage_version, CURRENT_STORAGE_VERSION);
+    assert_eq!(points.len(), 1);
+    assert_eq!(points[0].time, time);
+    assert_eq!(points[0].value, Value::Float(42.0));
+    assert_eq!(
+      db.drop_shards(&series, time + chrono::Duration::days(1)),
+      Ok(1)
+    );
+    assert_eq!(db.query("cpu", None), Ok(vec![]));
+  }
+
+  #[test]
+  fn test_refuse_newer_format_version() {
+    let tmp_dir = TempDir::new("test_refuse_newer_format_version").unwrap();
+    let db_path = tmp_dir.path().join("db");
+
+    {
+      let db = Database::open(&db_path, &Default::default()).unwrap();
+      drop(db);
+      let backend =
+        RocksDbBackend::open(&db_path, &Default::default(), &Default::default()).unwrap();
+      write_format_version(&backend, CURRENT_FORMAT_VERSION + 1).unwrap();
+    }
+
+    assert_eq!(
+      Database::open(&db_path, &Default::default()).err(),
+      Some(Error::UnsupportedFormatVersion(CURRENT_FORMAT_VERSION + 1))
+    );
+  }
+
+  #[test]
+  fn test_invalid_format_version() {
+    let tmp_dir = TempDir::new("test_invalid_format_version").unwrap();
+    let db_path = tmp_dir.path().join("db");
+
+    {
+      let backend =
+        RocksDbBackend::open(&db_path, &Default::default(), &Default::default()).unwrap();
+      backend
+        .put(SERIES_CF, &codec::catalog_key(FORMAT_VERSION), &[7])
+        .unwrap();
+    }
+
+    assert!(matches!(
+      Database::open(&db_path, &Default::default()),
+      Err(Error::Storage(_))
+    ));
+  }
+}
@@ -0,0 +1,489 @@
+//! Asynchronous replication from a primary to its followers.
+//!
+//! The primary serves its log of write batches at `/replication/log`.
+//! Followers poll it for the batches after the last one they applied, so a
+//! follower lags behind its primary by up to the poll interval, plus however
+//! long it takes to apply what it fetched. A follower is seeded from a backup
+//! of its primary, and is promoted to primary with the `promote` mutation.
+//!
+//! Expired points are dropped by the compaction filter of each database on
+//! its own, as compactions are not logged. A follower keeps the retention
+//! policies of the series it applies, so that it drops what its primary
+//! drops.
+
+use crate::database::{Database, Error};
+use crate::entities::value::Int64;
+use crate::storage::replicated::LogEntry;
+use bincode::{deserialize, serialize};
+use hyper::client::connect::{Connect, Connected, Destination};
+use hyper::{Body, Client, Uri};
+use std::io;
+use std::net;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::prelude::future::FutureResult;
+use tokio::prelude::*;
+use tokio::reactor::Handle;
+use tokio::timer::Delay;
+use warp::filters::BoxedFilter;
+use warp::http::{Response, StatusCode};
+use warp::Filter;
+
+/// How long a follower waits before polling again, once it has caught up
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// The most batches a follower fetches at once
+const FETCH_LIMIT: usize = 1000;
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct ReplicationConfig {
+  /// Number of write batches kept in the log for followers to fetch. A
+  /// follower that falls further behind has to be seeded again.
+  log_size: Option<u64>,
+  /// The address of the API of the primary to follow, like
+  /// "http://10.0.0.1:7766". Remove it once the follower is promoted, as a
+  /// promoted database refuses to follow again.
+  primary: Option<String>,
+}
+
+impl ReplicationConfig {
+  pub fn log_size(&self) -> u64 {
+    self.log_size.unwrap_or(100_000)
+  }
+
+  pub fn is_following(&self) -> bool {
+    self.primary.is_some()
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct LogQuery {
+  after: u64,
+}
+
+/// What the log endpoint responds with
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct LogChunk {
+  log_id: u64,
+  last_sequence: u64,
+  entries: Vec<LogEntry>,
+}
+
+#[derive(PartialEq, Debug, GraphQLObject)]
+#[graphql(description = "Where the database is in the log of write batches")]
+pub struct ReplicationStatus {
+  /// If the database applies the write batches of a primary
+  following: bool,
+  /// The sequence number of the last write batch
+  sequence: Int64,
+  /// The sequence number of the last write batch of the primary, when it
+  /// was last heard from
+  primary_sequence: Option<Int64>,
+}
+
+impl ReplicationStatus {
+  pub fn of(db: &Database) -> Option<ReplicationStatus> {
+    let replication = db.replication()?;
+    let following = replication.is_following();
+    Some(ReplicationStatus {
+      following,
+      sequence: Int64(replication.last_sequence() as i64),
+      primary_sequence: replication
+        .status()
+        .primary_sequence
+        .filter(|_| following)
+        .map(|sequence| Int64(sequence as i64)),
+    })
+  }
+}
+
+/// Serves the write batches after the `after` query parameter, encoded with
+/// bincode
+pub fn log_filter(db: Arc<RwLock<Database>>) -> BoxedFilter<(Response<Vec<u8>>,)> {
+  warp::get2()
+    .and(warp::path("replication"))
+    .and(warp::path("log"))
+    .and(warp::path::end())
+    .and(warp::query::<LogQuery>())
+    .map(move |query: LogQuery| {
+      let chunk = read_log(&db.read().unwrap(), query.after);
+      let (status, body) = match chunk {
+        Ok(chunk) => (StatusCode::OK, serialize(&chunk).unwrap()),
+        Err(err) => {
+          let status = match err {
+            Error::ReplicationDisabled => StatusCode::NOT_FOUND,
+            Error::LogTrimmed(_) => StatusCode::GONE,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+          };
+          (status, err.to_string().into_bytes())
+        }
+      };
+      Response::builder().status(status).body(body).unwrap()
+    })
+    .boxed()
+}
+
+fn read_log(db: &Database, after: u64) -> Result<LogChunk, Error> {
+  let replication = db.replication().ok_or(Error::ReplicationDisabled)?;
+  Ok(LogChunk {
+    log_id: replication.log_id(),
+    last_sequence: replication.last_sequence(),
+    entries: replication.read(after, FETCH_LIMIT)?,
+  })
+}
+
+/// Connects with the standard library. The connector of hyper builds socket
+/// addresses from the memory layout of those of the standard library, which
+/// changed in Rust 1.64, so it can not connect on newer versions.
+pub struct StdConnector;
+
+impl Connect for StdConnector {
+  type Transport = TcpStream;
+  type Error = io::Error;
+  type Future = FutureResult<(TcpStream, Connected), io::Error>;
+
+  fn connect(&self, destination: Destination) -> Self::Future {
+    let port = destination.port().unwrap_or(80);
+    future::result(
+      net::TcpStream::connect((destination.host(), port))
+        .and_then(|stream| TcpStream::from_std(stream, &Handle::default()))
+        .map(|stream| (stream, Connected::new())),
+    )
+  }
+}
+
+/// Polls the primary for write batches and applies them, until the
+/// database is promoted
+pub fn start_following(
+  config: &ReplicationConfig,
+  db: Arc<RwLock<Database>>,
+) -> Result<(), &'static str> {
+  let primary = config
+    .primary
+    .as_ref()
+    .ok_or("Missing primary to follow")?
+    .trim_end_matches('/')
+    .to_string();
+  primary
+    .parse::<Uri>()
+    .map_err(|_| "Invalid address for primary")?;
+
+  thread::spawn(move || {
+    info!("Following {}", primary);
+    let client = Client::builder().build(StdConnector);
+
+    let task = future::loop_fn((), move |_| {
+      let db = db.clone();
+      poll(&client, &primary, &db).then(move |result| {
+        let caught_up = match result {
+          Ok(applied) => applied < FETCH_LIMIT,
+          Err(err) => {
+            error!("Could not replicate from the primary: {}", err);
+            true
+          }
+        };
+        let wait = if caught_up {
+          POLL_INTERVAL
+        } else {
+          Duration::from_secs(0)
+        };
+        Delay::new(Instant::now() + wait).then(move |_| {
+          if db.read().unwrap().is_following() {
+            Ok(future::Loop::Continue(()))
+          } else {
+            info!("Promoted to primary, stopped following");
+            Ok(future::Loop::Break(()))
+          }
+        })
+      })
+    });
+
+    tokio::run(task);
+  });
+
+  Ok(())
+}
+
+/// Fetches the batches after the last one applied from the primary and
+/// applies them, returning how many were fetched
+fn poll(
+  client: &Client<StdConnector, Body>,
+  primary: &str,
+  db: &Arc<RwLock<Database>>,
+) -> impl Future<Item = usize, Error = String> {
+  let db = db.clone();
+  let after = db
+    .read()
+    .unwrap()
+    .replication()
+    .map_or(0, |replication| replication.last_sequence());
+  let uri = format!("{}/replication/log?after={}", primary, after)
+    .parse::<Uri>()
+    .unwrap();
+
+  client
+    .get(uri)
+    .and_then(|response| {
+      let status = response.status();
+      response
+        .into_body()
+        .concat2()
+        .map(move |body| (status, body))
+    })
+    .map_err(|err| err.to_string())
+    .and_then(|(status, body)| {
+      if !status.is_success() {
+        return Err(String::from_utf8_lossy(&body).into_owned());
+      }
+      deserialize::<LogChunk>(&body).map_err(|err| err.to_string())
+    })
+    .and_then(move |chunk| apply_chunk(&db.read().unwrap(), &chunk).map_err(|err| err.to_string()))
+}
+
+/// Applies the batches fetched from the primary, returning how many there
+/// were
+fn apply_chunk(db: &Database, chunk: &LogChunk) -> Result<usize, Error> {
+  let replication = db.replication().ok_or(Error::ReplicationDisabled)?;
+  replication.check_primary(chunk.log_id)?;
+  for entry in &chunk.entries {
+    db.apply(entry)?;
+  }
+  replication.heard_from_primary(chunk.last_sequence);
+  Ok(chunk.entries.len())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::codec;
+  use crate::database::POINTS_CF;
+  use crate::entities::point::NewPoint;
+  use crate::entities::series::{NewRetentionPolicy, NewSeries};
+  use crate::entities::value::Value;
+  use crate::storage::encrypted::Keyring;
+  use crate::storage_options::RocksDbConfig;
+  use chrono::{TimeZone, Utc};
+  use tempdir::TempDir;
+  use tokio::runtime::Runtime;
+
+  #[test]
+  fn test_follow() {
+    let primary = Database::in_memory().with_replication(100, false).unwrap();
+    let mut follower = Database::in_memory().with_replication(100, true).unwrap();
+    primary.create_series(new_series(None)).unwrap();
+    let point = |seconds: i64| NewPoint {
+      time: Utc.timestamp(1_560_000_000 + seconds, 0),
+      value: Value::Float(1.0),
+    };
+    primary.create_point("test", point(0), None).unwrap();
+
+    let apply = |follower: &Database| {
+      let after = follower.replication().unwrap().last_sequence();
+      apply_chunk(follower, &read_log(&primary, after).unwrap()).unwrap();
+    };
+    apply(&follower);
+    let status = ReplicationStatus::of(&follower).unwrap();
+    assert!(status.following);
+    assert_eq!(status.sequence, status.primary_sequence.unwrap());
+    assert!(follower.lag().unwrap() < chrono::Duration::seconds(1));
+
+    let snapshot = follower.snapshot().unwrap();
+    assert_eq!(snapshot.query("test", None).unwrap().len(), 1);
+    assert_eq!(snapshot.get_series("test").unwrap().unwrap().stats.count, 1);
+
+    primary.create_point("test", point(1), None).unwrap();
+    apply(&follower);
+    follower.promote().unwrap();
+    assert_eq!(follower.lag(), None);
+    assert_eq!(follower.promote(), Err(Error::NotFollowing));
+
+    // The promoted follower knows of the series of the primary
+    follower.create_point("test", point(2), None).unwrap();
+    let series = follower.get_series("test").unwrap().unwrap();
+    assert_eq!(series.stats.count, 3);
+    assert_eq!(follower.query("test", None).unwrap().len(), 3);
+
+    // The log of the promoted follower goes on from the log of the primary
+    let sequence = primary.replication().unwrap().last_sequence();
+    assert_eq!(
+      follower.replication().unwrap().last_sequence(),
+      sequence + 1
+    );
+    // And is not the log of the primary anymore
+    primary.create_point("test", point(3), None).unwrap();
+    let chunk = read_log(&primary, sequence).unwrap();
+    assert!(apply_chunk(&follower, &chunk).is_err());
+  }
+
+  fn new_series(retention_policy: Option<NewRetentionPolicy>) -> NewSeries {
+    NewSeries {
+      name: "test".to_string(),
+      retention_policy,
+      ..Default::default()
+    }
+  }
+
+  #[test]
+  fn test_promotion_is_kept() {
+    let dir = TempDir::new("kakoidb-promotion").unwrap();
+    let primary = Database::in_memory().with_replication(100, false).unwrap();
+    primary.create_series(new_series(None)).unwrap();
+    {
+      let mut follower = Database::open(dir.path(), &RocksDbConfig::default())
+        .unwrap()
+        .with_replication(100, true)
+        .unwrap();
+      apply_chunk(&follower, &read_log(&primary, 0).unwrap()).unwrap();
+      follower.promote().unwrap();
+    }
+
+    // Restarted with the primary still configured
+    let follower = Database::open(dir.path(), &RocksDbConfig::default())
+      .unwrap()
+      .with_replication(100, true)
+      .unwrap();
+    let after = follower.replication().unwrap().last_sequence();
+    primary
+      .create_point(
+        "test",
+        NewPoint {
+          time: Utc.timestamp(1_560_000_000, 0),
+          value: Value::Float(1.0),
+        },
+        None,
+      )
+      .unwrap();
+    match apply_chunk(&follower, &read_log(&primary, after).unwrap()) {
+      Err(Error::Replication(_)) => (),
+      result => panic!("Followed a diverged log: {:?}", result),
+    }
+    assert_eq!(follower.query("test", None).unwrap().len(), 0);
+  }
+
+  #[test]
+  fn test_follower_drops_expired_points() {
+    let dir = TempDir::new("kakoidb-follower-retention").unwrap();
+    let primary = Database::in_memory().with_replication(100, false).unwrap();
+    let follower = Database::open(dir.path(), &RocksDbConfig::default())
+      .unwrap()
+      .with_replication(100, true)
+      .unwrap();
+    let series = primary
+      .create_series(new_series(Some(NewRetentionPolicy {
+        compact: None,
+        drop_after: crate::entities::duration::Duration::from_string("1 day"),
+      })))
+      .unwrap();
+    let old = Utc::now() - chrono::Duration::days(2);
+    primary
+      .create_point(
+        "test",
+        NewPoint {
+          time: old,
+          value: Value::Float(1.0),
+        },
+        None,
+      )
+      .unwrap();
+
+    apply_chunk(&follower, &read_log(&primary, 0).unwrap()).unwrap();
+    assert_eq!(follower.query("test", None).unwrap().len(), 1);
+    let prefix = codec::point_prefix(series.id);
+    follower
+      .backend()
+      .compact_range(POINTS_CF, &prefix, &codec::prefix_end(&prefix));
+    assert_eq!(follower.query("test", None).unwrap().len(), 0);
+  }
+
+  #[test]
+  fn test_follow_after_key_rotation() {
+    let dir = TempDir::new("kakoidb-rotated-log").unwrap();
+    let key_1 = "01".repeat(32);
+    let key_2 = "02".repeat(32);
+    let keys = |keys: &str| Keyring::parse(keys).unwrap();
+    {
+      let primary = Database::open_encrypted(dir.path(), &Default::default(), keys(&key_1))
+        .unwrap()
+        .with_replication(100, false)
+        .unwrap();
+      primary.create_series(new_series(None)).unwrap();
+      primary
+        .create_point(
+          "test",
+          NewPoint {
+            time: Utc.timestamp(1_560_000_000, 0),
+            value: Value::Float(1.0),
+          },
+          None,
+        )
+        .unwrap();
+    }
+    Database::rotate_key(
+      dir.path(),
+      &Default::default(),
+      keys(&format!("{} {}", key_2, key_1)),
+    )
+    .unwrap();
+
+    // The log is read with the new key only
+    let primary = Database::open_encrypted(dir.path(), &Default::default(), keys(&key_2))
+      .unwrap()
+      .with_replication(100, false)
+      .unwrap();
+    let follower = Database::in_memory().with_replication(100, true).unwrap();
+    apply_chunk(&follower, &read_log(&primary, 0).unwrap()).unwrap();
+    assert_eq!(follower.query("test", None).unwrap().len(), 1);
+  }
+
+  #[test]
+  fn test_follow_over_http() {
+    let primary = Database::in_memory().with_replication(100, false).unwrap();
+    primary.create_series(new_series(None)).unwrap();
+    primary
+      .create_point(
+        "test",
+        NewPoint {
+          time: Utc.timestamp(1_560_000_000, 0),
+          value: Value::Float(1.0),
+        },
+        None,
+      )
+      .unwrap();
+    let entries = read_log(&primary, 0).unwrap().entries.len();
+    let primary = Arc::new(RwLock::new(primary));
+    let unreplicated = Arc::new(RwLock::new(Database::in_memory()));
+    let follower = Arc::new(RwLock::new(
+      Database::in_memory().with_replication(100, true).unwrap(),
+    ));
+
+    let mut runtime = Runtime::new().unwrap();
+    let (primary_address, server) =
+      warp::serve(log_filter(primary.clone())).bind_ephemeral(([127, 0, 0, 1], 0));
+    runtime.spawn(server);
+    let (unreplicated_address, server) =
+      warp::serve(log_filter(unreplicated)).bind_ephemeral(([127, 0, 0, 1], 0));
+    runtime.spawn(server);
+    let client = Client::builder().build(StdConnector);
+
+    let primary_uri = format!("http://{}", primary_address);
+    assert_eq!(
+      runtime.block_on(poll(&client, &primary_uri, &follower)),
+      Ok(entries)
+    );
+    assert_eq!(
+      runtime.block_on(poll(&client, &primary_uri, &follower)),
+      Ok(0)
+    );
+    assert_eq!(
+      follower.read().unwrap().query("test", None).unwrap().len(),
+      1
+    );
+
+    let unreplicated_uri = format!("http://{}", unreplicated_address);
+    assert_eq!(
+      runtime.block_on(poll(&client, &unreplicated_uri, &follower)),
+      Err(Error::ReplicationDisabled.to_string())
+    );
+  }
+}
@@ -0,0 +1,301 @@
+//! Drops expired points while RocksDB compacts.
+//!
+//! The compaction filter of the points, rollups and blocks column families
+//! looks up the `drop_after` of the series a key belongs to and removes
+//! points older than that, and blocks that ended before that. Expired data
+//! is thereby removed as a side effect of compactions RocksDB runs anyway,
+//! without scanning or locking anything. The points and bytes the filter
+//! drops are tallied per series, for the janitor to take out of the
+//! statistics. Values of encrypted databases are unsealed to be counted,
+//! the statistics of a series are refreshed instead if one can not be. The
+//! tally is kept in memory only, so the statistics of every series with a
+//! `drop_after` are refreshed once after the database is opened.
+
+use crate::block::{self, BLOCK_SPAN};
+use crate::catalog::SeriesId;
+use crate::codec;
+use crate::entities::series::Series;
+use crate::storage::encrypted::Keyring;
+use chrono::Utc;
+use rocksdb::compaction_filter::Decision;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+
+pub const COMPACTION_FILTER_NAME: &str = "kakoi_retention";
+
+/// What compactions dropped of a series
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Dropped {
+  /// The number of points and bytes
+  Counted(u64, u64),
+  /// Some values could not be counted, the statistics have to be refreshed
+  Uncounted,
+}
+
+impl Default for Dropped {
+  fn default() -> Dropped {
+    Dropped::Counted(0, 0)
+  }
+}
+
+/// The `drop_after` of every series with one, in nanoseconds. Shared between
+/// the database and the compaction filters.
+#[derive(Clone, Default)]
+pub struct RetentionTable {
+  drop_after: Arc<RwLock<HashMap<SeriesId, i64>>>,
+  /// What was dropped since the last `take_dropped`
+  dropped: Arc<Mutex<HashMap<SeriesId, Dropped>>>,
+  /// The keys the values are sealed with, if the database is encrypted
+  keys: Option<Arc<Keyring>>,
+}
+
+impl RetentionTable {
+  /// A table for a database encrypted with `keys`
+  pub fn encrypted(keys: Arc<Keyring>) -> RetentionTable {
+    RetentionTable {
+      keys: Some(keys),
+      ..Default::default()
+    }
+  }
+
+  pub fn set(&self, series: &Series) {
+    let drop_after = series
+      .retention_policy
+      .as_ref()
+      .and_then(|policy| policy.drop_after.as_ref())
+      .and_then(|drop_after| chrono::Duration::from(drop_after).num_nanoseconds());
+
+    let mut table = self.drop_after.write().unwrap();
+    match drop_after {
+      Some(drop_after) => table.insert(series.id, drop_after),
+      None => table.remove(&series.id),
+    };
+  }
+
+  pub fn remove(&self, series_id: SeriesId) {
+    self.drop_after.write().unwrap().remove(&series_id);
+    self.dropped.lock().unwrap().remove(&series_id);
+  }
+
+  /// If the point or block under `key` has expired at `now`, in nanoseconds
+  pub fn is_expired(&self, key: &[u8], now: i64) -> bool {
+    let drop_after = match codec::decode_key_series_id(key)
+      .and_then(|series_id| self.drop_after.read().unwrap().get(&series_id).cloned())
+    {
+      Some(drop_after) => drop_after,
+      None => return false,
+    };
+    let drop_until = now.saturating_sub(drop_after);
+
+    if let Some(time) = codec::decode_point_time(key) {
+      codec::time_to_nanos(&time) < drop_until
+    } else if let Some(start) = codec::decode_block_start(key) {
+      codec::time_to_nanos(&start).saturating_add(BLOCK_SPAN) <= drop_until
+    } else {
+      false
+    }
+  }
+
+  /// Marks what was dropped from the series as not counted, so that its
+  /// statistics are refreshed. Series without `drop_after` are left alone.
+  pub fn mark_uncounted(&self, series_id: SeriesId) {
+    if self.drop_after.read().unwrap().contains_key(&series_id) {
+      self
+        .dropped
+        .lock()
+        .unwrap()
+        .insert(series_id, Dropped::Uncounted);
+    }
+  }
+
+  /// Returns what was dropped from the series since the last call
+  pub fn take_dropped(&self, series_id: SeriesId) -> Dropped {
+    self
+      .dropped
+      .lock()
+      .unwrap()
+      .remove(&series_id)
+      .unwrap_or_default()
+  }
+
+  fn add_dropped(&self, cf: &str, key: &[u8], value: &[u8]) {
+    let series_id = match codec::decode_key_series_id(key) {
+      Some(series_id) => series_id,
+      None => return,
+    };
+    let value = match &self.keys {
+      Some(keys) => keys.unseal(cf, key, value),
+      None => Some(value.to_vec()),
+    };
+    let points = match (&value, codec::decode_block_start(key)) {
+      (Some(value), Some(_)) => block::point_count(value).map(u64::from),
+      (Some(_), None) => Some(1),
+      (None, _) => None,
+    };
+
+    let mut dropped = self.dropped.lock().unwrap();
+    let dropped = dropped.entry(series_id).or_default();
+    *dropped = match (*dropped, value, points) {
+      (Dropped::Counted(count, bytes), Some(value), Some(points)) => {
+        Dropped::Counted(count + points, bytes + (key.len() + value.len()) as u64)
+      }
+      _ => Dropped::Uncounted,
+    };
+  }
+
+  /// The filter of the column family `cf`
+  pub fn compaction_filter(
+    &self,
+    cf: &'static str,
+  ) -> impl FnMut(u32, &[u8], &[u8]) -> Decision + Send + 'static {
+    let table = self.clone();
+    move |_level, key, value| {
+      if table.is_expired(key, codec::time_to_nanos(&Utc::now())) {
+        table.add_dropped(cf, key, value);
+        Decision::Remove
+      } else {
+        Decision::Keep
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::database::{Database, BLOCKS_CF, POINTS_CF};
+  use crate::entities::duration::Duration;
+  use crate::entities::point::NewPoint;
+  use crate::entities::series::{NewRetentionPolicy, NewSeries};
+  use crate::entities::value::Value;
+  use crate::storage::rocks::RocksDbBackend;
+  use crate::storage::StorageBackend;
+  use tempdir::TempDir;
+
+  #[test]
+  fn test_compaction_drops_expired_points() {
+    let tmp_dir = TempDir::new("test_compaction_drops_expired_points").unwrap();
+    let db_path = tmp_dir.path().join("db");
+    let now = Utc::now();
+    let old = now - chrono::Duration::days(2);
+
+    let series = {
+      let db = Database::open(&db_path, &Default::default()).unwrap();
+      let series = db
+        .create_series(NewSeries {
+          name: "cpu".to_string(),
+          retention_policy: Some(NewRetentionPolicy {
+            compact: None,
+            drop_after: Duration::from_string("1 day"),
+          }),
+          ..Default::default()
+        })
+        .unwrap();
+      db.create_point(
+        "cpu",
+        NewPoint {
+          time: old,
+          value: Value::Float(1.0),
+        },
+        None,
+      )
+      .unwrap();
+      db.create_point(
+        "cpu",
+        NewPoint {
+          time: now,
+          value: Value::Float(2.0),
+        },
+        None,
+      )
+      .unwrap();
+      series
+    };
+
+    let retention = RetentionTable::default();
+    retention.set(&series);
+    let backend = RocksDbBackend::open(&db_path, &Default::default(), &retention).unwrap();
+    backend.compact_range(
+      POINTS_CF,
+      &codec::point_prefix(series.id),
+      &codec::prefix_end(&codec::point_prefix(series.id)),
+    );
+
+    assert_eq!(
+      backend.get(POINTS_CF, &codec::point_key(series.id, &old)),
+      Ok(None)
+    );
+    assert!(backend
+      .get(POINTS_CF, &codec::point_key(series.id, &now))
+      .unwrap()
+      .is_some());
+    match retention.take_dropped(series.id) {
+      Dropped::Counted(points, bytes) => {
+        assert_eq!(points, 1);
+        assert!(bytes > 0);
+      }
+      dropped => panic!("Dropped {:?}", dropped),
+    }
+    assert_eq!(retention.take_dropped(series.id), Dropped::Counted(0, 0));
+
+    // What was dropped is not counted anymore once the database is opened again
+    drop(backend);
+    let db = Database::open(&db_path, &Default::default()).unwrap();
+    db.drop_shards(&series, now - chrono::Duration::days(30))
+      .unwrap();
+    let stats = db.get_series("cpu").unwrap().unwrap().stats;
+    assert_eq!(stats.count, 1);
+    assert_eq!(stats.first, Some(now));
+  }
+
+  #[test]
+  fn test_compaction_counts_encrypted_blocks() {
+    let tmp_dir = TempDir::new("test_compaction_counts_encrypted_blocks").unwrap();
+    let keys = Keyring::parse(&"01".repeat(32)).unwrap();
+    let mut db = Database::open_encrypted(tmp_dir.path(), &Default::default(), keys).unwrap();
+    let now = Utc::now();
+    let old = block::block_start(&(now - chrono::Duration::days(3)));
+
+    let series = db
+      .create_series(NewSeries {
+        name: "cpu".to_string(),
+        retention_policy: Some(NewRetentionPolicy {
+          compact: None,
+          drop_after: Duration::from_string("1 day"),
+        }),
+        ..Default::default()
+      })
+      .unwrap();
+    let mut points = (0..12)
+      .map(|i| NewPoint {
+        time: old + chrono::Duration::minutes(i * 10),
+        value: Value::Float(i as f64),
+      })
+      .collect::<Vec<_>>();
+    points.push(NewPoint {
+      time: now,
+      value: Value::Float(1.0),
+    });
+    db.create_points("cpu", points, None).unwrap();
+    assert_eq!(
+      db.seal_blocks(&series, now - chrono::Duration::days(2)),
+      Ok(12)
+    );
+
+    let prefix = codec::block_prefix(series.id);
+    db.backend()
+      .compact_range(BLOCKS_CF, &prefix, &codec::prefix_end(&prefix));
+    db.drop_shards(&series, old - chrono::Duration::days(30))
+      .unwrap();
+
+    // The smallest and largest values are left as they are
+    let stats = db.get_series("cpu").unwrap().unwrap().stats;
+    assert_eq!(stats.count, 1);
+    assert_eq!(stats.first, Some(now));
+    db.refresh_stats(&series).unwrap();
+    assert_eq!(
+      db.get_series("cpu").unwrap().unwrap().stats.bytes,
+      stats.bytes
+    );
+  }
+}
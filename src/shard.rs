@@ -0,0 +1,67 @@
+//! Time partitioning of the points of a series.
+//!
+//! Every series splits time into shards of its `shard_duration`, aligned to
+//! the unix epoch. A shard covers a contiguous range of point and block keys,
+//! so it is dropped with a handful of range deletes no matter how many points
+//! it holds. The shards a series has data in are recorded under shard keys,
+//! which lets retention find them without scanning any points.
+
+use crate::block::BLOCK_SPAN;
+use crate::catalog::SeriesId;
+use crate::codec;
+use crate::database::{BLOCKS_CF, POINTS_CF, ROLLUPS_CF, SERIES_CF};
+use crate::entities::duration::{Duration, TimeUnit};
+use crate::storage::Batch;
+use chrono::{DateTime, Utc};
+
+pub fn default_shard_duration() -> Duration {
+  Duration {
+    time_unit: TimeUnit::Days,
+    value: 1,
+  }
+}
+
+/// The length of a shard in nanoseconds
+pub fn shard_span(shard_duration: &Duration) -> i64 {
+  chrono::Duration::from(shard_duration)
+    .num_nanoseconds()
+    .unwrap_or(i64::MAX)
+}
+
+/// Shards must hold whole blocks, or dropping one would cut a block in two
+pub fn is_valid_shard_duration(shard_duration: &Duration) -> bool {
+  let span = shard_span(shard_duration);
+  span > 0 && span % BLOCK_SPAN == 0
+}
+
+pub fn shard_start(time: &DateTime<Utc>, shard_duration: &Duration) -> DateTime<Utc> {
+  codec::truncate_time(time, shard_span(shard_duration))
+}
+
+pub fn shard_end(start: &DateTime<Utc>, shard_duration: &Duration) -> DateTime<Utc> {
+  *start + chrono::Duration::nanoseconds(shard_span(shard_duration))
+}
+
+/// Adds the deletes of every key of the shard starting at `start` to `batch`
+pub fn drop_shard(
+  batch: &mut Batch,
+  series_id: SeriesId,
+  start: &DateTime<Utc>,
+  shard_duration: &Duration,
+) {
+  let end = shard_end(start, shard_duration);
+
+  for cf in &[POINTS_CF, ROLLUPS_CF] {
+    batch.delete_range(
+      cf,
+      codec::point_key(series_id, start),
+      codec::point_key(series_id, &end),
+    );
+  }
+  batch.delete_range(
+    BLOCKS_CF,
+    codec::block_key(series_id, start),
+    codec::block_key(series_id, &end),
+  );
+  batch.delete(SERIES_CF, codec::shard_key(series_id, start));
+}
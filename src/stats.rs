@@ -0,0 +1,100 @@
+//! Keeps the statistics of every series up to date.
+//!
+//! Writes add their points to the statistics of the series in the same batch
+//! as the points, and deletes, compactions and sealing take the points they
+//! remove out of them. Only the points outside of a deleted range are read,
+//! and only when the first or last point, or the smallest or largest value,
+//! was deleted. The statistics are kept in memory as well, and changed under
+//! a lock, so that concurrent writes can not lose each other's updates.
+//! Points dropped by the compaction filter are counted until the janitor
+//! next drops the shards of the series.
+
+use crate::catalog::SeriesId;
+use crate::codec;
+use crate::database::{Error, SERIES_CF};
+use crate::entities::series::SeriesStats;
+use crate::storage::{iter_prefix, Batch, StorageBackend};
+use bincode::{deserialize, serialize};
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+
+#[derive(Default)]
+pub struct StatsTable {
+  stats: Mutex<HashMap<SeriesId, SeriesStats>>,
+}
+
+impl StatsTable {
+  pub fn load(db: &dyn StorageBackend) -> Result<StatsTable, Error> {
+    let mut stats = HashMap::new();
+    for item in iter_prefix(db, SERIES_CF, codec::stats_prefix()) {
+      let (key, value) = item?;
+      if let (Some(series_id), Ok(series_stats)) =
+        (codec::decode_stats_series_id(&key), deserialize(&value))
+      {
+        stats.insert(series_id, series_stats);
+      }
+    }
+
+    Ok(StatsTable {
+      stats: Mutex::new(stats),
+    })
+  }
+
+  /// The statistics `db` stores for the series, for databases that do not
+  /// load them
+  pub fn read(db: &dyn StorageBackend, series_id: SeriesId) -> Option<SeriesStats> {
+    let value = db.get(SERIES_CF, &codec::stats_key(series_id)).ok()??;
+    deserialize(&value).ok()
+  }
+
+  pub fn get(&self, series_id: SeriesId) -> Option<SeriesStats> {
+    self.stats.lock().unwrap().get(&series_id).cloned()
+  }
+
+  /// Locks the statistics until the returned guard writes its changes or is
+  /// dropped
+  pub fn lock(&self) -> StatsGuard<'_> {
+    StatsGuard {
+      stats: self.stats.lock().unwrap(),
+      pending: vec![],
+    }
+  }
+}
+
+pub struct StatsGuard<'a> {
+  stats: MutexGuard<'a, HashMap<SeriesId, SeriesStats>>,
+  /// Changes applied in memory once their batch is written
+  pending: Vec<(SeriesId, Option<SeriesStats>)>,
+}
+
+impl<'a> StatsGuard<'a> {
+  pub fn get(&self, series_id: SeriesId) -> SeriesStats {
+    self.stats.get(&series_id).cloned().unwrap_or_default()
+  }
+
+  pub fn set(&mut self, batch: &mut Batch, series_id: SeriesId, stats: SeriesStats) {
+    batch.put(
+      SERIES_CF,
+      codec::stats_key(series_id),
+      serialize(&stats).unwrap(),
+    );
+    self.pending.push((series_id, Some(stats)));
+  }
+
+  pub fn remove(&mut self, batch: &mut Batch, series_id: SeriesId) {
+    batch.delete(SERIES_CF, codec::stats_key(series_id));
+    self.pending.push((series_id, None));
+  }
+
+  /// Writes `batch` and applies the changes to the statistics in it
+  pub fn write(mut self, db: &dyn StorageBackend, batch: Batch) -> Result<(), Error> {
+    db.write(batch)?;
+    for (series_id, stats) in self.pending.drain(..) {
+      match stats {
+        Some(stats) => self.stats.insert(series_id, stats),
+        None => self.stats.remove(&series_id),
+      };
+    }
+    Ok(())
+  }
+}
@@ -0,0 +1,510 @@
+//! Encrypts stored values with keys kept outside of the database.
+//!
+//! Every value is sealed with XChaCha20-Poly1305 under a random nonce, with
+//! its column family and key as associated data, so a value can neither be
+//! read nor moved to another key without the encryption key. Keys stay in
+//! plain text, as they are needed to keep points in order. Most only hold
+//! ids and times, but series name keys hold the names of the series, and
+//! tag index keys the names and values of their tags.
+//!
+//! A sealed value starts with the fingerprint of the key it was sealed with,
+//! so a keyring of the current key and older keys reads values sealed under
+//! any of them. Rotating seals every value with the current key again, after
+//! which the older keys can be dropped. A check value, sealed with the key of
+//! the last rotation, tells a wrong key apart from damaged data when the
+//! database is opened.
+
+use super::rocks::BackupEngine;
+use super::{Batch, KeyValue, Operation, StorageBackend};
+use crate::codec;
+use crate::database::{
+  Error, BLOCKS_CF, DEFAULT_CF, POINTS_CF, QUARANTINE_CF, REPLICATION_CF, ROLLUPS_CF, SERIES_CF,
+};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use std::env;
+use std::fs;
+use std::sync::Arc;
+
+/// The environment variable keys can be given in, instead of a key file
+pub const KEY_VAR: &str = "KAKOI_ENCRYPTION_KEY";
+
+const FORMAT: u8 = 1;
+const FINGERPRINT_LEN: usize = 4;
+const NONCE_LEN: usize = 24;
+const HEADER_LEN: usize = 1 + FINGERPRINT_LEN + NONCE_LEN;
+
+const CHECK: &str = "encryption_check";
+const CHECK_VALUE: &[u8] = b"kakoidb";
+
+const COLUMN_FAMILIES: &[&str] = &[
+  DEFAULT_CF,
+  SERIES_CF,
+  POINTS_CF,
+  ROLLUPS_CF,
+  BLOCKS_CF,
+  QUARANTINE_CF,
+  REPLICATION_CF,
+];
+
+/// Values sealed again per write batch, and between progress logs
+const BATCH_SIZE: usize = 10_000;
+
+type Fingerprint = [u8; FINGERPRINT_LEN];
+
+struct Key {
+  cipher: XChaCha20Poly1305,
+  fingerprint: Fingerprint,
+}
+
+impl Key {
+  fn new(bytes: &[u8]) -> Key {
+    let cipher = XChaCha20Poly1305::new_from_slice(bytes).unwrap();
+    // The tag of an empty message identifies the key without revealing it.
+    // The zero nonce is never used for anything else.
+    let tag = cipher.encrypt(&XNonce::default(), b"".as_ref()).unwrap();
+    let mut fingerprint = Fingerprint::default();
+    fingerprint.copy_from_slice(&tag[..FINGERPRINT_LEN]);
+    Key {
+      cipher,
+      fingerprint,
+    }
+  }
+}
+
+/// The keys values are sealed with, the current key first
+pub struct Keyring {
+  keys: Vec<Key>,
+}
+
+impl Keyring {
+  /// Parses 256 bit keys, written as 64 hex digits and separated by
+  /// whitespace. Lines starting with `#` are ignored.
+  pub fn parse(text: &str) -> Result<Keyring, String> {
+    let keys = text
+      .lines()
+      .filter(|line| !line.trim_start().starts_with('#'))
+      .flat_map(str::split_whitespace)
+      .map(|key| match decode_hex(key) {
+        Some(bytes) if bytes.len() == 32 => Ok(Key::new(&bytes)),
+        _ => Err("Encryption keys must be 64 hex digits".to_string()),
+      })
+      .collect::<Result<Vec<_>, _>>()?;
+
+    if keys.is_empty() {
+      return Err("No encryption key given".to_string());
+    }
+    Ok(Keyring { keys })
+  }
+
+  /// Reads the keys from `KAKOI_ENCRYPTION_KEY` or from `key_file`. Returns
+  /// none if neither is given, as the database is not encrypted then.
+  pub fn load(key_file: Option<&str>) -> Result<Option<Keyring>, String> {
+    match (env::var(KEY_VAR).ok(), key_file) {
+      (Some(_), Some(_)) => Err(format!(
+        "Encryption keys are given both in {} and in a key file",
+        KEY_VAR
+      )),
+      (Some(keys), None) => Keyring::parse(&keys).map(Some),
+      (None, Some(path)) => {
+        let keys = fs::read_to_string(path)
+          .map_err(|err| format!("Could not read key file {}: {}", path, err))?;
+        Keyring::parse(&keys).map(Some)
+      }
+      (None, None) => Ok(None),
+    }
+  }
+
+  fn current(&self) -> &Key {
+    &self.keys[0]
+  }
+
+  fn seal(&self, cf: &str, key: &[u8], value: &[u8]) -> Vec<u8> {
+    let current = self.current();
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let aad = associated_data(cf, key);
+    let ciphertext = current
+      .cipher
+      .encrypt(
+        &nonce,
+        Payload {
+          msg: value,
+          aad: &aad,
+        },
+      )
+      .unwrap();
+
+    let mut sealed = Vec::with_capacity(HEADER_LEN + ciphertext.len());
+    sealed.push(FORMAT);
+    sealed.extend_from_slice(&current.fingerprint);
+    sealed.extend_from_slice(&nonce);
+    sealed.extend_from_slice(&ciphertext);
+    sealed
+  }
+
+  /// Returns none if the value was not sealed by any of the keys, or was
+  /// changed since
+  pub fn unseal(&self, cf: &str, key: &[u8], value: &[u8]) -> Option<Vec<u8>> {
+    let fingerprint = fingerprint(value)?;
+    let sealed_with = self.keys.iter().find(|k| k.fingerprint == fingerprint)?;
+    let aad = associated_data(cf, key);
+    sealed_with
+      .cipher
+      .decrypt(
+        XNonce::from_slice(&value[1 + FINGERPRINT_LEN..HEADER_LEN]),
+        Payload {
+          msg: &value[HEADER_LEN..],
+          aad: &aad,
+        },
+      )
+      .ok()
+  }
+}
+
+fn fingerprint(value: &[u8]) -> Option<Fingerprint> {
+  if value.len() < HEADER_LEN || value[0] != FORMAT {
+    return None;
+  }
+  let mut fingerprint = Fingerprint::default();
+  fingerprint.copy_from_slice(&value[1..=FINGERPRINT_LEN]);
+  Some(fingerprint)
+}
+
+fn associated_data(cf: &str, key: &[u8]) -> Vec<u8> {
+  let mut aad = cf.as_bytes().to_vec();
+  aad.push(0);
+  aad.extend_from_slice(key);
+  aad
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+  if !hex.len().is_multiple_of(2) {
+    return None;
+  }
+  (0..hex.len())
+    .step_by(2)
+    .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+    .collect()
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+  bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn is_empty(db: &dyn StorageBackend) -> Result<bool, Error> {
+  for cf in COLUMN_FAMILIES {
+    if let Some(item) = db.iter_from(cf, &[]).next() {
+      item?;
+      return Ok(false);
+    }
+  }
+  Ok(true)
+}
+
+pub struct EncryptedBackend {
+  inner: Box<dyn StorageBackend>,
+  keys: Arc<Keyring>,
+}
+
+impl EncryptedBackend {
+  /// Encrypts the values of `inner`, which must be empty or encrypted with
+  /// one of `keys`
+  pub fn new(
+    inner: Box<dyn StorageBackend>,
+    keys: Arc<Keyring>,
+  ) -> Result<EncryptedBackend, Error> {
+    let backend = EncryptedBackend { inner, keys };
+    let check_key = codec::catalog_key(CHECK);
+    match backend.inner.get(SERIES_CF, &check_key)? {
+      Some(check) => backend.verify(&check)?,
+      None if !is_empty(backend.inner.as_ref())? => return Err(Error::NotEncrypted),
+      // Nothing to check a read-only database against until it is written
+      None if backend.inner.is_read_only() => (),
+      None => backend.put(SERIES_CF, &check_key, CHECK_VALUE)?,
+    }
+    Ok(backend)
+  }
+
+  fn verify(&self, check: &[u8]) -> Result<(), Error> {
+    if self
+      .keys
+      .unseal(SERIES_CF, &codec::catalog_key(CHECK), check)
+      .is_none()
+    {
+      let fingerprint = fingerprint(check).unwrap_or_default();
+      return Err(Error::WrongEncryptionKey(encode_hex(&fingerprint)));
+    }
+    if fingerprint(check) != Some(self.keys.current().fingerprint) {
+      warn!("Some values are encrypted with an older key, rotate the key to drop it");
+    }
+    Ok(())
+  }
+
+  /// Seals every value of `inner` with the current key. Values that are not
+  /// sealed by any of the keys are sealed as they are if the database is not
+  /// encrypted yet, which encrypts it. Returns the backend along with the
+  /// number of values that were sealed.
+  pub fn rotate(
+    inner: Box<dyn StorageBackend>,
+    keys: Arc<Keyring>,
+  ) -> Result<(EncryptedBackend, usize), Error> {
+    let backend = EncryptedBackend { inner, keys };
+    let check_key = codec::catalog_key(CHECK);
+    let check = backend.inner.get(SERIES_CF, &check_key)?;
+    if let Some(check) = &check {
+      backend.verify(check)?;
+    }
+    let current = backend.keys.current().fingerprint;
+
+    let mut sealed = 0;
+    let mut batch = Batch::default();
+    for &cf in COLUMN_FAMILIES {
+      for item in backend.inner.iter_from(cf, &[]) {
+        let (key, value) = item?;
+        if cf == SERIES_CF && *key == *check_key {
+          continue;
+        }
+        let value = match backend.keys.unseal(cf, &key, &value) {
+          Some(_) if fingerprint(&value) == Some(current) => continue,
+          Some(value) => value,
+          None if check.is_none() => value.into_vec(),
+          None => {
+            warn!("Could not decrypt key {} in {}", encode_hex(&key), cf);
+            continue;
+          }
+        };
+        batch.put(cf, key, value);
+        sealed += 1;
+
+        if sealed % BATCH_SIZE == 0 {
+          backend.write(batch)?;
+          batch = Batch::default();
+          info!("Encrypted {} values", sealed);
+        }
+      }
+    }
+    // The check is sealed last, so an interrupted rotation is resumed with
+    // the older keys still required
+    batch.put(SERIES_CF, &check_key, CHECK_VALUE);
+    backend.write(batch)?;
+    info!("Encrypted {} values", sealed);
+
+    Ok((backend, sealed))
+  }
+
+  /// Fails if `db` is encrypted, as it can not be read without its keys
+  pub fn refuse_encrypted(db: &dyn StorageBackend) -> Result<(), Error> {
+    match db.get(SERIES_CF, &codec::catalog_key(CHECK)) {
+      Ok(Some(_)) => Err(Error::EncryptionKeyMissing),
+      Ok(None) => Ok(()),
+      Err(err) => Err(err),
+    }
+  }
+
+  /// Values that can not be unsealed are returned empty, so that they fail
+  /// to parse like any other damaged value
+  fn unseal_or_empty(&self, cf: &str, key: &[u8], value: &[u8]) -> Vec<u8> {
+    self.keys.unseal(cf, key, value).unwrap_or_else(|| {
+      warn!("Could not decrypt key {} in {}", encode_hex(key), cf);
+      vec![]
+    })
+  }
+}
+
+impl StorageBackend for EncryptedBackend {
+  fn get(&self, cf: &str, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+    Ok(
+      self
+        .inner
+        .get(cf, key)?
+        .map(|value| self.unseal_or_empty(cf, key, &value)),
+    )
+  }
+
+  fn put(&self, cf: &str, key: &[u8], value: &[u8]) -> Result<(), Error> {
+    self.inner.put(cf, key, &self.keys.seal(cf, key, value))
+  }
+
+  fn delete(&self, cf: &str, key: &[u8]) -> Result<(), Error> {
+    self.inner.delete(cf, key)
+  }
+
+  fn iter_from<'a>(
+    &'a self,
+    cf: &str,
+    start: &[u8],
+  ) -> Box<dyn Iterator<Item = Result<KeyValue, Error>> + 'a> {
+    let cf = cf.to_string();
+    Box::new(self.inner.iter_from(&cf, start).map(move |item| {
+      let (key, value) = item?;
+      let value = self.unseal_or_empty(&cf, &key, &value);
+      Ok((key, value.into_boxed_slice()))
+    }))
+  }
+
+  fn write(&self, batch: Batch) -> Result<(), Error> {
+    let mut sealed = Batch::with_durability(batch.durability());
+    for operation in batch.into_operations() {
+      match operation {
+        Operation::Put { cf, key, value } => {
+          let value = self.keys.seal(cf, &key, &value);
+          sealed.put(cf, key, value)
+        }
+        Operation::Delete { cf, key } => sealed.delete(cf, key),
+        Operation::DeleteRange { cf, from, to } => sealed.delete_range(cf, from, to),
+      }
+    }
+    self.inner.write(sealed)
+  }
+
+  fn compact_range(&self, cf: &str, from: &[u8], to: &[u8]) {
+    self.inner.compact_range(cf, from, to)
+  }
+
+  fn backup(&self, engine: &mut BackupEngine) -> Result<(), Error> {
+    self.inner.backup(engine)
+  }
+
+  fn get_raw(&self, cf: &str, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+    self.inner.get_raw(cf, key)
+  }
+
+  fn is_read_only(&self) -> bool {
+    self.inner.is_read_only()
+  }
+
+  fn snapshot(&self) -> Box<dyn StorageBackend> {
+    Box::new(EncryptedBackend {
+      inner: self.inner.snapshot(),
+      keys: self.keys.clone(),
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::storage::iter_prefix;
+  use crate::storage::memory::MemoryBackend;
+
+  const KEY_1: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+  const KEY_2: &str = "f0e0d0c0b0a090807060504030201000f0e0d0c0b0a090807060504030201000";
+
+  fn keyring(keys: &str) -> Arc<Keyring> {
+    Arc::new(Keyring::parse(keys).unwrap())
+  }
+
+  fn raw(backend: &EncryptedBackend, cf: &str, key: &[u8]) -> Vec<u8> {
+    backend.inner.get(cf, key).unwrap().unwrap()
+  }
+
+  #[test]
+  fn test_parse_keys() {
+    assert_eq!(
+      Keyring::parse(&format!("# current\n{}\n\n{} ", KEY_1, KEY_2))
+        .unwrap()
+        .keys
+        .len(),
+      2
+    );
+    assert!(Keyring::parse("").is_err());
+    assert!(Keyring::parse("0011").is_err());
+    assert!(Keyring::parse(&KEY_1.replace("00", "zz")).is_err());
+  }
+
+  #[test]
+  fn test_seal_values() {
+    let backend = EncryptedBackend::new(Box::new(MemoryBackend::new()), keyring(KEY_1)).unwrap();
+    backend.put(POINTS_CF, b"a", b"secret").unwrap();
+    let mut batch = Batch::default();
+    batch.put(POINTS_CF, b"b", b"other secret");
+    backend.write(batch).unwrap();
+
+    assert_eq!(
+      backend.get(POINTS_CF, b"a").unwrap(),
+      Some(b"secret".to_vec())
+    );
+    assert_eq!(
+      iter_prefix(&backend, POINTS_CF, vec![])
+        .map(|item| item.unwrap().1.into_vec())
+        .collect::<Vec<_>>(),
+      vec![b"secret".to_vec(), b"other secret".to_vec()]
+    );
+    let sealed = raw(&backend, POINTS_CF, b"a");
+    assert!(!sealed
+      .windows(b"secret".len())
+      .any(|window| window == b"secret"));
+
+    // A value moved to another key can not be read
+    backend.inner.put(POINTS_CF, b"c", &sealed).unwrap();
+    assert_eq!(backend.get(POINTS_CF, b"c").unwrap(), Some(vec![]));
+  }
+
+  #[test]
+  fn test_wrong_key() {
+    let backend = EncryptedBackend::new(Box::new(MemoryBackend::new()), keyring(KEY_1)).unwrap();
+    let inner = backend.inner;
+
+    assert_eq!(
+      EncryptedBackend::refuse_encrypted(inner.as_ref()).err(),
+      Some(Error::EncryptionKeyMissing)
+    );
+    match EncryptedBackend::new(inner, keyring(KEY_2)) {
+      Err(Error::WrongEncryptionKey(_)) => (),
+      _ => panic!("Opened with the wrong key"),
+    }
+  }
+
+  #[test]
+  fn test_rotate() {
+    let inner = MemoryBackend::new();
+    inner.put(POINTS_CF, b"a", b"plain").unwrap();
+    match EncryptedBackend::new(Box::new(inner), keyring(KEY_1)) {
+      Err(Error::NotEncrypted) => (),
+      _ => panic!("Opened a database that is not encrypted"),
+    }
+
+    // Encrypts the database
+    let inner = MemoryBackend::new();
+    inner.put(POINTS_CF, b"a", b"plain").unwrap();
+    let (backend, sealed) = EncryptedBackend::rotate(Box::new(inner), keyring(KEY_1)).unwrap();
+    assert_eq!(sealed, 1);
+    assert_ne!(raw(&backend, POINTS_CF, b"a"), b"plain".to_vec());
+
+    let backend = EncryptedBackend::new(backend.inner, keyring(KEY_1)).unwrap();
+    assert_eq!(
+      backend.get(POINTS_CF, b"a").unwrap(),
+      Some(b"plain".to_vec())
+    );
+    let (_, sealed) = EncryptedBackend::rotate(backend.inner, keyring(KEY_1)).unwrap();
+    assert_eq!(sealed, 0);
+  }
+
+  #[test]
+  fn test_rotate_key() {
+    let old = EncryptedBackend::new(Box::new(MemoryBackend::new()), keyring(KEY_1)).unwrap();
+    old.put(POINTS_CF, b"a", b"secret").unwrap();
+
+    // Both keys are needed until the rotation is done
+    let both = format!("{} {}", KEY_2, KEY_1);
+    let backend = EncryptedBackend::new(old.inner, keyring(&both)).unwrap();
+    backend.put(POINTS_CF, b"b", b"new secret").unwrap();
+    assert_eq!(
+      backend.get(POINTS_CF, b"a").unwrap(),
+      Some(b"secret".to_vec())
+    );
+    let (backend, sealed) = EncryptedBackend::rotate(backend.inner, keyring(&both)).unwrap();
+    assert_eq!(sealed, 1);
+
+    // The old key can be dropped once the rotation is done
+    let backend = EncryptedBackend::new(backend.inner, keyring(KEY_2)).unwrap();
+    assert_eq!(
+      backend.get(POINTS_CF, b"a").unwrap(),
+      Some(b"secret".to_vec())
+    );
+    assert_eq!(
+      backend.get(POINTS_CF, b"b").unwrap(),
+      Some(b"new secret".to_vec())
+    );
+  }
+}
@@ -0,0 +1,184 @@
+use super::{Batch, KeyValue, Operation, StorageBackend};
+use crate::database::Error;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Bound;
+use std::sync::{Arc, RwLock};
+
+type ColumnFamily = BTreeMap<Vec<u8>, Vec<u8>>;
+
+/// Column families are shared with the snapshots taken of them, and only
+/// copied when they are next written
+type ColumnFamilies = HashMap<String, Arc<ColumnFamily>>;
+
+/// Keeps everything in memory, for tests and embedding. Nothing is persisted.
+#[derive(Default)]
+pub struct MemoryBackend {
+  column_families: RwLock<ColumnFamilies>,
+}
+
+fn column_family_mut<'a>(
+  column_families: &'a mut ColumnFamilies,
+  cf: &str,
+) -> &'a mut ColumnFamily {
+  Arc::make_mut(column_families.entry(cf.to_string()).or_default())
+}
+
+impl MemoryBackend {
+  pub fn new() -> MemoryBackend {
+    Default::default()
+  }
+}
+
+impl StorageBackend for MemoryBackend {
+  fn get(&self, cf: &str, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+    let column_families = self.column_families.read().unwrap();
+    Ok(
+      column_families
+        .get(cf)
+        .and_then(|column_family| column_family.get(key))
+        .cloned(),
+    )
+  }
+
+  fn put(&self, cf: &str, key: &[u8], value: &[u8]) -> Result<(), Error> {
+    let mut column_families = self.column_families.write().unwrap();
+    column_family_mut(&mut column_families, cf).insert(key.to_vec(), value.to_vec());
+    Ok(())
+  }
+
+  fn delete(&self, cf: &str, key: &[u8]) -> Result<(), Error> {
+    let mut column_families = self.column_families.write().unwrap();
+    if let Some(column_family) = column_families.get_mut(cf) {
+      Arc::make_mut(column_family).remove(key);
+    }
+    Ok(())
+  }
+
+  fn iter_from<'a>(
+    &'a self,
+    cf: &str,
+    start: &[u8],
+  ) -> Box<dyn Iterator<Item = Result<KeyValue, Error>> + 'a> {
+    Box::new(MemoryIterator {
+      backend: self,
+      cf: cf.to_string(),
+      next: Bound::Included(start.to_vec()),
+    })
+  }
+
+  fn write(&self, batch: Batch) -> Result<(), Error> {
+    let mut column_families = self.column_families.write().unwrap();
+    for operation in batch.into_operations() {
+      match operation {
+        Operation::Put { cf, key, value } => {
+          column_family_mut(&mut column_families, cf).insert(key, value);
+        }
+        Operation::Delete { cf, key } => {
+          if let Some(column_family) = column_families.get_mut(cf) {
+            Arc::make_mut(column_family).remove(&key);
+          }
+        }
+        Operation::DeleteRange { cf, from, to } => {
+          if let Some(column_family) = column_families.get_mut(cf) {
+            let column_family = Arc::make_mut(column_family);
+            let mut removed = column_family.split_off(&from);
+            let mut kept = removed.split_off(&to);
+            column_family.append(&mut kept);
+          }
+        }
+      }
+    }
+    Ok(())
+  }
+
+  /// Shares the column families, the first write to one after a snapshot
+  /// copies it
+  fn snapshot(&self) -> Box<dyn StorageBackend> {
+    let column_families = self.column_families.read().unwrap().clone();
+    Box::new(MemorySnapshot {
+      column_families: Arc::new(column_families),
+    })
+  }
+}
+
+#[derive(Clone)]
+struct MemorySnapshot {
+  column_families: Arc<ColumnFamilies>,
+}
+
+impl StorageBackend for MemorySnapshot {
+  fn get(&self, cf: &str, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+    Ok(
+      self
+        .column_families
+        .get(cf)
+        .and_then(|column_family| column_family.get(key))
+        .cloned(),
+    )
+  }
+
+  fn put(&self, _cf: &str, _key: &[u8], _value: &[u8]) -> Result<(), Error> {
+    Err(Error::ReadOnly("be written"))
+  }
+
+  fn delete(&self, _cf: &str, _key: &[u8]) -> Result<(), Error> {
+    Err(Error::ReadOnly("be written"))
+  }
+
+  fn iter_from<'a>(
+    &'a self,
+    cf: &str,
+    start: &[u8],
+  ) -> Box<dyn Iterator<Item = Result<KeyValue, Error>> + 'a> {
+    match self.column_families.get(cf) {
+      Some(column_family) => Box::new(
+        column_family
+          .range::<[u8], _>((Bound::Included(start), Bound::Unbounded))
+          .map(|(key, value)| {
+            Ok((
+              key.clone().into_boxed_slice(),
+              value.clone().into_boxed_slice(),
+            ))
+          }),
+      ),
+      None => Box::new(std::iter::empty()),
+    }
+  }
+
+  fn write(&self, _batch: Batch) -> Result<(), Error> {
+    Err(Error::ReadOnly("be written"))
+  }
+
+  fn is_read_only(&self) -> bool {
+    true
+  }
+
+  fn snapshot(&self) -> Box<dyn StorageBackend> {
+    Box::new(self.clone())
+  }
+}
+
+/// Looks up one key at a time, so that the lock is not held while iterating
+struct MemoryIterator<'a> {
+  backend: &'a MemoryBackend,
+  cf: String,
+  next: Bound<Vec<u8>>,
+}
+
+impl<'a> Iterator for MemoryIterator<'a> {
+  type Item = Result<KeyValue, Error>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    let column_families = self.backend.column_families.read().unwrap();
+    let (key, value) = column_families
+      .get(&self.cf)?
+      .range((self.next.clone(), Bound::Unbounded))
+      .next()?;
+
+    self.next = Bound::Excluded(key.clone());
+    Some(Ok((
+      key.clone().into_boxed_slice(),
+      value.clone().into_boxed_slice(),
+    )))
+  }
+}
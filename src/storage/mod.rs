@@ -0,0 +1,221 @@
+//! The key value stores kakoidb can keep its data in.
+//!
+//! `Database` only talks to its store through `StorageBackend`. Keys are
+//! grouped into column families, named by the constants in `database`, and
+//! are iterated in bytewise order.
+
+pub mod encrypted;
+pub mod memory;
+pub mod read_only;
+pub mod replicated;
+pub mod rocks;
+
+use crate::database::{self, Error};
+use crate::storage_options::deserialize_from_str;
+use rocks::BackupEngine;
+use serde::de::{self, Deserialize, Deserializer};
+use std::str::FromStr;
+
+#[derive(Serialize, PartialEq, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendKind {
+  Rocksdb,
+  Memory,
+}
+
+impl FromStr for BackendKind {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "rocksdb" => Ok(BackendKind::Rocksdb),
+      "memory" => Ok(BackendKind::Memory),
+      _ => Err(format!("Unknown backend \"{}\"", s)),
+    }
+  }
+}
+
+impl<'de> Deserialize<'de> for BackendKind {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    deserialize_from_str(deserializer)
+  }
+}
+
+/// How far a write has to make it towards disk before it is acknowledged
+#[derive(Serialize, PartialEq, Debug, Clone, Copy, Default, GraphQLEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Durability {
+  /// The write ahead log is fsync'd before every write returns
+  Sync,
+  /// The write ahead log is written but left to the OS to flush, so a
+  /// machine crash may lose the latest writes
+  #[default]
+  Async,
+  /// The write ahead log is skipped, so a crash loses everything that was
+  /// not flushed yet. For bulk loads that can be repeated.
+  Disabled,
+}
+
+impl FromStr for Durability {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "sync" => Ok(Durability::Sync),
+      "async" => Ok(Durability::Async),
+      "disabled" => Ok(Durability::Disabled),
+      _ => Err(format!("Unknown durability \"{}\"", s)),
+    }
+  }
+}
+
+impl<'de> Deserialize<'de> for Durability {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    deserialize_from_str(deserializer)
+  }
+}
+
+pub type KeyValue = (Box<[u8]>, Box<[u8]>);
+
+pub trait StorageBackend: Send + Sync {
+  fn get(&self, cf: &str, key: &[u8]) -> Result<Option<Vec<u8>>, Error>;
+
+  fn put(&self, cf: &str, key: &[u8], value: &[u8]) -> Result<(), Error>;
+
+  fn delete(&self, cf: &str, key: &[u8]) -> Result<(), Error>;
+
+  /// Iterates over the keys of `cf` in order, starting at `start`. Ends
+  /// after the first error.
+  fn iter_from<'a>(
+    &'a self,
+    cf: &str,
+    start: &[u8],
+  ) -> Box<dyn Iterator<Item = Result<KeyValue, Error>> + 'a>;
+
+  /// The value as it is stored, without undoing what wrapping backends did
+  /// to it
+  fn get_raw(&self, cf: &str, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+    self.get(cf, key)
+  }
+
+  /// Applies all operations of `batch` atomically, as durably as the batch
+  /// asks for. Backends that persist nothing ignore the durability.
+  fn write(&self, batch: Batch) -> Result<(), Error>;
+
+  /// Compacts the keys of `cf` from `from` up to `to`, which removes the
+  /// tombstones left by deletes. Backends without tombstones do nothing.
+  fn compact_range(&self, _cf: &str, _from: &[u8], _to: &[u8]) {}
+
+  fn backup(&self, _engine: &mut BackupEngine) -> Result<(), Error> {
+    Err(Error::Unsupported("Backups"))
+  }
+
+  /// If every write fails, as the store is only opened for reading
+  fn is_read_only(&self) -> bool {
+    false
+  }
+
+  /// A view of the store as it is now, which later writes do not change.
+  /// Writing to it fails.
+  fn snapshot(&self) -> Box<dyn StorageBackend>;
+}
+
+/// Iterates over the keys of `cf` starting with `prefix`
+pub fn iter_prefix<'a>(
+  backend: &'a dyn StorageBackend,
+  cf: &str,
+  prefix: Vec<u8>,
+) -> impl Iterator<Item = Result<KeyValue, Error>> + 'a {
+  backend
+    .iter_from(cf, &prefix)
+    .take_while(move |item| match item {
+      Ok((key, _)) => key.starts_with(&prefix),
+      Err(_) => true,
+    })
+}
+
+/// Column families are named by the constants in `database`. Operations
+/// decoded from the replication log are mapped back to those constants,
+/// rather than borrowing from what was decoded.
+type ColumnFamilyName = &'static str;
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub enum Operation {
+  Put {
+    #[serde(deserialize_with = "deserialize_column_family")]
+    cf: ColumnFamilyName,
+    key: Vec<u8>,
+    value: Vec<u8>,
+  },
+  Delete {
+    #[serde(deserialize_with = "deserialize_column_family")]
+    cf: ColumnFamilyName,
+    key: Vec<u8>,
+  },
+  /// Deletes the keys from `from` up to, but not including, `to`
+  DeleteRange {
+    #[serde(deserialize_with = "deserialize_column_family")]
+    cf: ColumnFamilyName,
+    from: Vec<u8>,
+    to: Vec<u8>,
+  },
+}
+
+fn deserialize_column_family<'de, D: Deserializer<'de>>(
+  deserializer: D,
+) -> Result<&'static str, D::Error> {
+  let name = String::deserialize(deserializer)?;
+  database::column_family(&name)
+    .ok_or_else(|| de::Error::custom(format!("Unknown column family \"{}\"", name)))
+}
+
+/// Writes that are applied together by `StorageBackend::write`
+#[derive(Default)]
+pub struct Batch {
+  operations: Vec<Operation>,
+  durability: Durability,
+}
+
+impl Batch {
+  pub fn with_durability(durability: Durability) -> Batch {
+    Batch {
+      durability,
+      ..Default::default()
+    }
+  }
+
+  pub fn durability(&self) -> Durability {
+    self.durability
+  }
+
+  pub fn put<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, cf: &'static str, key: K, value: V) {
+    self.operations.push(Operation::Put {
+      cf,
+      key: key.as_ref().to_vec(),
+      value: value.as_ref().to_vec(),
+    });
+  }
+
+  pub fn delete<K: AsRef<[u8]>>(&mut self, cf: &'static str, key: K) {
+    self.operations.push(Operation::Delete {
+      cf,
+      key: key.as_ref().to_vec(),
+    });
+  }
+
+  pub fn delete_range<K: AsRef<[u8]>>(&mut self, cf: &'static str, from: K, to: K) {
+    self.operations.push(Operation::DeleteRange {
+      cf,
+      from: from.as_ref().to_vec(),
+      to: to.as_ref().to_vec(),
+    });
+  }
+
+  pub fn push(&mut self, operation: Operation) {
+    self.operations.push(operation);
+  }
+
+  pub fn into_operations(self) -> Vec<Operation> {
+    self.operations
+  }
+}
@@ -0,0 +1,156 @@
+//! Reads a RocksDB database that another process writes to.
+//!
+//! Opening a database for reading only does not take the lock of the data
+//! directory. A read-only database sees what was written up to when it was
+//! opened, including the write ahead log, and is reopened to catch up.
+
+use super::rocks::Handles;
+use super::{Batch, KeyValue, StorageBackend};
+use crate::database::Error;
+use crate::storage_options::RocksDbConfig;
+use rocksdb::{Options, DB};
+use std::path::Path;
+use std::sync::Arc;
+
+#[derive(Clone)]
+pub struct ReadOnlyBackend {
+  handles: Arc<Handles>,
+}
+
+impl ReadOnlyBackend {
+  /// Opens every column family of the database at `path`
+  pub fn open<P: AsRef<Path>>(path: P, config: &RocksDbConfig) -> Result<ReadOnlyBackend, Error> {
+    let path = path.as_ref();
+    let names = DB::list_cf(&Options::default(), path).map_err(Error::Inner)?;
+    let options = config.options(&Default::default());
+    let handles = Handles::open(path, options, names, true)?;
+    Ok(ReadOnlyBackend {
+      handles: Arc::new(handles),
+    })
+  }
+}
+
+impl StorageBackend for ReadOnlyBackend {
+  /// Column families the database was written without are empty
+  fn get(&self, cf: &str, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+    self.handles.get(self.handles.read_options(), cf, key)
+  }
+
+  fn put(&self, _cf: &str, _key: &[u8], _value: &[u8]) -> Result<(), Error> {
+    Err(Error::ReadOnly("be written"))
+  }
+
+  fn delete(&self, _cf: &str, _key: &[u8]) -> Result<(), Error> {
+    Err(Error::ReadOnly("be written"))
+  }
+
+  fn iter_from<'a>(
+    &'a self,
+    cf: &str,
+    start: &[u8],
+  ) -> Box<dyn Iterator<Item = Result<KeyValue, Error>> + 'a> {
+    self
+      .handles
+      .iter_from(self.handles.read_options(), cf, start)
+  }
+
+  fn write(&self, _batch: Batch) -> Result<(), Error> {
+    Err(Error::ReadOnly("be written"))
+  }
+
+  fn is_read_only(&self) -> bool {
+    true
+  }
+
+  /// Never sees later writes, so it is its own snapshot
+  fn snapshot(&self) -> Box<dyn StorageBackend> {
+    Box::new(self.clone())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::database::{Database, POINTS_CF};
+  use crate::entities::point::NewPoint;
+  use crate::entities::series::NewSeries;
+  use crate::entities::value::Value;
+  use crate::storage::rocks::RocksDbBackend;
+  use chrono::{TimeZone, Utc};
+  use tempdir::TempDir;
+
+  #[test]
+  fn test_read_while_written() {
+    let tmp_dir = TempDir::new("test_read_while_written").unwrap();
+    let db_path = tmp_dir.path().join("db");
+    let db = Database::open(&db_path, &Default::default()).unwrap();
+    db.create_series(NewSeries {
+      name: "test".to_string(),
+      ..Default::default()
+    })
+    .unwrap();
+    let time = Utc.timestamp(1_560_000_000, 0);
+    db.create_point(
+      "test",
+      NewPoint {
+        time,
+        value: Value::Float(1.0),
+      },
+      None,
+    )
+    .unwrap();
+
+    // The writer still holds the lock of the data directory
+    assert!(RocksDbBackend::open(&db_path, &Default::default(), &Default::default()).is_err());
+
+    let read_only = Database::open_read_only(&db_path, &Default::default(), None).unwrap();
+    assert!(read_only.lag().is_some());
+    assert_eq!(read_only.query("test", None).unwrap().len(), 1);
+    assert_eq!(
+      read_only.backend().put(POINTS_CF, b"key", b"value"),
+      Err(Error::ReadOnly("be written"))
+    );
+
+    db.create_point(
+      "test",
+      NewPoint {
+        time: time + chrono::Duration::seconds(1),
+        value: Value::Float(2.0),
+      },
+      None,
+    )
+    .unwrap();
+    assert_eq!(read_only.query("test", None).unwrap().len(), 1);
+
+    // Catches up by opening again
+    let read_only = Database::open_read_only(&db_path, &Default::default(), None).unwrap();
+    assert_eq!(read_only.query("test", None).unwrap().len(), 2);
+    assert_eq!(read_only.backend().iter_from(POINTS_CF, &[]).count(), 2);
+  }
+
+  #[test]
+  fn test_read_errors() {
+    let tmp_dir = TempDir::new("test_read_errors").unwrap();
+    let db_path = tmp_dir.path().join("db");
+    {
+      let backend =
+        RocksDbBackend::open(&db_path, &Default::default(), &Default::default()).unwrap();
+      backend.put(POINTS_CF, b"key", &[7; 64]).unwrap();
+      backend.compact_range(POINTS_CF, b"", b"\xff");
+    }
+    // Damages the data block of the table the point was flushed to
+    for entry in std::fs::read_dir(&db_path).unwrap() {
+      let path = entry.unwrap().path();
+      if path.extension().is_some_and(|extension| extension == "sst") {
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[8] ^= 0xff;
+        std::fs::write(&path, bytes).unwrap();
+      }
+    }
+
+    let backend = ReadOnlyBackend::open(&db_path, &Default::default()).unwrap();
+    assert!(backend.get(POINTS_CF, b"key").is_err());
+    let iterated = backend.iter_from(POINTS_CF, b"").collect::<Vec<_>>();
+    assert!(matches!(iterated.as_slice(), [Err(Error::Storage(_))]));
+  }
+}
@@ -0,0 +1,398 @@
+//! Logs every write batch under a sequence number, for followers to tail.
+//!
+//! The log is kept in the replication column family and is written in the
+//! same batch as the writes it records, so it can neither miss nor invent a
+//! write. A follower applies the batches of its primary together with their
+//! log entries, under the sequence numbers of the primary. Its own log is then
+//! where it resumes from, and what its followers tail once it is promoted.
+//!
+//! Writes of a follower that do not come from its primary, such as the
+//! migrations run when it is opened, are not logged, as they would take the
+//! sequence numbers of the primary.
+//!
+//! Every log has a random id, which a follower seeded from a backup of its
+//! primary shares, and a new follower takes on. Promoting a follower gives
+//! its log a new id, as it goes on with batches the primary does not have,
+//! and a follower refuses to apply batches of a log with another id.
+
+use super::rocks::BackupEngine;
+use super::{Batch, Durability, KeyValue, Operation, StorageBackend};
+use crate::codec;
+use crate::database::{self, Error, REPLICATION_CF, SERIES_CF};
+use bincode::{deserialize, serialize};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
+use chrono::{DateTime, Utc};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+const LAST_SEQUENCE: &str = "last_sequence";
+const LOG_ID: &str = "log_id";
+/// How many batches are written between trimming the log
+const TRIM_INTERVAL: u64 = 1024;
+
+/// A write batch of the log
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct LogEntry {
+  pub sequence: u64,
+  /// The operations of the batch, encoded with bincode
+  pub operations: Vec<u8>,
+}
+
+/// How far behind its primary a follower is
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct FollowerStatus {
+  /// The last sequence number of the primary, once it has been heard from
+  pub primary_sequence: Option<u64>,
+  /// When the follower had applied every batch of its primary, or started
+  /// following it
+  pub caught_up: DateTime<Utc>,
+}
+
+#[derive(Clone)]
+pub struct ReplicatedBackend {
+  shared: Arc<Shared>,
+}
+
+struct Shared {
+  inner: Box<dyn StorageBackend>,
+  /// Held while a batch is logged, so that sequence numbers are assigned in
+  /// the order batches are written
+  last_sequence: Mutex<u64>,
+  /// How many batches are kept for followers
+  log_size: u64,
+  log_id: AtomicU64,
+  following: AtomicBool,
+  status: Mutex<FollowerStatus>,
+}
+
+impl ReplicatedBackend {
+  /// Logs the writes to `inner`, unless `following` a primary
+  pub fn new(
+    inner: Box<dyn StorageBackend>,
+    log_size: u64,
+    following: bool,
+  ) -> Result<ReplicatedBackend, Error> {
+    let last_sequence = inner
+      .get(SERIES_CF, &codec::catalog_key(LAST_SEQUENCE))?
+      .and_then(|value| codec::decode_log_sequence(&value))
+      .unwrap_or(0);
+    let log_id = match inner.get(SERIES_CF, &codec::catalog_key(LOG_ID))? {
+      Some(value) => codec::decode_log_sequence(&value)
+        .ok_or_else(|| Error::Storage("Stored log id is not 8 bytes long".to_string()))?,
+      None => {
+        let log_id = OsRng.next_u64();
+        inner.put(
+          SERIES_CF,
+          &codec::catalog_key(LOG_ID),
+          &log_id.to_be_bytes(),
+        )?;
+        log_id
+      }
+    };
+
+    Ok(ReplicatedBackend {
+      shared: Arc::new(Shared {
+        inner,
+        last_sequence: Mutex::new(last_sequence),
+        log_size,
+        log_id: AtomicU64::new(log_id),
+        following: AtomicBool::new(following),
+        status: Mutex::new(FollowerStatus {
+          primary_sequence: None,
+          caught_up: Utc::now(),
+        }),
+      }),
+    })
+  }
+
+  pub fn last_sequence(&self) -> u64 {
+    *self.shared.last_sequence.lock().unwrap()
+  }
+
+  pub fn log_id(&self) -> u64 {
+    self.shared.log_id.load(Ordering::SeqCst)
+  }
+
+  fn set_log_id(&self, log_id: u64) -> Result<(), Error> {
+    self.shared.inner.put(
+      SERIES_CF,
+      &codec::catalog_key(LOG_ID),
+      &log_id.to_be_bytes(),
+    )?;
+    self.shared.log_id.store(log_id, Ordering::SeqCst);
+    Ok(())
+  }
+
+  /// Fails unless the batches of the log `primary_log_id` follow the
+  /// batches applied so far. A follower that applied none takes the log on.
+  pub fn check_primary(&self, primary_log_id: u64) -> Result<(), Error> {
+    let last_sequence = self.shared.last_sequence.lock().unwrap();
+    if self.log_id() == primary_log_id {
+      return Ok(());
+    }
+    if *last_sequence > 0 {
+      return Err(Error::Replication(
+        "The log of the primary is not the one followed so far, seed the follower again"
+          .to_string(),
+      ));
+    }
+    self.set_log_id(primary_log_id)
+  }
+
+  pub fn is_following(&self) -> bool {
+    self.shared.following.load(Ordering::SeqCst)
+  }
+
+  pub fn status(&self) -> FollowerStatus {
+    *self.shared.status.lock().unwrap()
+  }
+
+  /// Up to `limit` batches of the log, starting with the one after `after`
+  pub fn read(&self, after: u64, limit: usize) -> Result<Vec<LogEntry>, Error> {
+    let last_sequence = self.last_sequence();
+    if after > last_sequence {
+      return Err(Error::Replication(format!(
+        "Write batches after {} were asked for, but the log ends at {}",
+        after, last_sequence
+      )));
+    }
+
+    let end = codec::log_key(last_sequence);
+    let entries = self
+      .shared
+      .inner
+      .iter_from(REPLICATION_CF, &codec::log_key(after + 1))
+      .take_while(|item| match item {
+        Ok((key, _)) => **key <= end[..],
+        Err(_) => true,
+      })
+      .take(limit)
+      .filter_map(|item| match item {
+        Ok((key, value)) => Some(Ok(LogEntry {
+          sequence: codec::decode_log_sequence(&key)?,
+          operations: value.into_vec(),
+        })),
+        Err(err) => Some(Err(err)),
+      })
+      .collect::<Result<Vec<_>, _>>()?;
+
+    match entries.first() {
+      Some(entry) if entry.sequence != after + 1 => Err(Error::LogTrimmed(after)),
+      None if after < last_sequence => Err(Error::LogTrimmed(after)),
+      _ => Ok(entries),
+    }
+  }
+
+  /// Writes a batch of the primary, unless it was applied before, and
+  /// returns the operations that were written
+  pub fn apply(&self, entry: &LogEntry) -> Result<Vec<Operation>, Error> {
+    let mut last_sequence = self.shared.last_sequence.lock().unwrap();
+    if !self.is_following() {
+      return Err(Error::NotFollowing);
+    }
+    if entry.sequence <= *last_sequence {
+      return Ok(vec![]);
+    }
+    if entry.sequence != *last_sequence + 1 {
+      return Err(Error::Replication(format!(
+        "Write batch {} does not follow {}",
+        entry.sequence, *last_sequence
+      )));
+    }
+
+    let operations = deserialize::<Vec<Operation>>(&entry.operations)
+      .map_err(|err| Error::Replication(err.to_string()))?;
+    let batch = self.logged(
+      entry.sequence,
+      operations.clone(),
+      &entry.operations,
+      Durability::default(),
+    );
+    self.shared.inner.write(batch)?;
+    *last_sequence = entry.sequence;
+    Ok(operations)
+  }
+
+  /// Records that the primary has logged up to `primary_sequence`
+  pub fn heard_from_primary(&self, primary_sequence: u64) {
+    let caught_up = self.last_sequence() >= primary_sequence;
+    let mut status = self.shared.status.lock().unwrap();
+    status.primary_sequence = Some(primary_sequence);
+    if caught_up {
+      status.caught_up = Utc::now();
+    }
+  }
+
+  /// Stops following the primary, every write is logged from now on. The
+  /// log gets a new id, so that the database refuses to follow the primary
+  /// again.
+  pub fn promote(&self) -> Result<(), Error> {
+    let _last_sequence = self.shared.last_sequence.lock().unwrap();
+    if !self.is_following() {
+      return Err(Error::NotFollowing);
+    }
+    self.set_log_id(OsRng.next_u64())?;
+    self.shared.following.store(false, Ordering::SeqCst);
+    Ok(())
+  }
+
+  /// `operations` with their log entry, and the entries that fell out of
+  /// the log
+  fn logged(
+    &self,
+    sequence: u64,
+    operations: Vec<Operation>,
+    encoded: &[u8],
+    durability: Durability,
+  ) -> Batch {
+    let mut batch = Batch::with_durability(durability);
+    for operation in operations {
+      batch.push(operation);
+    }
+    batch.put(REPLICATION_CF, codec::log_key(sequence), encoded);
+    batch.put(
+      SERIES_CF,
+      codec::catalog_key(LAST_SEQUENCE),
+      codec::log_key(sequence),
+    );
+    if sequence.is_multiple_of(TRIM_INTERVAL) && sequence > self.shared.log_size {
+      batch.delete_range(
+        REPLICATION_CF,
+        codec::log_key(0),
+        codec::log_key(sequence - self.shared.log_size),
+      );
+    }
+    batch
+  }
+}
+
+fn static_column_family(cf: &str) -> &'static str {
+  database::column_family(cf).unwrap_or_else(|| panic!("Unknown column family \"{}\"", cf))
+}
+
+impl StorageBackend for ReplicatedBackend {
+  fn get(&self, cf: &str, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+    self.shared.inner.get(cf, key)
+  }
+
+  fn put(&self, cf: &str, key: &[u8], value: &[u8]) -> Result<(), Error> {
+    let mut batch = Batch::default();
+    batch.put(static_column_family(cf), key, value);
+    self.write(batch)
+  }
+
+  fn delete(&self, cf: &str, key: &[u8]) -> Result<(), Error> {
+    let mut batch = Batch::default();
+    batch.delete(static_column_family(cf), key);
+    self.write(batch)
+  }
+
+  fn iter_from<'a>(
+    &'a self,
+    cf: &str,
+    start: &[u8],
+  ) -> Box<dyn Iterator<Item = Result<KeyValue, Error>> + 'a> {
+    self.shared.inner.iter_from(cf, start)
+  }
+
+  fn write(&self, batch: Batch) -> Result<(), Error> {
+    let mut last_sequence = self.shared.last_sequence.lock().unwrap();
+    if self.is_following() {
+      return self.shared.inner.write(batch);
+    }
+
+    let sequence = *last_sequence + 1;
+    let durability = batch.durability();
+    let operations = batch.into_operations();
+    let encoded = serialize(&operations).unwrap();
+    let batch = self.logged(sequence, operations, &encoded, durability);
+    self.shared.inner.write(batch)?;
+    *last_sequence = sequence;
+    Ok(())
+  }
+
+  fn compact_range(&self, cf: &str, from: &[u8], to: &[u8]) {
+    self.shared.inner.compact_range(cf, from, to)
+  }
+
+  fn backup(&self, engine: &mut BackupEngine) -> Result<(), Error> {
+    self.shared.inner.backup(engine)
+  }
+
+  fn get_raw(&self, cf: &str, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+    self.shared.inner.get_raw(cf, key)
+  }
+
+  fn is_read_only(&self) -> bool {
+    self.shared.inner.is_read_only()
+  }
+
+  fn snapshot(&self) -> Box<dyn StorageBackend> {
+    self.shared.inner.snapshot()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::database::POINTS_CF;
+  use crate::storage::memory::MemoryBackend;
+
+  fn backend(following: bool) -> ReplicatedBackend {
+    ReplicatedBackend::new(Box::new(MemoryBackend::new()), 2048, following).unwrap()
+  }
+
+  #[test]
+  fn test_replicate() {
+    let primary = backend(false);
+    let follower = backend(true);
+    primary.put(POINTS_CF, b"a", b"1").unwrap();
+    let mut batch = Batch::default();
+    batch.put(POINTS_CF, b"b", b"2");
+    batch.delete(POINTS_CF, b"a");
+    primary.write(batch).unwrap();
+    assert_eq!(primary.last_sequence(), 2);
+
+    // Writes of a follower are its own
+    follower.put(POINTS_CF, b"c", b"3").unwrap();
+    assert_eq!(follower.last_sequence(), 0);
+
+    let entries = primary.read(0, 10).unwrap();
+    assert_eq!(entries.len(), 2);
+    for entry in entries.iter().chain(&entries) {
+      follower.apply(entry).unwrap();
+    }
+    assert_eq!(follower.last_sequence(), 2);
+    assert_eq!(follower.get(POINTS_CF, b"a"), Ok(None));
+    assert_eq!(follower.get(POINTS_CF, b"b"), Ok(Some(b"2".to_vec())));
+    assert_eq!(follower.read(1, 10).unwrap(), entries[1..].to_vec());
+
+    let missing = LogEntry {
+      sequence: 4,
+      operations: entries[0].operations.clone(),
+    };
+    assert!(follower.apply(&missing).is_err());
+
+    follower.heard_from_primary(2);
+    assert_eq!(follower.status().primary_sequence, Some(2));
+
+    follower.promote().unwrap();
+    assert_eq!(follower.promote(), Err(Error::NotFollowing));
+    assert_eq!(follower.apply(&missing), Err(Error::NotFollowing));
+    follower.put(POINTS_CF, b"d", b"4").unwrap();
+    assert_eq!(follower.last_sequence(), 3);
+  }
+
+  #[test]
+  fn test_trim_log() {
+    let primary = ReplicatedBackend::new(Box::new(MemoryBackend::new()), 10, false).unwrap();
+    for i in 0..TRIM_INTERVAL {
+      primary.put(POINTS_CF, &i.to_be_bytes(), b"").unwrap();
+    }
+
+    assert_eq!(primary.read(TRIM_INTERVAL, 10), Ok(vec![]));
+    assert_eq!(primary.read(TRIM_INTERVAL - 10, 10).unwrap().len(), 10);
+    assert_eq!(primary.read(0, 10), Err(Error::LogTrimmed(0)));
+  }
+}
@@ -0,0 +1,536 @@
+//! Keeps the data in RocksDB.
+//!
+//! Databases are opened through the C API, as the `rocksdb` crate neither
+//! reports errors that end an iteration nor hands out the handles snapshots
+//! and backups need. Read-only databases share the handles and iterators.
+
+use super::{Batch, Durability, KeyValue, Operation, StorageBackend};
+use crate::database::Error;
+use crate::retention::RetentionTable;
+use crate::storage_options::{RocksDbConfig, RocksDbOptions};
+use librocksdb_sys as ffi;
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::marker::PhantomData;
+use std::os::raw::c_char;
+use std::path::Path;
+use std::ptr;
+use std::slice;
+use std::sync::Arc;
+
+/// Takes the message of an error returned by the C API
+unsafe fn take_error(error: *mut c_char) -> String {
+  let message = CStr::from_ptr(error).to_string_lossy().into_owned();
+  ffi::rocksdb_free(error as *mut _);
+  message
+}
+
+/// Calls `f` with an error pointer, and returns the error it sets
+unsafe fn check<T, F>(f: F) -> Result<T, String>
+where
+  F: FnOnce(*mut *mut c_char) -> T,
+{
+  let mut error = ptr::null_mut();
+  let result = f(&mut error);
+  if error.is_null() {
+    Ok(result)
+  } else {
+    Err(take_error(error))
+  }
+}
+
+fn c_path(path: &Path) -> Result<CString, Error> {
+  CString::new(path.to_string_lossy().as_bytes())
+    .map_err(|_| Error::Storage("Database path contains a null byte".to_string()))
+}
+
+/// An open database and its column families
+pub struct Handles {
+  db: *mut ffi::rocksdb_t,
+  column_families: HashMap<String, *mut ffi::rocksdb_column_family_handle_t>,
+  read_options: *mut ffi::rocksdb_readoptions_t,
+  /// Kept until the database is closed
+  _options: RocksDbOptions,
+}
+
+// SAFETY: RocksDB synchronizes access to a database and its column family
+// handles, and neither the handles nor the options are changed after the
+// database is opened
+unsafe impl Send for Handles {}
+unsafe impl Sync for Handles {}
+
+impl Handles {
+  /// Opens the column families `names` of the database at `path`, or all of
+  /// them if it is opened for reading only
+  pub fn open(
+    path: &Path,
+    options: RocksDbOptions,
+    names: Vec<String>,
+    read_only: bool,
+  ) -> Result<Handles, Error> {
+    let c_path = c_path(path)?;
+    let c_names = names
+      .iter()
+      .map(|name| CString::new(name.as_bytes()).unwrap())
+      .collect::<Vec<_>>();
+    let mut name_pointers = c_names.iter().map(|name| name.as_ptr()).collect::<Vec<_>>();
+    let mut family_options = names
+      .iter()
+      .map(|name| options.column_family(name))
+      .collect::<Vec<_>>();
+    let mut handles = vec![ptr::null_mut(); names.len()];
+
+    let db = unsafe {
+      check(|error| {
+        if read_only {
+          ffi::rocksdb_open_for_read_only_column_families(
+            options.db,
+            c_path.as_ptr(),
+            names.len() as _,
+            name_pointers.as_mut_ptr(),
+            family_options.as_mut_ptr(),
+            handles.as_mut_ptr(),
+            0,
+            error,
+          )
+        } else {
+          ffi::rocksdb_open_column_families(
+            options.db,
+            c_path.as_ptr(),
+            names.len() as _,
+            name_pointers.as_mut_ptr(),
+            family_options.as_mut_ptr(),
+            handles.as_mut_ptr(),
+            error,
+          )
+        }
+      })
+    }
+    .map_err(|message| {
+      // Repairing may lose data, so it is left to the operator
+      if message.starts_with("Corruption") {
+        Error::Corrupted(message)
+      } else {
+        Error::Storage(message)
+      }
+    })?;
+
+    Ok(Handles {
+      db,
+      column_families: names.into_iter().zip(handles).collect(),
+      read_options: unsafe { ffi::rocksdb_readoptions_create() },
+      _options: options,
+    })
+  }
+
+  /// Reads the latest data
+  pub fn read_options(&self) -> *const ffi::rocksdb_readoptions_t {
+    self.read_options
+  }
+
+  fn column_family(&self, cf: &str) -> Option<*mut ffi::rocksdb_column_family_handle_t> {
+    self.column_families.get(cf).cloned()
+  }
+
+  /// The handle of a column family that is written to
+  fn writable_column_family(&self, cf: &str) -> *mut ffi::rocksdb_column_family_handle_t {
+    self
+      .column_family(cf)
+      .unwrap_or_else(|| panic!("Column family \"{}\" is not open", cf))
+  }
+
+  /// Column families the database was opened without are empty
+  pub fn get(
+    &self,
+    read_options: *const ffi::rocksdb_readoptions_t,
+    cf: &str,
+    key: &[u8],
+  ) -> Result<Option<Vec<u8>>, Error> {
+    let handle = match self.column_family(cf) {
+      Some(handle) => handle,
+      None => return Ok(None),
+    };
+    unsafe {
+      let mut length = 0;
+      let value = check(|error| {
+        ffi::rocksdb_get_cf(
+          self.db,
+          read_options,
+          handle,
+          key.as_ptr() as *const c_char,
+          key.len(),
+          &mut length,
+          error,
+        )
+      })
+      .map_err(|message| {
+        Error::Storage(format!("Could not read from the database: {}", message))
+      })?;
+      if value.is_null() {
+        return Ok(None);
+      }
+      let bytes = slice::from_raw_parts(value as *const u8, length).to_vec();
+      ffi::rocksdb_free(value as *mut _);
+      Ok(Some(bytes))
+    }
+  }
+
+  pub fn iter_from<'a>(
+    &'a self,
+    read_options: *const ffi::rocksdb_readoptions_t,
+    cf: &str,
+    start: &[u8],
+  ) -> Box<dyn Iterator<Item = Result<KeyValue, Error>> + 'a> {
+    let handle = match self.column_family(cf) {
+      Some(handle) => handle,
+      None => return Box::new(std::iter::empty()),
+    };
+    unsafe {
+      let iterator = ffi::rocksdb_create_iterator_cf(self.db, read_options, handle);
+      ffi::rocksdb_iter_seek(iterator, start.as_ptr() as *const c_char, start.len());
+      Box::new(RocksDbIterator {
+        iterator,
+        failed: false,
+        handles: PhantomData,
+      })
+    }
+  }
+}
+
+impl Drop for Handles {
+  fn drop(&mut self) {
+    unsafe {
+      for handle in self.column_families.values() {
+        ffi::rocksdb_column_family_handle_destroy(*handle);
+      }
+      ffi::rocksdb_close(self.db);
+      ffi::rocksdb_readoptions_destroy(self.read_options);
+    }
+  }
+}
+
+/// Yields an error if reading fails, rather than ending early as if the
+/// keys that could not be read were not there
+struct RocksDbIterator<'a> {
+  iterator: *mut ffi::rocksdb_iterator_t,
+  failed: bool,
+  handles: PhantomData<&'a Handles>,
+}
+
+impl<'a> Iterator for RocksDbIterator<'a> {
+  type Item = Result<KeyValue, Error>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    if self.failed {
+      return None;
+    }
+    unsafe {
+      if ffi::rocksdb_iter_valid(self.iterator) == 0 {
+        let mut error = ptr::null_mut();
+        ffi::rocksdb_iter_get_error(self.iterator, &mut error);
+        if error.is_null() {
+          return None;
+        }
+        self.failed = true;
+        return Some(Err(Error::Storage(format!(
+          "Could not read from the database: {}",
+          take_error(error)
+        ))));
+      }
+      let mut length = 0;
+      let key = ffi::rocksdb_iter_key(self.iterator, &mut length);
+      let key = slice::from_raw_parts(key as *const u8, length).into();
+      let value = ffi::rocksdb_iter_value(self.iterator, &mut length);
+      let value = slice::from_raw_parts(value as *const u8, length).into();
+      ffi::rocksdb_iter_next(self.iterator);
+      Some(Ok((key, value)))
+    }
+  }
+}
+
+impl<'a> Drop for RocksDbIterator<'a> {
+  fn drop(&mut self) {
+    unsafe { ffi::rocksdb_iter_destroy(self.iterator) }
+  }
+}
+
+/// Stores backups of databases, sharing table files between them
+pub struct BackupEngine {
+  engine: *mut ffi::rocksdb_backup_engine_t,
+}
+
+impl BackupEngine {
+  pub fn open<P: AsRef<Path>>(path: P) -> Result<BackupEngine, Error> {
+    let path = c_path(path.as_ref())?;
+    unsafe {
+      let options = ffi::rocksdb_options_create();
+      let engine = check(|error| ffi::rocksdb_backup_engine_open(options, path.as_ptr(), error));
+      ffi::rocksdb_options_destroy(options);
+      Ok(BackupEngine {
+        engine: engine.map_err(Error::Storage)?,
+      })
+    }
+  }
+
+  pub fn purge_old_backups(&mut self, keep: usize) -> Result<(), Error> {
+    unsafe {
+      check(|error| ffi::rocksdb_backup_engine_purge_old_backups(self.engine, keep as u32, error))
+        .map_err(Error::Storage)
+    }
+  }
+
+  /// Replaces the database in `db_dir` with the latest backup
+  pub fn restore_from_latest_backup<P: AsRef<Path>>(&mut self, db_dir: P) -> Result<(), Error> {
+    let db_dir = c_path(db_dir.as_ref())?;
+    unsafe {
+      let options = ffi::rocksdb_restore_options_create();
+      let restored = check(|error| {
+        ffi::rocksdb_backup_engine_restore_db_from_latest_backup(
+          self.engine,
+          db_dir.as_ptr(),
+          db_dir.as_ptr(),
+          options,
+          error,
+        )
+      });
+      ffi::rocksdb_restore_options_destroy(options);
+      restored.map_err(Error::Storage)
+    }
+  }
+}
+
+impl Drop for BackupEngine {
+  fn drop(&mut self) {
+    unsafe { ffi::rocksdb_backup_engine_close(self.engine) }
+  }
+}
+
+pub struct RocksDbBackend {
+  handles: Arc<Handles>,
+}
+
+impl RocksDbBackend {
+  pub fn open<P: AsRef<Path>>(
+    path: P,
+    config: &RocksDbConfig,
+    retention: &RetentionTable,
+  ) -> Result<RocksDbBackend, Error> {
+    info!("RocksDB options: {:?}", config.effective());
+
+    let options = config.options(retention);
+    let names = options
+      .column_families
+      .iter()
+      .map(|(name, _)| name.to_string())
+      .collect();
+    let handles = Handles::open(path.as_ref(), options, names, false)?;
+    Ok(RocksDbBackend {
+      handles: Arc::new(handles),
+    })
+  }
+
+  /// Salvages what it can of corrupted files. Recent writes and whole files
+  /// may be lost.
+  pub fn repair<P: AsRef<Path>>(path: P, config: &RocksDbConfig) -> Result<(), Error> {
+    let path = c_path(path.as_ref())?;
+    let options = config.options(&Default::default());
+    unsafe { check(|error| ffi::rocksdb_repair_db(options.db, path.as_ptr(), error)) }
+      .map_err(Error::Storage)
+  }
+}
+
+impl StorageBackend for RocksDbBackend {
+  fn get(&self, cf: &str, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+    self.handles.get(self.handles.read_options(), cf, key)
+  }
+
+  fn put(&self, cf: &str, key: &[u8], value: &[u8]) -> Result<(), Error> {
+    let handles = &self.handles;
+    unsafe {
+      let write_options = ffi::rocksdb_writeoptions_create();
+      let written = check(|error| {
+        ffi::rocksdb_put_cf(
+          handles.db,
+          write_options,
+          handles.writable_column_family(cf),
+          key.as_ptr() as *const c_char,
+          key.len(),
+          value.as_ptr() as *const c_char,
+          value.len(),
+          error,
+        )
+      });
+      ffi::rocksdb_writeoptions_destroy(write_options);
+      written.map_err(Error::Storage)
+    }
+  }
+
+  fn delete(&self, cf: &str, key: &[u8]) -> Result<(), Error> {
+    let handles = &self.handles;
+    unsafe {
+      let write_options = ffi::rocksdb_writeoptions_create();
+      let written = check(|error| {
+        ffi::rocksdb_delete_cf(
+          handles.db,
+          write_options,
+          handles.writable_column_family(cf),
+          key.as_ptr() as *const c_char,
+          key.len(),
+          error,
+        )
+      });
+      ffi::rocksdb_writeoptions_destroy(write_options);
+      written.map_err(Error::Storage)
+    }
+  }
+
+  fn iter_from<'a>(
+    &'a self,
+    cf: &str,
+    start: &[u8],
+  ) -> Box<dyn Iterator<Item = Result<KeyValue, Error>> + 'a> {
+    self
+      .handles
+      .iter_from(self.handles.read_options(), cf, start)
+  }
+
+  fn write(&self, batch: Batch) -> Result<(), Error> {
+    let handles = &self.handles;
+    unsafe {
+      let write_options = ffi::rocksdb_writeoptions_create();
+      match batch.durability() {
+        Durability::Sync => ffi::rocksdb_writeoptions_set_sync(write_options, 1),
+        Durability::Async => (),
+        Durability::Disabled => ffi::rocksdb_writeoptions_disable_WAL(write_options, 1),
+      }
+
+      let write_batch = ffi::rocksdb_writebatch_create();
+      for operation in batch.into_operations() {
+        match operation {
+          Operation::Put { cf, key, value } => ffi::rocksdb_writebatch_put_cf(
+            write_batch,
+            handles.writable_column_family(cf),
+            key.as_ptr() as *const c_char,
+            key.len(),
+            value.as_ptr() as *const c_char,
+            value.len(),
+          ),
+          Operation::Delete { cf, key } => ffi::rocksdb_writebatch_delete_cf(
+            write_batch,
+            handles.writable_column_family(cf),
+            key.as_ptr() as *const c_char,
+            key.len(),
+          ),
+          Operation::DeleteRange { cf, from, to } => ffi::rocksdb_writebatch_delete_range_cf(
+            write_batch,
+            handles.writable_column_family(cf),
+            from.as_ptr() as *const c_char,
+            from.len(),
+            to.as_ptr() as *const c_char,
+            to.len(),
+          ),
+        }
+      }
+
+      let written =
+        check(|error| ffi::rocksdb_write(handles.db, write_options, write_batch, error));
+      ffi::rocksdb_writebatch_destroy(write_batch);
+      ffi::rocksdb_writeoptions_destroy(write_options);
+      written.map_err(Error::Storage)
+    }
+  }
+
+  fn compact_range(&self, cf: &str, from: &[u8], to: &[u8]) {
+    unsafe {
+      ffi::rocksdb_compact_range_cf(
+        self.handles.db,
+        self.handles.writable_column_family(cf),
+        from.as_ptr() as *const c_char,
+        from.len(),
+        to.as_ptr() as *const c_char,
+        to.len(),
+      )
+    }
+  }
+
+  fn backup(&self, engine: &mut BackupEngine) -> Result<(), Error> {
+    unsafe {
+      check(|error| {
+        ffi::rocksdb_backup_engine_create_new_backup_flush(engine.engine, self.handles.db, 1, error)
+      })
+    }
+    .map_err(Error::Storage)
+  }
+
+  fn snapshot(&self) -> Box<dyn StorageBackend> {
+    unsafe {
+      let snapshot = ffi::rocksdb_create_snapshot(self.handles.db);
+      let read_options = ffi::rocksdb_readoptions_create();
+      ffi::rocksdb_readoptions_set_snapshot(read_options, snapshot);
+      Box::new(RocksDbSnapshot(Arc::new(SnapshotHandle {
+        handles: self.handles.clone(),
+        snapshot,
+        read_options,
+      })))
+    }
+  }
+}
+
+/// A snapshot, which keeps its database open
+struct SnapshotHandle {
+  handles: Arc<Handles>,
+  snapshot: *const ffi::rocksdb_snapshot_t,
+  read_options: *mut ffi::rocksdb_readoptions_t,
+}
+
+// SAFETY: A snapshot is immutable and RocksDB reads it from any thread. The
+// read options are only changed before the handle is shared.
+unsafe impl Send for SnapshotHandle {}
+unsafe impl Sync for SnapshotHandle {}
+
+impl Drop for SnapshotHandle {
+  fn drop(&mut self) {
+    // The database is only closed once `handles` is dropped, after this
+    unsafe {
+      ffi::rocksdb_readoptions_destroy(self.read_options);
+      ffi::rocksdb_release_snapshot(self.handles.db, self.snapshot);
+    }
+  }
+}
+
+#[derive(Clone)]
+struct RocksDbSnapshot(Arc<SnapshotHandle>);
+
+impl StorageBackend for RocksDbSnapshot {
+  fn get(&self, cf: &str, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+    self.0.handles.get(self.0.read_options, cf, key)
+  }
+
+  fn put(&self, _cf: &str, _key: &[u8], _value: &[u8]) -> Result<(), Error> {
+    Err(Error::ReadOnly("be written"))
+  }
+
+  fn delete(&self, _cf: &str, _key: &[u8]) -> Result<(), Error> {
+    Err(Error::ReadOnly("be written"))
+  }
+
+  fn iter_from<'a>(
+    &'a self,
+    cf: &str,
+    start: &[u8],
+  ) -> Box<dyn Iterator<Item = Result<KeyValue, Error>> + 'a> {
+    self.0.handles.iter_from(self.0.read_options, cf, start)
+  }
+
+  fn write(&self, _batch: Batch) -> Result<(), Error> {
+    Err(Error::ReadOnly("be written"))
+  }
+
+  fn is_read_only(&self) -> bool {
+    true
+  }
+
+  fn snapshot(&self) -> Box<dyn StorageBackend> {
+    Box::new(self.clone())
+  }
+}
@@ -0,0 +1,321 @@
+//! RocksDB tuning, configured under `[storage.rocksdb]`.
+//!
+//! Every option is optional. Options that are left out fall back to the
+//! defaults below, which are logged together with the configured options when
+//! the database is opened.
+
+use crate::database::{
+  BLOCKS_CF, DEFAULT_CF, POINTS_CF, QUARANTINE_CF, REPLICATION_CF, ROLLUPS_CF, SERIES_CF,
+};
+use crate::retention::{RetentionTable, COMPACTION_FILTER_NAME};
+use librocksdb_sys as ffi;
+use rocksdb::compaction_filter::{self, CompactionFilterCallback, CompactionFilterFn};
+use serde::de::{self, Deserialize, Deserializer};
+use std::ffi::CString;
+use std::os::raw::{c_int, c_void};
+use std::str::FromStr;
+
+const DEFAULT_BLOCK_CACHE_SIZE: usize = 8 * 1024 * 1024;
+const DEFAULT_WRITE_BUFFER_SIZE: usize = 64 * 1024 * 1024;
+const DEFAULT_BLOOM_FILTER_BITS: i32 = 10;
+const DEFAULT_MAX_OPEN_FILES: i32 = -1;
+const DEFAULT_BACKGROUND_JOBS: i32 = 2;
+
+#[derive(Serialize, PartialEq, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+  None,
+  Snappy,
+  Zlib,
+  Bz2,
+  Lz4,
+  Lz4hc,
+  Zstd,
+}
+
+impl FromStr for Compression {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "none" => Ok(Compression::None),
+      "snappy" => Ok(Compression::Snappy),
+      "zlib" => Ok(Compression::Zlib),
+      "bz2" => Ok(Compression::Bz2),
+      "lz4" => Ok(Compression::Lz4),
+      "lz4hc" => Ok(Compression::Lz4hc),
+      "zstd" => Ok(Compression::Zstd),
+      _ => Err(format!("Unknown compression \"{}\"", s)),
+    }
+  }
+}
+
+impl<'de> Deserialize<'de> for Compression {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    deserialize_from_str(deserializer)
+  }
+}
+
+/// Reads a config enum from its name, the config crate can not deserialize
+/// enums by itself
+pub fn deserialize_from_str<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+  D: Deserializer<'de>,
+  T: FromStr<Err = String>,
+{
+  String::deserialize(deserializer)?
+    .parse()
+    .map_err(de::Error::custom)
+}
+
+impl Compression {
+  fn code(self) -> c_int {
+    (match self {
+      Compression::None => ffi::rocksdb_no_compression,
+      Compression::Snappy => ffi::rocksdb_snappy_compression,
+      Compression::Zlib => ffi::rocksdb_zlib_compression,
+      Compression::Bz2 => ffi::rocksdb_bz2_compression,
+      Compression::Lz4 => ffi::rocksdb_lz4_compression,
+      Compression::Lz4hc => ffi::rocksdb_lz4hc_compression,
+      Compression::Zstd => ffi::rocksdb_zstd_compression,
+    }) as c_int
+  }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Default)]
+pub struct RocksDbConfig {
+  /// Size in bytes of the LRU block cache shared by all column families
+  pub block_cache_size: Option<usize>,
+  /// Size in bytes of the memtable of each column family
+  pub write_buffer_size: Option<usize>,
+  /// Compression of the points and rollups column families, per level. Only
+  /// settable in the config file, as environment variables can not hold a
+  /// list.
+  pub compression_per_level: Option<Vec<Compression>>,
+  /// Bits per key of the bloom filters, 0 disables them
+  pub bloom_filter_bits: Option<i32>,
+  /// Maximum number of open files, -1 keeps all files open
+  pub max_open_files: Option<i32>,
+  /// Number of threads used for flushes and compactions
+  pub background_jobs: Option<i32>,
+}
+
+impl RocksDbConfig {
+  /// The config with defaults filled in for every option that was left out
+  pub fn effective(&self) -> RocksDbConfig {
+    RocksDbConfig {
+      block_cache_size: self.block_cache_size.or(Some(DEFAULT_BLOCK_CACHE_SIZE)),
+      write_buffer_size: self.write_buffer_size.or(Some(DEFAULT_WRITE_BUFFER_SIZE)),
+      compression_per_level: self.compression_per_level.clone(),
+      bloom_filter_bits: self.bloom_filter_bits.or(Some(DEFAULT_BLOOM_FILTER_BITS)),
+      max_open_files: self.max_open_files.or(Some(DEFAULT_MAX_OPEN_FILES)),
+      background_jobs: self.background_jobs.or(Some(DEFAULT_BACKGROUND_JOBS)),
+    }
+  }
+
+  /// The options of the database and of each of its column families
+  pub fn options(&self, retention: &RetentionTable) -> RocksDbOptions {
+    let config = self.effective();
+    let background_jobs = config.background_jobs.unwrap();
+
+    unsafe {
+      let db = ffi::rocksdb_options_create();
+      ffi::rocksdb_options_set_create_if_missing(db, 1);
+      ffi::rocksdb_options_set_create_missing_column_families(db, 1);
+      ffi::rocksdb_options_set_max_open_files(db, config.max_open_files.unwrap());
+      ffi::rocksdb_options_increase_parallelism(db, background_jobs);
+      ffi::rocksdb_options_set_max_background_compactions(db, background_jobs);
+      ffi::rocksdb_options_set_max_background_flushes(db, (background_jobs / 4).max(1));
+
+      // Every column family shares the block cache
+      let table_options = ffi::rocksdb_block_based_options_create();
+      let cache = ffi::rocksdb_cache_create_lru(config.block_cache_size.unwrap());
+      ffi::rocksdb_block_based_options_set_block_cache(table_options, cache);
+      if config.bloom_filter_bits.unwrap() > 0 {
+        let bloom = ffi::rocksdb_filterpolicy_create_bloom(config.bloom_filter_bits.unwrap());
+        ffi::rocksdb_block_based_options_set_filter_policy(table_options, bloom);
+      }
+      let family_options = |compression: Compression| {
+        let options = ffi::rocksdb_options_create();
+        ffi::rocksdb_options_set_block_based_table_factory(options, table_options);
+        ffi::rocksdb_options_set_write_buffer_size(options, config.write_buffer_size.unwrap());
+        ffi::rocksdb_options_set_compression(options, compression.code());
+        options
+      };
+
+      // Series are small and only ever looked up by key
+      let series_options = family_options(Compression::None);
+
+      // Points are written at a high rate and scanned in ranges
+      let points_options = family_options(Compression::Lz4);
+
+      // Rollups are written in bulk by the janitor and rarely read
+      let rollups_options = family_options(Compression::Zstd);
+
+      if let Some(levels) = &config.compression_per_level {
+        let mut levels = levels
+          .iter()
+          .map(|compression| compression.code())
+          .collect::<Vec<_>>();
+        for options in &[points_options, rollups_options] {
+          ffi::rocksdb_options_set_compression_per_level(
+            *options,
+            levels.as_mut_ptr(),
+            levels.len(),
+          );
+        }
+      }
+
+      // Blocks are already compressed
+      let blocks_options = family_options(Compression::None);
+
+      let mut filters = vec![];
+      for (cf, options) in &[
+        (POINTS_CF, points_options),
+        (ROLLUPS_CF, rollups_options),
+        (BLOCKS_CF, blocks_options),
+      ] {
+        let filter = compaction_filter(retention.compaction_filter(cf));
+        ffi::rocksdb_options_set_compaction_filter(*options, filter);
+        filters.push(filter);
+      }
+
+      let column_families = vec![
+        // Only holds what was written before column families were used
+        (DEFAULT_CF, family_options(Compression::None)),
+        (SERIES_CF, series_options),
+        (POINTS_CF, points_options),
+        (ROLLUPS_CF, rollups_options),
+        (BLOCKS_CF, blocks_options),
+        // Only written by the integrity check
+        (QUARANTINE_CF, family_options(Compression::None)),
+        // Written once per write batch and read in order by followers
+        (REPLICATION_CF, family_options(Compression::Lz4)),
+      ];
+      // The column families hold their own references to both
+      ffi::rocksdb_block_based_options_destroy(table_options);
+      ffi::rocksdb_cache_destroy(cache);
+
+      RocksDbOptions {
+        db,
+        column_families,
+        filters,
+      }
+    }
+  }
+}
+
+/// Wraps `filter` for the C API. RocksDB does not take ownership of
+/// compaction filters, they have to be destroyed after the database is
+/// closed.
+unsafe fn compaction_filter<F>(filter: F) -> *mut ffi::rocksdb_compactionfilter_t
+where
+  F: CompactionFilterFn + Send + 'static,
+{
+  let callback = Box::new(CompactionFilterCallback {
+    name: CString::new(COMPACTION_FILTER_NAME).unwrap(),
+    filter_fn: filter,
+  });
+  ffi::rocksdb_compactionfilter_create(
+    Box::into_raw(callback) as *mut c_void,
+    Some(compaction_filter::destructor_callback::<F>),
+    Some(compaction_filter::filter_callback::<F>),
+    Some(compaction_filter::name_callback::<F>),
+  )
+}
+
+/// Options built through the C API, which must outlive the database opened
+/// with them
+pub struct RocksDbOptions {
+  pub db: *mut ffi::rocksdb_options_t,
+  pub column_families: Vec<(&'static str, *mut ffi::rocksdb_options_t)>,
+  filters: Vec<*mut ffi::rocksdb_compactionfilter_t>,
+}
+
+impl RocksDbOptions {
+  /// The options of the column family `cf`, or of the database for column
+  /// families it does not know of
+  pub fn column_family(&self, cf: &str) -> *const ffi::rocksdb_options_t {
+    self
+      .column_families
+      .iter()
+      .find(|(name, _)| *name == cf)
+      .map_or(self.db, |(_, options)| *options)
+  }
+}
+
+impl Drop for RocksDbOptions {
+  fn drop(&mut self) {
+    unsafe {
+      for (_, options) in &self.column_families {
+        ffi::rocksdb_options_destroy(*options);
+      }
+      ffi::rocksdb_options_destroy(self.db);
+      for filter in &self.filters {
+        ffi::rocksdb_compactionfilter_destroy(*filter);
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const SETTINGS: &str = r#"
+    block_cache_size = 1073741824
+    write_buffer_size = 33554432
+    compression_per_level = ['none', 'lz4', 'zstd']
+    bloom_filter_bits = 0
+    max_open_files = 512
+    background_jobs = 4
+  "#;
+
+  #[test]
+  fn test_deserialize() {
+    let mut settings = config::Config::default();
+    settings
+      .merge(config::File::from_str(SETTINGS, config::FileFormat::Toml))
+      .unwrap();
+
+    assert_eq!(
+      settings.try_into::<RocksDbConfig>().unwrap(),
+      RocksDbConfig {
+        block_cache_size: Some(1_073_741_824),
+        write_buffer_size: Some(33_554_432),
+        compression_per_level: Some(vec![Compression::None, Compression::Lz4, Compression::Zstd]),
+        bloom_filter_bits: Some(0),
+        max_open_files: Some(512),
+        background_jobs: Some(4),
+      }
+    );
+  }
+
+  #[test]
+  fn test_unknown_compression() {
+    let mut settings = config::Config::default();
+    settings
+      .merge(config::File::from_str(
+        "compression_per_level = ['lz5']",
+        config::FileFormat::Toml,
+      ))
+      .unwrap();
+
+    let err = settings.try_into::<RocksDbConfig>().unwrap_err();
+    assert!(err.to_string().contains("Unknown compression \"lz5\""));
+  }
+
+  #[test]
+  fn test_effective() {
+    let config = RocksDbConfig {
+      background_jobs: Some(4),
+      ..Default::default()
+    }
+    .effective();
+
+    assert_eq!(config.block_cache_size, Some(DEFAULT_BLOCK_CACHE_SIZE));
+    assert_eq!(config.max_open_files, Some(DEFAULT_MAX_OPEN_FILES));
+    assert_eq!(config.background_jobs, Some(4));
+    assert_eq!(config.compression_per_level, None);
+  }
+}